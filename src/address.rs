@@ -0,0 +1,160 @@
+//! Host names and IP literals for the NUT and proxy connections, including
+//! bracketed IPv6 literals and link-local zone IDs such as `fe80::1%eth0`.
+
+use log::debug;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use tokio::net::{TcpStream, lookup_host};
+
+/// Formats `host:port`, bracketing IPv6 literals as in `[fe80::1%eth0]:3493`.
+pub fn format_address(host: &str, port: u16) -> String {
+	let host = unbracket(host);
+	if host.contains(':') {
+		format!("[{}]:{}", host, port)
+	} else {
+		format!("{}:{}", host, port)
+	}
+}
+
+/// The IP address `host` spells out, or None for a host name. Zone IDs are
+/// dropped since they only mean something on this machine.
+pub fn ip_literal(host: &str) -> Option<IpAddr> {
+	let host = unbracket(host);
+	let address = host.split_once('%').map_or(host, |(address, _)| address);
+	address.parse().ok()
+}
+
+/// Every address `host` stands for: the literal itself, or the A and AAAA
+/// records of a host name in the order the resolver returns them.
+pub async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+	let host = unbracket(host);
+
+	if let Some((address, zone)) = host.split_once('%') {
+		let address: Ipv6Addr = address.parse().map_err(|_| {
+			Error::new(
+				ErrorKind::InvalidInput,
+				format!("'{}' has a zone ID but is not an IPv6 address", host),
+			)
+		})?;
+		let scope_id = scope_id(zone)?;
+		return Ok(vec![SocketAddr::V6(SocketAddrV6::new(
+			address, port, 0, scope_id,
+		))]);
+	}
+
+	if let Ok(address) = host.parse::<IpAddr>() {
+		return Ok(vec![SocketAddr::new(address, port)]);
+	}
+
+	let addresses: Vec<SocketAddr> = lookup_host((host, port)).await?.collect();
+	if addresses.is_empty() {
+		return Err(Error::new(
+			ErrorKind::NotFound,
+			format!("{} has no A or AAAA records", host),
+		));
+	}
+	Ok(addresses)
+}
+
+/// Connects to the first address of `host` that accepts, trying them in order,
+/// so a name with both IPv4 and IPv6 records works over either.
+pub async fn connect(host: &str, port: u16) -> Result<TcpStream> {
+	let mut last_error = None;
+
+	for address in resolve(host, port).await? {
+		match TcpStream::connect(address).await {
+			Ok(stream) => return Ok(stream),
+			Err(e) => {
+				debug!("Connecting to {} failed: {}", address, e);
+				last_error = Some(e);
+			}
+		}
+	}
+
+	Err(last_error.expect("resolve returns at least one address"))
+}
+
+// Accept `[::1]` as written in URLs as well as the bare literal
+fn unbracket(host: &str) -> &str {
+	host
+		.strip_prefix('[')
+		.and_then(|host| host.strip_suffix(']'))
+		.unwrap_or(host)
+}
+
+// Numeric zones work everywhere; interface names are looked up on Linux
+fn scope_id(zone: &str) -> Result<u32> {
+	if let Ok(index) = zone.parse() {
+		return Ok(index);
+	}
+
+	(!zone.is_empty() && !zone.contains('/'))
+		.then(|| std::fs::read_to_string(format!("/sys/class/net/{}/ifindex", zone)).ok())
+		.flatten()
+		.and_then(|index| index.trim().parse().ok())
+		.ok_or_else(|| {
+			Error::new(
+				ErrorKind::InvalidInput,
+				format!("unknown network interface '{}' in the zone ID", zone),
+			)
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ipv6_literals_are_bracketed() {
+		assert_eq!(format_address("10.0.30.1", 3493), "10.0.30.1:3493");
+		assert_eq!(
+			format_address("nut.example.com", 3493),
+			"nut.example.com:3493"
+		);
+		assert_eq!(format_address("fe80::1", 3493), "[fe80::1]:3493");
+		assert_eq!(format_address("[fe80::1]", 3493), "[fe80::1]:3493");
+		assert_eq!(format_address("fe80::1%eth0", 3493), "[fe80::1%eth0]:3493");
+	}
+
+	#[test]
+	fn ip_literals_drop_brackets_and_zones() {
+		assert_eq!(ip_literal("10.0.30.1"), "10.0.30.1".parse().ok());
+		assert_eq!(ip_literal("[2001:db8::1]"), "2001:db8::1".parse().ok());
+		assert_eq!(ip_literal("fe80::1%2"), "fe80::1".parse().ok());
+		assert_eq!(ip_literal("nut.example.com"), None);
+	}
+
+	#[tokio::test]
+	async fn literals_resolve_to_themselves() {
+		assert_eq!(
+			resolve("10.0.30.1", 3493).await.unwrap(),
+			["10.0.30.1:3493".parse().unwrap()]
+		);
+		for host in ["::1", "[::1]"] {
+			assert_eq!(
+				resolve(host, 3493).await.unwrap(),
+				["[::1]:3493".parse().unwrap()]
+			);
+		}
+
+		let scoped = resolve("fe80::1%7", 3493).await.unwrap();
+		let [SocketAddr::V6(address)] = scoped.as_slice() else {
+			panic!("unexpected addresses: {:?}", scoped);
+		};
+		assert_eq!(address.scope_id(), 7);
+
+		assert!(resolve("10.0.30.1%eth0", 3493).await.is_err());
+		assert!(resolve("fe80::1%no/such", 3493).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn host_names_resolve_to_every_address() {
+		let addresses = resolve("localhost", 3493).await.unwrap();
+		assert!(!addresses.is_empty());
+		assert!(
+			addresses
+				.iter()
+				.all(|address| address.ip().is_loopback() && address.port() == 3493)
+		);
+	}
+}
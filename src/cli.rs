@@ -0,0 +1,162 @@
+use clap::{ArgAction, Parser, Subcommand};
+
+use rabbitnut::config::Config;
+
+/// UPS monitor for Network UPS Tools servers.
+///
+/// Flags override the matching values from the configuration file.
+#[derive(Debug, Parser)]
+#[command(name = "rabbitnut", version)]
+pub struct Args {
+	/// Path to the configuration file, or - to read it from stdin
+	#[arg(default_value = "config.toml")]
+	pub config: String,
+
+	#[command(subcommand)]
+	pub command: Option<Command>,
+
+	/// Merge every .toml file in this directory over the configuration file
+	#[arg(long)]
+	pub config_dir: Option<String>,
+
+	/// Write a documented sample configuration to PATH (or stdout) and exit
+	#[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "-")]
+	pub generate_config: Option<String>,
+
+	/// Overwrite an existing file with --generate-config
+	#[arg(long, requires = "generate_config")]
+	pub force: bool,
+
+	/// Log shutdowns instead of executing them
+	#[arg(long)]
+	pub dry_run: bool,
+
+	/// Only monitor and export metrics, never shut anything down
+	#[arg(long)]
+	pub no_shutdown: bool,
+
+	/// Poll once, print a check-plugin summary and exit with its status code
+	#[arg(long)]
+	pub once: bool,
+
+	/// Check that the NUT server is reachable, the login works and ups.name exists, then exit
+	#[arg(long, conflicts_with = "once")]
+	pub test_connection: bool,
+
+	/// Stop cleanly after monitoring for SECS seconds, e.g. for smoke tests
+	#[arg(long, value_name = "SECS", conflicts_with_all = ["once", "test_connection"])]
+	pub run_for: Option<u64>,
+
+	/// Replace the NUT server with the scripted outage from [simulation]
+	#[arg(long)]
+	pub simulate: bool,
+
+	/// Override ups.host
+	#[arg(long)]
+	pub ups_host: Option<String>,
+
+	/// Override ups.name
+	#[arg(long)]
+	pub ups_name: Option<String>,
+
+	/// Override monitoring.poll_interval (seconds)
+	#[arg(long)]
+	pub poll_interval: Option<u64>,
+
+	/// Override metrics.port
+	#[arg(long)]
+	pub metrics_port: Option<u16>,
+
+	/// Override logging.log_level
+	#[arg(long)]
+	pub log_level: Option<String>,
+
+	/// Log at debug level, or trace with -vv
+	#[arg(short, long, action = ArgAction::Count, conflicts_with_all = ["quiet", "log_level"])]
+	pub verbose: u8,
+
+	/// Only log warnings and errors
+	#[arg(short, long, conflicts_with = "log_level")]
+	pub quiet: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+	/// List recent outages from the history database
+	History {
+		/// Path to the configuration file
+		#[arg(default_value = "config.toml")]
+		config: String,
+	},
+}
+
+impl Args {
+	/// Path of the configuration file, whether given before or after the subcommand.
+	pub fn config_path(&self) -> &str {
+		match self.command {
+			Some(Command::History { ref config }) => config,
+			None => &self.config,
+		}
+	}
+
+	/// Overwrites the loaded configuration with every flag that was given.
+	pub fn apply(&self, config: &mut Config) {
+		if self.dry_run {
+			config.shutdown.dry_run = true;
+		}
+
+		if self.no_shutdown {
+			config.shutdown.enabled = false;
+		}
+
+		if self.simulate {
+			config
+				.simulation
+				.get_or_insert_with(Default::default)
+				.enabled = true;
+		}
+
+		if let Some(ref host) = self.ups_host {
+			config.ups.host = host.clone();
+		}
+
+		if let Some(ref name) = self.ups_name {
+			config.ups.name = name.clone();
+		}
+
+		if let Some(poll_interval) = self.poll_interval {
+			config.monitoring.poll_interval = poll_interval;
+		}
+
+		if let Some(secs) = self.run_for {
+			config.monitoring.max_runtime_secs = Some(secs);
+		}
+
+		// Without a [metrics] section there is no endpoint to move
+		if let Some(port) = self.metrics_port
+			&& let Some(ref mut metrics) = config.metrics
+		{
+			metrics.port = port;
+		}
+
+		if let Some(ref level) = self.log_level {
+			config.logging.log_level = level.clone();
+		}
+
+		// Check plugins read the first line of output, so only errors are logged
+		// unless more was asked for
+		if self.once && self.log_level.is_none() && self.verbose == 0 && !self.quiet {
+			config.logging.log_level = "error".to_string();
+		}
+
+		let level = match (self.verbose, self.quiet) {
+			(0, false) => None,
+			(0, true) => Some("warn"),
+			(1, _) => Some("debug"),
+			_ => Some("trace"),
+		};
+		if let Some(level) = level {
+			config.logging.log_level = level.to_string();
+		}
+	}
+}
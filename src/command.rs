@@ -0,0 +1,47 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Resolves the program of a command line such as `shutdown -h now`, either as
+/// a path or by searching `PATH`. Returns `None` if no executable file is found.
+pub fn find_program(command: &str) -> Option<PathBuf> {
+	let program = command.split_whitespace().next()?;
+	let path = Path::new(program);
+
+	// Paths are used as-is, bare names are looked up like a shell would
+	if path.components().count() > 1 {
+		return is_executable(path).then(|| path.to_path_buf());
+	}
+
+	let extensions = executable_extensions();
+	env::split_paths(&env::var_os("PATH")?)
+		.flat_map(|dir| {
+			extensions
+				.iter()
+				.map(move |extension| dir.join(format!("{}{}", program, extension)))
+		})
+		.find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+	use std::os::unix::fs::PermissionsExt;
+
+	path
+		.metadata()
+		.is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+	path.is_file()
+}
+
+// Windows resolves `shutdown` to `shutdown.exe` through PATHEXT
+fn executable_extensions() -> Vec<String> {
+	let mut extensions = vec![String::new()];
+	if cfg!(windows) {
+		let pathext = env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+		extensions.extend(pathext.split(';').map(|e| e.to_lowercase()));
+	}
+	extensions
+}
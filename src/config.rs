@@ -1,6 +1,16 @@
 use serde::Deserialize;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::env;
 use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
 
+use crate::events::EventType;
+
+/// The complete configuration, as loaded from TOML by [`Config::load`].
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
 	pub ups: UpsConfig,
@@ -8,6 +18,12 @@ pub struct Config {
 	pub shutdown: ShutdownConfig,
 	pub logging: LoggingConfig,
 	pub metrics: Option<MetricsConfig>,
+	pub simulation: Option<SimulationConfig>,
+	pub mqtt: Option<MqttConfig>,
+	pub history: Option<HistoryConfig>,
+	pub hooks: Option<HooksConfig>,
+	#[serde(default)]
+	pub notifications: Vec<NotificationConfig>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -17,11 +33,89 @@ pub struct UpsConfig {
 	pub port: u16,
 	pub username: Option<String>,
 	pub password: Option<String>,
+	#[serde(default)]
+	pub servers: Vec<NutServerConfig>,
+	#[serde(default)]
+	pub extra_vars: Vec<String>,
+	#[serde(default = "default_role")]
+	pub role: String,
+	pub proxy: Option<ProxyConfig>,
+	pub keepalive_secs: Option<u64>,
+	// Written to the UPS with SET VAR once at startup, by variable name
+	#[serde(default)]
+	pub set_vars: HashMap<String, String>,
+}
+
+/// SOCKS5 proxy the NUT connections are tunnelled through.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProxyConfig {
+	pub host: String,
+	#[serde(default = "default_proxy_port")]
+	pub port: u16,
+	pub username: Option<String>,
+	pub password: Option<String>,
+}
+
+fn default_proxy_port() -> u16 {
+	1080
+}
+
+fn default_role() -> String {
+	"monitor".to_string()
+}
+
+/// An additional NUT server exposing the same UPS, used for failover.
+#[derive(Debug, Deserialize, Clone)]
+pub struct NutServerConfig {
+	pub host: String,
+	#[serde(default = "default_nut_port")]
+	pub port: u16,
+}
+
+fn default_nut_port() -> u16 {
+	3493
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct MonitoringConfig {
 	pub poll_interval: u64,
+	#[serde(default = "default_max_failures_before_stale")]
+	pub max_failures_before_stale: u32,
+	#[serde(default)]
+	pub heartbeat_interval_secs: u64,
+	#[serde(default = "default_startup_timeout_secs")]
+	pub startup_timeout_secs: u64,
+	pub temperature_alert_celsius: Option<f64>,
+	#[serde(default = "default_shutdown_timeout_secs")]
+	pub shutdown_timeout_secs: u64,
+	#[serde(default = "default_notification_cooldown_secs")]
+	pub notification_cooldown_secs: u64,
+	pub failure_alert_threshold: Option<u32>,
+	#[serde(default)]
+	pub cycle_watchdog_secs: u64,
+	#[serde(default)]
+	pub warn_levels: Vec<f64>,
+	pub state_file: Option<String>,
+	#[serde(default)]
+	pub log_lifetime_stats: bool,
+	pub stale_after_unchanged_polls: Option<u32>,
+	pub max_runtime_secs: Option<u64>,
+}
+
+fn default_max_failures_before_stale() -> u32 {
+	3
+}
+
+fn default_startup_timeout_secs() -> u64 {
+	60
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+	5
+}
+
+fn default_notification_cooldown_secs() -> u64 {
+	60
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -30,14 +124,103 @@ pub struct ShutdownConfig {
 	pub on_battery_seconds: u64,
 	pub battery_percent_threshold: f64,
 	pub runtime_threshold: u64,
+	pub runtime_percent_threshold: Option<f64>,
+	#[serde(default)]
+	pub min_on_battery_seconds_before_action: u64,
+	pub stale_data_shutdown_secs: Option<u64>,
+	#[serde(default = "default_shutdown_command")]
 	pub shutdown_command: String,
+	pub low_battery_command: Option<String>,
+	pub on_battery_timeout_command: Option<String>,
 	pub shutdown_grace_period: u64,
+	pub countdown_log_interval_secs: Option<u64>,
+	pub countdown_command: Option<String>,
+	#[serde(default)]
+	pub dry_run: bool,
+	#[serde(default)]
+	pub strict: bool,
+	#[serde(default)]
+	pub notify_hosts: Vec<String>,
+	pub pre_shutdown_command: Option<String>,
+	#[serde(default = "default_pre_shutdown_timeout_secs")]
+	pub pre_shutdown_timeout_secs: u64,
+	#[serde(default)]
+	pub command_ups_shutdown: bool,
+	#[serde(default)]
+	pub ups_shutdown_method: UpsShutdownMethod,
+	#[serde(default)]
+	pub abort_window_secs: u64,
+	#[serde(default = "default_shutdown_cancel_command")]
+	pub shutdown_cancel_command: String,
+	#[serde(default)]
+	pub retry_shutdown_command: bool,
+	#[serde(default = "default_shutdown_command_retries")]
+	pub shutdown_command_retries: u32,
+	pub fallback_shutdown_command: Option<String>,
+}
+
+/// How the UPS is told to cut its own power when `command_ups_shutdown` is set.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpsShutdownMethod {
+	/// `INSTCMD <ups> shutdown.return`: cut power after `ups.delay.shutdown`
+	/// and restore it once utility power returns.
+	#[default]
+	Instcmd,
+	/// `FSD <ups>`: set the forced shutdown flag for upsmon to act on.
+	Fsd,
+}
+
+fn default_pre_shutdown_timeout_secs() -> u64 {
+	60
+}
+
+fn default_shutdown_command_retries() -> u32 {
+	3
+}
+
+// The usual immediate power-off command for the platform we were built for
+fn default_shutdown_command() -> String {
+	if cfg!(target_os = "windows") {
+		"shutdown /s /t 0".to_string()
+	} else if cfg!(target_os = "macos") {
+		"shutdown -h now".to_string()
+	} else {
+		"/sbin/shutdown -h +0".to_string()
+	}
+}
+
+// Cancels a pending shutdown started by `default_shutdown_command` with a delay
+fn default_shutdown_cancel_command() -> String {
+	if cfg!(target_os = "windows") {
+		"shutdown /a".to_string()
+	} else if cfg!(target_os = "macos") {
+		"killall shutdown".to_string()
+	} else {
+		"/sbin/shutdown -c".to_string()
+	}
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct LoggingConfig {
 	pub log_file: Option<String>,
 	pub log_level: String,
+	#[serde(default)]
+	pub module_levels: HashMap<String, String>,
+	pub event_log_file: Option<String>,
+	pub timestamp_format: Option<String>,
+	#[serde(default)]
+	pub utc: bool,
+}
+
+impl LoggingConfig {
+	/// chrono format string for log timestamps.
+	pub fn timestamp_format(&self) -> &str {
+		self
+			.timestamp_format
+			.as_deref()
+			.unwrap_or("%Y-%m-%d %H:%M:%S")
+	}
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -45,14 +228,751 @@ pub struct MetricsConfig {
 	pub enabled: bool,
 	pub port: u16,
 	pub bearer_token: Option<String>,
+	pub bearer_tokens: Option<Vec<String>>,
 	pub format: Option<String>,
+	pub influx_measurement: Option<String>,
+	pub rate_limit_per_minute: Option<u32>,
+	pub metrics_path: Option<String>,
+	pub admin_token: Option<String>,
+	#[serde(default)]
+	pub cors_allowed_origins: Vec<String>,
+	#[serde(default)]
+	pub access_log: bool,
+	#[serde(default)]
+	pub allow_simulation: bool,
+	#[serde(default)]
+	pub labels: HashMap<String, String>,
+	#[serde(default = "default_max_websocket_connections")]
+	pub max_websocket_connections: usize,
+	pub push: Option<PushConfig>,
+}
+
+fn default_max_websocket_connections() -> usize {
+	16
+}
+
+/// Prometheus Pushgateway every successful poll is pushed to.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PushConfig {
+	pub pushgateway_url: String,
+	#[serde(default = "default_push_job")]
+	pub job: String,
+	// Defaults to the host name
+	pub instance: Option<String>,
+}
+
+fn default_push_job() -> String {
+	"rabbitnut".to_string()
+}
+
+impl MetricsConfig {
+	pub fn metrics_path(&self) -> &str {
+		self.metrics_path.as_deref().unwrap_or("/metrics")
+	}
+
+	/// All accepted bearer tokens from `bearer_token` and `bearer_tokens`.
+	pub fn tokens(&self) -> Vec<String> {
+		self
+			.bearer_token
+			.iter()
+			.chain(self.bearer_tokens.iter().flatten())
+			.cloned()
+			.collect()
+	}
+}
+
+/// A webhook receiving power event notifications.
+#[derive(Debug, Deserialize, Clone)]
+pub struct NotificationConfig {
+	// Unused by Telegram, which is addressed through `bot_token` and `chat_id`
+	#[serde(default)]
+	pub url: String,
+	#[serde(default)]
+	pub notification_type: NotificationType,
+	pub bot_token: Option<String>,
+	pub chat_id: Option<String>,
+	// Only notify for these events; all events when unset
+	pub events: Option<Vec<EventType>>,
+	pub message_template: Option<String>,
+	// Per-event templates, overriding `message_template`
+	#[serde(default)]
+	pub templates: HashMap<String, String>,
+}
+
+/// Body schema used when POSTing to a notification webhook.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationType {
+	#[default]
+	Generic,
+	Slack,
+	Discord,
+	Telegram,
+}
+
+/// Commands run at points of the outage lifecycle other than the shutdown.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HooksConfig {
+	pub on_battery_command: Option<String>,
+	pub on_restore_command: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "history"), allow(dead_code))]
+pub struct HistoryConfig {
+	pub database_path: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub struct MqttConfig {
+	pub host: String,
+	#[serde(default = "default_mqtt_port")]
+	pub port: u16,
+	#[serde(default)]
+	pub tls: bool,
+	pub username: Option<String>,
+	pub password: Option<String>,
+	#[serde(default = "default_mqtt_base_topic")]
+	pub base_topic: String,
+	pub client_id: Option<String>,
+}
+
+fn default_mqtt_port() -> u16 {
+	1883
+}
+
+fn default_mqtt_base_topic() -> String {
+	"rabbitnut".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SimulationConfig {
+	pub enabled: bool,
+	pub online_polls: u64,
+	pub battery_polls: u64,
+	pub charge_drop_per_poll: f64,
+	pub full_runtime: u64,
+	pub output_power: f64,
 }
 
+impl Default for SimulationConfig {
+	fn default() -> Self {
+		SimulationConfig {
+			enabled: false,
+			online_polls: 3,
+			battery_polls: 30,
+			charge_drop_per_poll: 3.0,
+			full_runtime: 1800,
+			output_power: 150.0,
+		}
+	}
+}
+
+/// The bundled, fully commented sample configuration.
+pub const SAMPLE_CONFIG: &str = include_str!("../config.toml");
+
 impl Config {
-	pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-		let config_str = fs::read_to_string(path)?;
-		let config: Config = toml::from_str(&config_str)?;
-		Ok(config)
+	/// Loads the file at `path`, merges in the files it `include`s and every
+	/// `.toml` file in `config_dir`, then layers `RABBITNUT_*` environment
+	/// variables on top. The file may be missing when the environment provides
+	/// every required value. A `path` of `-` reads the configuration from stdin,
+	/// with includes resolved against the current directory.
+	pub fn load(path: &str, config_dir: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+		let vars: Vec<(String, String)> = env::vars()
+			.filter(|(name, _)| name.starts_with(ENV_PREFIX))
+			.collect();
+
+		Self::load_with_env(path, config_dir, vars)
+	}
+
+	fn load_with_env(
+		path: &str,
+		config_dir: Option<&str>,
+		vars: Vec<(String, String)>,
+	) -> Result<Self, Box<dyn std::error::Error>> {
+		let (mut table, missing) = match read_source(path) {
+			Ok(config_str) => (parse_table(path, &config_str)?, None),
+			Err(e) if e.kind() == ErrorKind::NotFound && !vars.is_empty() => (Table::new(), Some(e)),
+			Err(e) => return Err(format!("Failed to read {}: {}", path, e).into()),
+		};
+
+		// Include patterns are relative to the main file
+		let base_dir = Path::new(path).parent().unwrap_or(Path::new(""));
+		let mut patterns = match table.remove("include") {
+			None => Vec::new(),
+			Some(Value::String(pattern)) => vec![pattern],
+			Some(Value::Array(patterns)) => patterns
+				.into_iter()
+				.map(|pattern| match pattern {
+					Value::String(pattern) => Ok(pattern),
+					other => Err(format!("include entries must be strings, found {}", other)),
+				})
+				.collect::<Result<_, _>>()?,
+			Some(other) => {
+				return Err(format!("include must be a string or array, found {}", other).into());
+			}
+		}
+		.into_iter()
+		.map(|pattern| base_dir.join(pattern))
+		.collect::<Vec<PathBuf>>();
+
+		if let Some(dir) = config_dir {
+			patterns.push(Path::new(dir).join("*.toml"));
+		}
+
+		for pattern in patterns {
+			for include in expand_include(&pattern)? {
+				let include_str = fs::read_to_string(&include)
+					.map_err(|e| format!("Failed to read {}: {}", include.display(), e))?;
+				let mut overlay = parse_table(&include.display().to_string(), &include_str)?;
+				// Only the main file may include others
+				overlay.remove("include");
+				merge_tables(&mut table, overlay);
+			}
+		}
+
+		Config::deserialize(apply_env_overrides(table, vars)).map_err(|e| match missing {
+			// Unrelated RABBITNUT_ variables must not hide a mistyped path
+			Some(missing) => format!(
+				"Failed to read {}: {}, and the RABBITNUT_ environment variables are not a complete configuration ({})",
+				path,
+				missing,
+				e.message()
+			)
+			.into(),
+			None => format!("Invalid configuration: {}", e).into(),
+		})
+	}
+
+	/// Rejects configurations that would fail at runtime.
+	pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+		let watchdog = self.monitoring.cycle_watchdog_secs;
+		if watchdog > 0 && watchdog <= self.monitoring.poll_interval {
+			return Err(
+				format!(
+					"monitoring.cycle_watchdog_secs ({}) must be longer than poll_interval ({})",
+					watchdog, self.monitoring.poll_interval
+				)
+				.into(),
+			);
+		}
+
+		if self.monitoring.max_runtime_secs == Some(0) {
+			return Err("monitoring.max_runtime_secs must be at least 1".into());
+		}
+
+		if let Some(level) = self
+			.monitoring
+			.warn_levels
+			.iter()
+			.find(|level| !(0.0..=100.0).contains(*level))
+		{
+			return Err(
+				format!(
+					"monitoring.warn_levels entry {} must be between 0 and 100",
+					level
+				)
+				.into(),
+			);
+		}
+
+		if let Some(percent) = self.shutdown.runtime_percent_threshold
+			&& !(percent > 0.0 && percent <= 100.0)
+		{
+			return Err(
+				format!(
+					"shutdown.runtime_percent_threshold ({}) must be above 0 and at most 100",
+					percent
+				)
+				.into(),
+			);
+		}
+
+		// chrono panics when formatting with an invalid specifier
+		let format = self.logging.timestamp_format();
+		if chrono::format::StrftimeItems::new(format).any(|item| item == chrono::format::Item::Error) {
+			return Err(format!("logging.timestamp_format '{}' is invalid", format).into());
+		}
+
+		if let Some(keepalive) = self.ups.keepalive_secs
+			&& (keepalive == 0 || keepalive >= self.monitoring.poll_interval)
+		{
+			return Err(
+				format!(
+					"ups.keepalive_secs ({}) must be at least 1 and shorter than poll_interval ({})",
+					keepalive, self.monitoring.poll_interval
+				)
+				.into(),
+			);
+		}
+
+		// Only the primary session stays open between polls, there is nothing
+		// else to keep alive
+		if self.ups.keepalive_secs.is_some() && self.ups.role != "primary" {
+			return Err(
+				"ups.keepalive_secs needs ups.role \"primary\", other connections are opened per poll"
+					.into(),
+			);
+		}
+
+		// Two identical readings in a row are normal on line power
+		if self
+			.monitoring
+			.stale_after_unchanged_polls
+			.is_some_and(|polls| polls < 2)
+		{
+			return Err("monitoring.stale_after_unchanged_polls must be at least 2".into());
+		}
+
+		if self.shutdown.countdown_log_interval_secs == Some(0) {
+			return Err("shutdown.countdown_log_interval_secs must be at least 1".into());
+		}
+
+		// upsd only accepts SET VAR from a logged-in user
+		if !self.ups.set_vars.is_empty() && (self.ups.username.is_none() || self.ups.password.is_none())
+		{
+			return Err("ups.set_vars needs ups.username and ups.password".into());
+		}
+
+		if let Some(ref proxy) = self.ups.proxy
+			&& proxy.username.is_some() != proxy.password.is_some()
+		{
+			return Err("ups.proxy needs both username and password, or neither".into());
+		}
+
+		if !["monitor", "primary"].contains(&self.ups.role.as_str()) {
+			return Err(
+				format!(
+					"ups.role '{}' must be \"monitor\" or \"primary\"",
+					self.ups.role
+				)
+				.into(),
+			);
+		}
+
+		// LOGIN and PRIMARY are refused without a logged-in upsmon user
+		if self.ups.role == "primary" && (self.ups.username.is_none() || self.ups.password.is_none()) {
+			return Err("ups.role \"primary\" needs ups.username and ups.password".into());
+		}
+
+		for (index, channel) in self.notifications.iter().enumerate() {
+			let missing = match channel.notification_type {
+				NotificationType::Telegram => {
+					if channel.bot_token.is_none() {
+						Some("bot_token")
+					} else if channel.chat_id.is_none() {
+						Some("chat_id")
+					} else {
+						None
+					}
+				}
+				_ => channel.url.is_empty().then_some("url"),
+			};
+			if let Some(field) = missing {
+				return Err(format!("notifications[{}] is missing {}", index, field).into());
+			}
+		}
+
+		if let Some(ref metrics) = self.metrics {
+			let path = metrics.metrics_path();
+			if !path.starts_with('/') {
+				return Err(format!("metrics.metrics_path '{}' must start with '/'", path).into());
+			}
+			if ["/", "/events", "/status", "/health", "/ws"].contains(&path)
+				|| crate::metrics::FORMAT_PATHS
+					.iter()
+					.any(|(reserved, _)| *reserved == path)
+				|| path.starts_with("/admin/")
+			{
+				return Err(format!("metrics.metrics_path '{}' is reserved", path).into());
+			}
+			if let Some(name) = metrics
+				.labels
+				.keys()
+				.find(|name| !is_valid_label_name(name))
+			{
+				return Err(
+					format!(
+						"metrics.labels name '{}' must match [a-zA-Z_][a-zA-Z0-9_]* and not start with __",
+						name
+					)
+					.into(),
+				);
+			}
+			if let Some(name) = metrics
+				.labels
+				.keys()
+				.find(|name| RESERVED_LABELS.contains(&name.as_str()))
+			{
+				return Err(format!("metrics.labels name '{}' is used by RabbitNUT", name).into());
+			}
+			if let Some(origin) = metrics
+				.cors_allowed_origins
+				.iter()
+				.find(|origin| origin.parse::<axum::http::HeaderValue>().is_err())
+			{
+				return Err(format!("metrics.cors_allowed_origins entry '{}' is invalid", origin).into());
+			}
+			if let Some(ref push) = metrics.push {
+				if !push.pushgateway_url.starts_with("http://")
+					&& !push.pushgateway_url.starts_with("https://")
+				{
+					return Err(
+						format!(
+							"metrics.push.pushgateway_url '{}' must start with http:// or https://",
+							push.pushgateway_url
+						)
+						.into(),
+					);
+				}
+				// Both become path segments of the push URL
+				for (field, value) in [
+					("job", Some(&push.job)),
+					("instance", push.instance.as_ref()),
+				] {
+					if let Some(value) = value
+						&& (value.is_empty() || value.contains('/'))
+					{
+						return Err(
+							format!(
+								"metrics.push.{} '{}' must be non-empty without '/'",
+								field, value
+							)
+							.into(),
+						);
+					}
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Settings that are valid but probably unsafe, for logging at startup.
+	pub fn warnings(&self) -> Vec<String> {
+		let mut warnings = Vec::new();
+		if !self.shutdown.enabled {
+			return warnings;
+		}
+
+		let poll_interval = self.monitoring.poll_interval;
+		let thresholds = [
+			("runtime_threshold", self.shutdown.runtime_threshold),
+			("on_battery_seconds", self.shutdown.on_battery_seconds),
+		];
+		for (name, threshold) in thresholds {
+			if threshold == 0 || poll_interval.saturating_mul(MIN_POLLS_PER_THRESHOLD) <= threshold {
+				continue;
+			}
+
+			warnings.push(format!(
+				"monitoring.poll_interval ({}s) is long compared to shutdown.{} ({}s), the threshold may pass unnoticed between polls; use a poll_interval of at most {}s",
+				poll_interval,
+				name,
+				threshold,
+				(threshold / MIN_POLLS_PER_THRESHOLD).max(1)
+			));
+		}
+
+		warnings
+	}
+}
+
+// Polls that should fit into each shutdown threshold, so one slow or failed
+// poll doesn't let the UPS run past it
+const MIN_POLLS_PER_THRESHOLD: u64 = 2;
+
+// Labels the exporter sets itself
+const RESERVED_LABELS: [&str; 6] = [
+	"ups_name",
+	"ups_host",
+	"server",
+	"var",
+	"value",
+	"battery_date",
+];
+
+// Prometheus label names; the `__` prefix is reserved for internal use
+fn is_valid_label_name(name: &str) -> bool {
+	let mut chars = name.chars();
+	chars
+		.next()
+		.is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+		&& !name.starts_with("__")
+}
+
+// Contents of the configuration file, or of stdin for `-`
+fn read_source(path: &str) -> std::io::Result<String> {
+	if path == "-" {
+		read_stdin_config(std::io::stdin())
+	} else {
+		fs::read_to_string(path)
+	}
+}
+
+fn read_stdin_config(mut reader: impl Read) -> std::io::Result<String> {
+	let mut contents = String::new();
+	reader.read_to_string(&mut contents)?;
+	if contents.trim().is_empty() {
+		return Err(std::io::Error::new(
+			ErrorKind::UnexpectedEof,
+			"no config provided on stdin",
+		));
+	}
+	Ok(contents)
+}
+
+fn parse_table(path: &str, contents: &str) -> Result<Table, Box<dyn std::error::Error>> {
+	toml::from_str(contents).map_err(|e| format!("Failed to parse {}: {}", path, e).into())
+}
+
+// Files matching an include pattern, ordered by file name
+fn expand_include(pattern: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+	let pattern = pattern.to_string_lossy();
+	let mut paths = glob::glob(&pattern)
+		.map_err(|e| format!("Invalid include pattern '{}': {}", pattern, e))?
+		.collect::<Result<Vec<_>, _>>()?;
+	paths.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+	Ok(paths)
+}
+
+/// Deep-merges `overlay` into `base`: tables are merged key by key, anything
+/// else (strings, numbers, arrays, including arrays of tables) is replaced.
+fn merge_tables(base: &mut Table, overlay: Table) {
+	for (key, value) in overlay {
+		match (base.get_mut(&key), value) {
+			(Some(Value::Table(existing)), Value::Table(value)) => merge_tables(existing, value),
+			(_, value) => {
+				base.insert(key, value);
+			}
+		}
+	}
+}
+
+// Environment variables are RABBITNUT_<SECTION>__<KEY>, e.g. RABBITNUT_UPS__HOST
+const ENV_PREFIX: &str = "RABBITNUT_";
+
+/// Layers every `RABBITNUT_SECTION__KEY` variable over the merged files as
+/// `section.key`. The values are kept as written until the setting they land
+/// on asks for a type, see [`ConfigNode`].
+fn apply_env_overrides(
+	table: Table,
+	vars: impl IntoIterator<Item = (String, String)>,
+) -> ConfigNode {
+	let mut root = ConfigNode::from_table(table);
+
+	for (name, raw) in vars {
+		let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+			continue;
+		};
+		let keys: Vec<String> = path.split("__").map(str::to_lowercase).collect();
+		let Some((key, sections)) = keys.split_last() else {
+			continue;
+		};
+		if sections.is_empty() || keys.iter().any(String::is_empty) {
+			continue;
+		}
+
+		let mut current = &mut root;
+		for section in sections {
+			let ConfigNode::Table(table) = current else {
+				unreachable!("sections are always tables");
+			};
+			let entry = table
+				.entry(section.clone())
+				.or_insert_with(|| ConfigNode::Table(BTreeMap::new()));
+			if !matches!(entry, ConfigNode::Table(_)) {
+				*entry = ConfigNode::Table(BTreeMap::new());
+			}
+			current = entry;
+		}
+
+		if let ConfigNode::Table(table) = current {
+			table.insert(key.clone(), ConfigNode::Env(raw));
+		}
+	}
+
+	root
+}
+
+/// The merged configuration with the environment values still as raw text.
+/// Each is read according to the setting it lands on: as written where a
+/// string is expected, so `RABBITNUT_UPS__PASSWORD=123456` stays a password,
+/// and as TOML (numbers, booleans, arrays) everywhere else.
+enum ConfigNode {
+	Toml(Value),
+	Env(String),
+	Table(BTreeMap<String, ConfigNode>),
+}
+
+impl ConfigNode {
+	fn from_table(table: Table) -> Self {
+		ConfigNode::Table(
+			table
+				.into_iter()
+				.map(|(key, value)| match value {
+					Value::Table(table) => (key, ConfigNode::from_table(table)),
+					value => (key, ConfigNode::Toml(value)),
+				})
+				.collect(),
+		)
+	}
+}
+
+// A quoted value is unquoted, anything else is taken literally
+fn env_string(raw: String) -> String {
+	match parse_env_value(raw.clone()) {
+		Value::String(value) => value,
+		_ => raw,
+	}
+}
+
+fn parse_env_value(raw: String) -> Value {
+	match toml::from_str::<Table>(&format!("value = {}", raw)) {
+		Ok(mut parsed) => parsed.remove("value").unwrap_or(Value::String(raw)),
+		Err(_) => Value::String(raw),
+	}
+}
+
+/// Deserialization error naming the setting it occurred in, like toml's own.
+#[derive(Debug)]
+struct ConfigNodeError {
+	message: String,
+	path: Vec<String>,
+}
+
+impl ConfigNodeError {
+	fn message(&self) -> &str {
+		&self.message
+	}
+}
+
+impl std::fmt::Display for ConfigNodeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if self.path.is_empty() {
+			write!(f, "{}", self.message)
+		} else {
+			write!(f, "{}\nin `{}`", self.message, self.path.join("."))
+		}
+	}
+}
+
+impl std::error::Error for ConfigNodeError {}
+
+impl de::Error for ConfigNodeError {
+	fn custom<T: std::fmt::Display>(message: T) -> Self {
+		ConfigNodeError {
+			message: message.to_string(),
+			path: Vec::new(),
+		}
+	}
+}
+
+impl From<toml::de::Error> for ConfigNodeError {
+	fn from(error: toml::de::Error) -> Self {
+		de::Error::custom(error.to_string().trim_end())
+	}
+}
+
+impl<'de> de::Deserializer<'de> for ConfigNode {
+	type Error = ConfigNodeError;
+
+	fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+		match self {
+			ConfigNode::Toml(value) => Ok(value.deserialize_any(visitor)?),
+			ConfigNode::Env(raw) => Ok(parse_env_value(raw).deserialize_any(visitor)?),
+			ConfigNode::Table(table) => visitor.visit_map(NodeMap {
+				entries: table.into_iter(),
+				value: None,
+			}),
+		}
+	}
+
+	fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+		visitor.visit_some(self)
+	}
+
+	fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+		self.deserialize_string(visitor)
+	}
+
+	fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+		match self {
+			ConfigNode::Env(raw) => visitor.visit_string(env_string(raw)),
+			node => node.deserialize_any(visitor),
+		}
+	}
+
+	fn deserialize_enum<V: Visitor<'de>>(
+		self,
+		name: &'static str,
+		variants: &'static [&'static str],
+		visitor: V,
+	) -> Result<V::Value, Self::Error> {
+		match self {
+			ConfigNode::Toml(value) => Ok(value.deserialize_enum(name, variants, visitor)?),
+			ConfigNode::Env(raw) => Ok(parse_env_value(raw).deserialize_enum(name, variants, visitor)?),
+			node => node.deserialize_any(visitor),
+		}
+	}
+
+	fn deserialize_newtype_struct<V: Visitor<'de>>(
+		self,
+		_name: &'static str,
+		visitor: V,
+	) -> Result<V::Value, Self::Error> {
+		visitor.visit_newtype_struct(self)
+	}
+
+	serde::forward_to_deserialize_any! {
+		bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf
+		unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+	}
+}
+
+impl<'de> IntoDeserializer<'de, ConfigNodeError> for ConfigNode {
+	type Deserializer = Self;
+
+	fn into_deserializer(self) -> Self {
+		self
+	}
+}
+
+// Walks a table, adding the key to errors from its values
+struct NodeMap {
+	entries: std::collections::btree_map::IntoIter<String, ConfigNode>,
+	value: Option<(String, ConfigNode)>,
+}
+
+impl<'de> MapAccess<'de> for NodeMap {
+	type Error = ConfigNodeError;
+
+	fn next_key_seed<K: DeserializeSeed<'de>>(
+		&mut self,
+		seed: K,
+	) -> Result<Option<K::Value>, Self::Error> {
+		let Some((key, value)) = self.entries.next() else {
+			return Ok(None);
+		};
+		let parsed = seed.deserialize(IntoDeserializer::<ConfigNodeError>::into_deserializer(
+			key.clone(),
+		))?;
+		self.value = Some((key, value));
+		Ok(Some(parsed))
+	}
+
+	fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, Self::Error> {
+		let (key, value) = self
+			.value
+			.take()
+			.ok_or_else(|| <ConfigNodeError as de::Error>::custom("value requested before its key"))?;
+		seed.deserialize(value).map_err(|mut error| {
+			error.path.insert(0, key);
+			error
+		})
 	}
 }
 
@@ -65,26 +985,322 @@ impl Default for Config {
 				port: 3493,
 				username: None,
 				password: None,
+				servers: Vec::new(),
+				extra_vars: Vec::new(),
+				role: default_role(),
+				keepalive_secs: None,
+				set_vars: HashMap::new(),
+				proxy: None,
+			},
+			monitoring: MonitoringConfig {
+				poll_interval: 5,
+				max_failures_before_stale: default_max_failures_before_stale(),
+				heartbeat_interval_secs: 0,
+				startup_timeout_secs: default_startup_timeout_secs(),
+				temperature_alert_celsius: None,
+				shutdown_timeout_secs: default_shutdown_timeout_secs(),
+				notification_cooldown_secs: default_notification_cooldown_secs(),
+				failure_alert_threshold: None,
+				cycle_watchdog_secs: 0,
+				warn_levels: Vec::new(),
+				state_file: None,
+				log_lifetime_stats: false,
+				stale_after_unchanged_polls: None,
+				max_runtime_secs: None,
 			},
-			monitoring: MonitoringConfig { poll_interval: 5 },
 			shutdown: ShutdownConfig {
 				enabled: false,
 				on_battery_seconds: 300,
 				battery_percent_threshold: 20.0,
 				runtime_threshold: 180,
-				shutdown_command: "/sbin/shutdown -h +0".to_string(),
+				runtime_percent_threshold: None,
+				min_on_battery_seconds_before_action: 0,
+				stale_data_shutdown_secs: None,
+				shutdown_command: default_shutdown_command(),
+				low_battery_command: None,
+				on_battery_timeout_command: None,
 				shutdown_grace_period: 30,
+				countdown_log_interval_secs: None,
+				countdown_command: None,
+				dry_run: false,
+				strict: false,
+				notify_hosts: Vec::new(),
+				pre_shutdown_command: None,
+				pre_shutdown_timeout_secs: default_pre_shutdown_timeout_secs(),
+				command_ups_shutdown: false,
+				ups_shutdown_method: UpsShutdownMethod::default(),
+				abort_window_secs: 0,
+				shutdown_cancel_command: default_shutdown_cancel_command(),
+				retry_shutdown_command: false,
+				shutdown_command_retries: default_shutdown_command_retries(),
+				fallback_shutdown_command: None,
 			},
 			logging: LoggingConfig {
 				log_file: None,
 				log_level: "info".to_string(),
+				module_levels: HashMap::new(),
+				event_log_file: None,
+				timestamp_format: None,
+				utc: false,
 			},
 			metrics: Some(MetricsConfig {
 				enabled: false,
 				port: 8089,
 				bearer_token: None,
+				bearer_tokens: None,
 				format: Some("openmetrics".to_string()),
+				influx_measurement: None,
+				rate_limit_per_minute: None,
+				metrics_path: None,
+				admin_token: None,
+				cors_allowed_origins: Vec::new(),
+				access_log: false,
+				allow_simulation: false,
+				labels: HashMap::new(),
+				max_websocket_connections: default_max_websocket_connections(),
+				push: None,
 			}),
+			simulation: None,
+			mqtt: None,
+			history: None,
+			hooks: None,
+			notifications: Vec::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+		vars
+			.iter()
+			.map(|(name, value)| (name.to_string(), value.to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn sample_config_is_valid() {
+		let config: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+		config.validate().unwrap();
+	}
+
+	#[test]
+	fn long_poll_interval_is_warned_about() {
+		let mut config: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+		config.shutdown.enabled = true;
+		config.shutdown.runtime_threshold = 30;
+		config.monitoring.poll_interval = 10;
+		assert!(config.warnings().is_empty());
+
+		config.monitoring.poll_interval = 60;
+		let warnings = config.warnings();
+		assert_eq!(warnings.len(), 1);
+		assert!(warnings[0].contains("at most 15s"));
+	}
+
+	#[test]
+	fn metric_label_names_are_validated() {
+		let mut config: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+		let labels = &mut config.metrics.as_mut().unwrap().labels;
+		labels.insert("site".to_string(), "nyc \"east\"".to_string());
+		config.validate().unwrap();
+
+		for name in ["1rack", "env-name", "__site", "ups_name"] {
+			let mut config = config.clone();
+			let labels = &mut config.metrics.as_mut().unwrap().labels;
+			labels.insert(name.to_string(), "x".to_string());
+			assert!(config.validate().is_err(), "{} was accepted", name);
+		}
+	}
+
+	#[test]
+	fn empty_stdin_config_is_rejected() {
+		let error = read_stdin_config("  \n".as_bytes()).unwrap_err();
+		assert_eq!(error.to_string(), "no config provided on stdin");
+
+		let contents = read_stdin_config(SAMPLE_CONFIG.as_bytes()).unwrap();
+		parse_table("-", &contents).unwrap();
+	}
+
+	#[test]
+	fn invalid_timestamp_format_is_rejected() {
+		let mut config: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+		config.logging.timestamp_format = Some("%Y-%m-%dT%H:%M:%S%.3f%:z".to_string());
+		config.validate().unwrap();
+
+		config.logging.timestamp_format = Some("%Y-%Q".to_string());
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn primary_role_requires_credentials() {
+		let mut config: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+		config.ups.role = "primary".to_string();
+		config.ups.password = None;
+		assert!(config.validate().is_err());
+
+		config.ups.username = Some("upsmon".to_string());
+		config.ups.password = Some("secret".to_string());
+		config.validate().unwrap();
+	}
+
+	#[test]
+	fn keepalive_requires_primary_role() {
+		let mut config: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+		config.ups.keepalive_secs = Some(2);
+		assert!(config.validate().is_err());
+
+		config.ups.role = "primary".to_string();
+		config.ups.username = Some("upsmon".to_string());
+		config.ups.password = Some("secret".to_string());
+		config.validate().unwrap();
+	}
+
+	#[test]
+	fn telegram_channels_require_token_and_chat_id() {
+		let mut config: Config = toml::from_str(&format!(
+			"{}\n[[notifications]]\nnotification_type = \"telegram\"\nbot_token = \"123:abc\"",
+			SAMPLE_CONFIG
+		))
+		.unwrap();
+		assert!(config.validate().is_err());
+
+		config.notifications[0].chat_id = Some("-100123".to_string());
+		config.validate().unwrap();
+	}
+
+	#[test]
+	fn merge_tables_merges_tables_and_replaces_scalars() {
+		let mut base: Table =
+			toml::from_str("[ups]\nhost = \"a\"\nport = 3493\n[shutdown]\nnotify_hosts = [\"x\", \"y\"]")
+				.unwrap();
+		let overlay: Table =
+			toml::from_str("[ups]\nhost = \"b\"\n[shutdown]\nnotify_hosts = [\"z\"]").unwrap();
+
+		merge_tables(&mut base, overlay);
+
+		assert_eq!(base["ups"]["host"].as_str(), Some("b"));
+		assert_eq!(base["ups"]["port"].as_integer(), Some(3493));
+		assert_eq!(
+			base["shutdown"]["notify_hosts"].as_array().unwrap().len(),
+			1
+		);
+	}
+
+	#[test]
+	fn env_overrides_build_a_complete_config() {
+		let node = apply_env_overrides(
+			Table::new(),
+			env(&[
+				("RABBITNUT_UPS__HOST", "10.0.0.5"),
+				("RABBITNUT_UPS__NAME", "ups"),
+				("RABBITNUT_UPS__PORT", "3493"),
+				("RABBITNUT_MONITORING__POLL_INTERVAL", "5"),
+				("RABBITNUT_SHUTDOWN__ENABLED", "true"),
+				("RABBITNUT_SHUTDOWN__ON_BATTERY_SECONDS", "300"),
+				("RABBITNUT_SHUTDOWN__BATTERY_PERCENT_THRESHOLD", "20.5"),
+				("RABBITNUT_SHUTDOWN__RUNTIME_THRESHOLD", "180"),
+				("RABBITNUT_SHUTDOWN__SHUTDOWN_GRACE_PERIOD", "30"),
+				("RABBITNUT_SHUTDOWN__NOTIFY_HOSTS", r#"["a:80", "b:80"]"#),
+				("RABBITNUT_LOGGING__LOG_LEVEL", "debug"),
+			]),
+		);
+
+		let config = Config::deserialize(node).unwrap();
+		assert_eq!(config.ups.host, "10.0.0.5");
+		assert_eq!(config.monitoring.poll_interval, 5);
+		assert!(config.shutdown.enabled);
+		assert_eq!(config.shutdown.battery_percent_threshold, 20.5);
+		assert_eq!(config.shutdown.notify_hosts, vec!["a:80", "b:80"]);
+		assert_eq!(config.logging.log_level, "debug");
+	}
+
+	#[test]
+	fn env_overrides_keep_string_keys_from_the_file() {
+		#[derive(Deserialize)]
+		struct Partial {
+			ups: PartialUps,
 		}
+		#[derive(Deserialize)]
+		struct PartialUps {
+			password: String,
+			username: String,
+			port: u16,
+		}
+
+		let table: Table = toml::from_str("[ups]\npassword = \"secret\"\nport = 3493").unwrap();
+		let node = apply_env_overrides(
+			table,
+			env(&[
+				("RABBITNUT_UPS__PASSWORD", "12345"),
+				("RABBITNUT_UPS__USERNAME", "\"007\""),
+				("RABBITNUT_UPS__PORT", "3494"),
+			]),
+		);
+
+		let ups = Partial::deserialize(node).unwrap().ups;
+		assert_eq!(ups.password, "12345");
+		assert_eq!(ups.username, "007");
+		assert_eq!(ups.port, 3494);
+	}
+
+	#[test]
+	fn env_overrides_read_numbers_as_strings_where_expected() {
+		let config = Config::load_with_env(
+			"/nonexistent/rabbitnut.toml",
+			None,
+			env(&[
+				("RABBITNUT_UPS__HOST", "10.0.0.5"),
+				("RABBITNUT_UPS__NAME", "ups"),
+				("RABBITNUT_UPS__PORT", "3493"),
+				("RABBITNUT_UPS__USERNAME", "1000"),
+				("RABBITNUT_UPS__PASSWORD", "123456"),
+				("RABBITNUT_MONITORING__POLL_INTERVAL", "5"),
+				("RABBITNUT_SHUTDOWN__ENABLED", "false"),
+				("RABBITNUT_SHUTDOWN__ON_BATTERY_SECONDS", "300"),
+				("RABBITNUT_SHUTDOWN__BATTERY_PERCENT_THRESHOLD", "20"),
+				("RABBITNUT_SHUTDOWN__RUNTIME_THRESHOLD", "180"),
+				("RABBITNUT_SHUTDOWN__SHUTDOWN_GRACE_PERIOD", "30"),
+				("RABBITNUT_LOGGING__LOG_LEVEL", "info"),
+			]),
+		)
+		.unwrap();
+
+		assert_eq!(config.ups.username.as_deref(), Some("1000"));
+		assert_eq!(config.ups.password.as_deref(), Some("123456"));
+		assert_eq!(config.ups.port, 3493);
+	}
+
+	#[test]
+	fn env_override_errors_name_the_setting() {
+		let table: Table = toml::from_str(SAMPLE_CONFIG).unwrap();
+		let error = Config::deserialize(apply_env_overrides(
+			table,
+			env(&[("RABBITNUT_UPS__PORT", "not-a-port")]),
+		))
+		.err()
+		.unwrap();
+
+		assert!(error.to_string().contains("in `ups.port`"), "{}", error);
+	}
+
+	#[test]
+	fn missing_file_needs_a_complete_environment() {
+		let error = Config::load_with_env(
+			"/nonexistent/rabbitnut.toml",
+			None,
+			env(&[("RABBITNUT_UPS__HOST", "10.0.0.5")]),
+		)
+		.unwrap_err();
+
+		assert!(
+			error
+				.to_string()
+				.starts_with("Failed to read /nonexistent/rabbitnut.toml"),
+			"{}",
+			error
+		);
 	}
 }
@@ -0,0 +1,56 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Runtime switches set through the admin endpoints and read by the monitor.
+#[derive(Debug, Default)]
+pub struct ShutdownControl {
+	disabled: AtomicBool,
+	cancel_requested: AtomicBool,
+	simulated_outage: Mutex<Option<SimulatedOutage>>,
+}
+
+/// Fake on-battery reading injected through `POST /admin/simulate-outage`.
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatedOutage {
+	pub battery_charge: f64,
+	pub battery_runtime: u64,
+	until: Instant,
+}
+
+impl ShutdownControl {
+	pub fn set_disabled(&self, disabled: bool) {
+		self.disabled.store(disabled, Ordering::SeqCst);
+	}
+
+	pub fn is_disabled(&self) -> bool {
+		self.disabled.load(Ordering::SeqCst)
+	}
+
+	pub fn request_cancel(&self) {
+		self.cancel_requested.store(true, Ordering::SeqCst);
+	}
+
+	/// Returns whether a cancel was requested, clearing the request.
+	pub fn take_cancel(&self) -> bool {
+		self.cancel_requested.swap(false, Ordering::SeqCst)
+	}
+
+	/// Reports the UPS on battery with these readings until `duration` elapses.
+	pub fn simulate_outage(&self, battery_charge: f64, battery_runtime: u64, duration: Duration) {
+		*self.simulated_outage.lock().unwrap() = Some(SimulatedOutage {
+			battery_charge,
+			battery_runtime,
+			until: Instant::now() + duration,
+		});
+	}
+
+	/// The simulated outage in progress, if any; expired ones are cleared.
+	pub fn simulated_outage(&self) -> Option<SimulatedOutage> {
+		let mut outage = self.simulated_outage.lock().unwrap();
+		if outage.is_some_and(|outage| Instant::now() >= outage.until) {
+			*outage = None;
+		}
+		*outage
+	}
+}
@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::monitor::ShutdownReason;
+use crate::ups::UpsStatus;
+
+/// Receives what the monitor observes, for reacting in-process. Every method
+/// does nothing by default, so handlers only implement what they need.
+///
+/// Handlers run on the monitor task and should return quickly; a panic is
+/// logged and doesn't stop the monitor.
+pub trait EventHandler: Send + Sync {
+	/// After every successful poll.
+	fn on_poll(&self, _status: &UpsStatus) {}
+
+	/// When the UPS switches to battery power.
+	fn on_battery(&self, _status: &UpsStatus) {}
+
+	/// When line power returns after an outage.
+	fn on_line(&self, _status: &UpsStatus) {}
+
+	/// When a shutdown threshold is reached, before the grace period.
+	fn on_threshold(&self, _reason: ShutdownReason) {}
+
+	/// After the grace period, right before `pre_shutdown_command` and the
+	/// shutdown command run. Not called in dry-run mode.
+	fn pre_shutdown(&self, _reason: ShutdownReason) {}
+
+	/// With every event that is notified, after `notification_cooldown_secs`
+	/// has held back and collapsed flapping transitions.
+	fn on_event(&self, _event: &PowerEvent) {}
+}
+
+impl<T: EventHandler + ?Sized> EventHandler for Arc<T> {
+	fn on_poll(&self, status: &UpsStatus) {
+		(**self).on_poll(status)
+	}
+
+	fn on_battery(&self, status: &UpsStatus) {
+		(**self).on_battery(status)
+	}
+
+	fn on_line(&self, status: &UpsStatus) {
+		(**self).on_line(status)
+	}
+
+	fn on_threshold(&self, reason: ShutdownReason) {
+		(**self).on_threshold(reason)
+	}
+
+	fn pre_shutdown(&self, reason: ShutdownReason) {
+		(**self).pre_shutdown(reason)
+	}
+
+	fn on_event(&self, event: &PowerEvent) {
+		(**self).on_event(event)
+	}
+}
+
+/// A discrete power event worth keeping for post-incident analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+	OnBattery,
+	OnLine,
+	ShutdownCountdown,
+	ShutdownTriggered,
+	ShutdownCancelled,
+	TemperatureHigh,
+	PollFailing,
+	PollRecovered,
+	BatteryLow,
+	ReplaceBattery,
+	Overload,
+	OnBypass,
+}
+
+impl EventType {
+	/// The opposite power transition, which cancels out a pending notification.
+	pub fn opposite(&self) -> Option<EventType> {
+		match self {
+			EventType::OnBattery => Some(EventType::OnLine),
+			EventType::OnLine => Some(EventType::OnBattery),
+			_ => None,
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			EventType::OnBattery => "on_battery",
+			EventType::OnLine => "on_line",
+			EventType::ShutdownCountdown => "shutdown_countdown",
+			EventType::ShutdownTriggered => "shutdown_triggered",
+			EventType::ShutdownCancelled => "shutdown_cancelled",
+			EventType::TemperatureHigh => "temperature_high",
+			EventType::PollFailing => "poll_failing",
+			EventType::PollRecovered => "poll_recovered",
+			EventType::BatteryLow => "battery_low",
+			EventType::ReplaceBattery => "replace_battery",
+			EventType::Overload => "overload",
+			EventType::OnBypass => "on_bypass",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PowerEvent {
+	pub timestamp: String,
+	pub event: EventType,
+	pub battery_charge: Option<f64>,
+	pub battery_runtime: Option<u64>,
+	pub ups_status: Option<String>,
+	pub on_battery_duration_seconds: Option<u64>,
+	pub reason: Option<String>,
+}
+
+/// Append-only JSON Lines file with one `PowerEvent` per line.
+pub struct EventLog {
+	file: File,
+}
+
+impl EventLog {
+	pub fn open(path: &str) -> io::Result<Self> {
+		if let Some(parent) = Path::new(path).parent()
+			&& !parent.as_os_str().is_empty()
+			&& !parent.exists()
+		{
+			std::fs::create_dir_all(parent)?;
+		}
+
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		Ok(EventLog { file })
+	}
+
+	pub fn append(&mut self, event: &PowerEvent) -> io::Result<()> {
+		let mut line = serde_json::to_string(event)?;
+		line.push('\n');
+		self.file.write_all(line.as_bytes())
+	}
+}
@@ -0,0 +1,150 @@
+use rusqlite::{Connection, params};
+use std::path::Path;
+
+use crate::events::{EventType, PowerEvent};
+
+// Number of outages listed by `rabbitnut history`
+const RECENT_OUTAGES: usize = 20;
+
+/// SQLite store of power events for querying outage history.
+pub struct History {
+	connection: Connection,
+}
+
+/// An on-battery period reconstructed from the stored events.
+struct Outage {
+	started: String,
+	duration_seconds: Option<u64>,
+	lowest_charge: Option<f64>,
+	shutdown_triggered: bool,
+}
+
+impl History {
+	pub fn open(path: &str) -> rusqlite::Result<Self> {
+		if let Some(parent) = Path::new(path).parent()
+			&& !parent.as_os_str().is_empty()
+			&& !parent.exists()
+		{
+			let _ = std::fs::create_dir_all(parent);
+		}
+
+		let connection = Connection::open(path)?;
+		connection.execute_batch(
+			"CREATE TABLE IF NOT EXISTS events (
+				id INTEGER PRIMARY KEY AUTOINCREMENT,
+				timestamp TEXT NOT NULL,
+				event_type TEXT NOT NULL,
+				charge REAL,
+				runtime INTEGER,
+				on_battery_duration INTEGER
+			);",
+		)?;
+
+		Ok(History { connection })
+	}
+
+	pub fn insert(&self, event: &PowerEvent) -> rusqlite::Result<()> {
+		self.connection.execute(
+			"INSERT INTO events (timestamp, event_type, charge, runtime, on_battery_duration)
+			VALUES (?1, ?2, ?3, ?4, ?5)",
+			params![
+				event.timestamp,
+				event.event.as_str(),
+				event.battery_charge,
+				event.battery_runtime.map(|runtime| runtime as i64),
+				event
+					.on_battery_duration_seconds
+					.map(|duration| duration as i64),
+			],
+		)?;
+		Ok(())
+	}
+
+	// Pair each transfer to battery with the following return to line power
+	fn outages(&self) -> rusqlite::Result<Vec<Outage>> {
+		let mut statement = self.connection.prepare(
+			"SELECT timestamp, event_type, charge, on_battery_duration FROM events ORDER BY id",
+		)?;
+		let rows = statement.query_map([], |row| {
+			Ok((
+				row.get::<_, String>(0)?,
+				row.get::<_, String>(1)?,
+				row.get::<_, Option<f64>>(2)?,
+				row.get::<_, Option<i64>>(3)?,
+			))
+		})?;
+
+		let mut outages: Vec<Outage> = Vec::new();
+		let mut ongoing: Option<Outage> = None;
+
+		for row in rows {
+			let (timestamp, event_type, charge, duration) = row?;
+
+			if event_type == EventType::OnBattery.as_str() {
+				outages.extend(ongoing.take());
+				ongoing = Some(Outage {
+					started: timestamp,
+					duration_seconds: None,
+					lowest_charge: charge,
+					shutdown_triggered: false,
+				});
+				continue;
+			}
+
+			let Some(ref mut outage) = ongoing else {
+				continue;
+			};
+
+			if let Some(charge) = charge {
+				outage.lowest_charge = Some(outage.lowest_charge.map_or(charge, |c| c.min(charge)));
+			}
+
+			if event_type == EventType::ShutdownTriggered.as_str() {
+				outage.shutdown_triggered = true;
+			} else if event_type == EventType::OnLine.as_str() {
+				outage.duration_seconds = duration.map(|d| d as u64);
+				outages.extend(ongoing.take());
+			}
+		}
+
+		outages.extend(ongoing);
+		Ok(outages)
+	}
+}
+
+/// Prints the most recent outages, newest first, for `rabbitnut history`.
+pub fn print_recent_outages(database_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+	let history = History::open(database_path)?;
+	let outages = history.outages()?;
+
+	if outages.is_empty() {
+		println!("No outages recorded in {}", database_path);
+		return Ok(());
+	}
+
+	println!("Recent outages (newest first):");
+	for outage in outages.iter().rev().take(RECENT_OUTAGES) {
+		let duration = match outage.duration_seconds {
+			Some(seconds) => format!("{}m {}s", seconds / 60, seconds % 60),
+			None => "ongoing".to_string(),
+		};
+		let lowest_charge = match outage.lowest_charge {
+			Some(charge) => format!("{}%", charge),
+			None => "unknown".to_string(),
+		};
+
+		println!(
+			"  {}  duration: {}  lowest charge: {}{}",
+			outage.started,
+			duration,
+			lowest_charge,
+			if outage.shutdown_triggered {
+				"  (shutdown triggered)"
+			} else {
+				""
+			}
+		);
+	}
+
+	Ok(())
+}
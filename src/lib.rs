@@ -0,0 +1,50 @@
+//! UPS monitoring for Network UPS Tools servers with automatic shutdown.
+//!
+//! The `rabbitnut` binary is a thin wrapper around this crate; services can
+//! embed the same monitor and react to power events in-process:
+//!
+//! ```no_run
+//! use rabbitnut::{Config, UpsMonitor};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let config = Config::load("config.toml", None)?;
+//!     config.validate()?;
+//!
+//!     let mut monitor = UpsMonitor::new(config)
+//!         .on_battery(|status| println!("On battery: {}", status))
+//!         .on_line(|status| println!("Back on line power: {}", status))
+//!         .pre_shutdown(|reason| println!("Shutting down: {}", reason));
+//!     monitor.run().await;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! [`UpsClient`] can also be used on its own to read a UPS once.
+
+mod address;
+pub mod command;
+pub mod config;
+mod control;
+pub mod events;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod logging;
+mod metrics;
+#[cfg(test)]
+mod mock_nut;
+pub mod monitor;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod notify;
+mod rate_limit;
+mod simulation;
+mod socks;
+mod state_file;
+pub mod ups;
+mod watchdog;
+
+pub use config::Config;
+pub use events::{EventHandler, PowerEvent};
+pub use monitor::{ShutdownReason, UpsMonitor};
+pub use ups::{UpsClient, UpsError, UpsSource, UpsStatus};
@@ -1,4 +1,4 @@
-use chrono::Local;
+use chrono::{Local, Utc};
 use log::LevelFilter;
 use std::fs::OpenOptions;
 use std::path::Path;
@@ -10,12 +10,26 @@ pub fn setup_logging(config: &LoggingConfig) -> Result<(), Box<dyn std::error::E
 
 	let mut dispatch = fern::Dispatch::new()
 		.level(log_level)
-		.level_for("rabbitnut", log_level)
-		.chain(create_stdout_logger(log_level));
+		.level_for("rabbitnut", log_level);
+
+	// The chained loggers filter too, so they must let the most verbose override through
+	let mut max_level = log_level;
+	for (target, level) in &config.module_levels {
+		let level = parse_log_level(level);
+		dispatch = dispatch.level_for(target.clone(), level);
+		max_level = max_level.max(level);
+	}
+
+	let timestamp = Timestamp {
+		format: config.timestamp_format().to_string(),
+		utc: config.utc,
+	};
+
+	dispatch = dispatch.chain(create_stdout_logger(max_level, timestamp.clone()));
 
 	if let Some(log_file) = &config.log_file {
 		ensure_log_file_exists(log_file)?;
-		dispatch = dispatch.chain(create_file_logger(log_file, log_level)?);
+		dispatch = dispatch.chain(create_file_logger(log_file, max_level, timestamp)?);
 	}
 
 	dispatch.apply()?;
@@ -38,9 +52,26 @@ fn parse_log_level(level: &str) -> LevelFilter {
 	}
 }
 
-fn create_stdout_logger(level: LevelFilter) -> fern::Dispatch {
+// How each log line's time is written, shared by the stdout and file loggers
+#[derive(Clone)]
+struct Timestamp {
+	format: String,
+	utc: bool,
+}
+
+impl Timestamp {
+	fn now(&self) -> String {
+		if self.utc {
+			Utc::now().format(&self.format).to_string()
+		} else {
+			Local::now().format(&self.format).to_string()
+		}
+	}
+}
+
+fn create_stdout_logger(level: LevelFilter, timestamp: Timestamp) -> fern::Dispatch {
 	fern::Dispatch::new()
-		.format(|out, message, record| {
+		.format(move |out, message, record| {
 			use colored::*;
 
 			let level_string = match record.level() {
@@ -53,7 +84,7 @@ fn create_stdout_logger(level: LevelFilter) -> fern::Dispatch {
 
 			out.finish(format_args!(
 				"{} {} {}",
-				Local::now().format("%Y-%m-%d %H:%M:%S"),
+				timestamp.now(),
 				level_string,
 				message
 			))
@@ -65,13 +96,14 @@ fn create_stdout_logger(level: LevelFilter) -> fern::Dispatch {
 fn create_file_logger(
 	path: &str,
 	level: LevelFilter,
+	timestamp: Timestamp,
 ) -> Result<fern::Dispatch, Box<dyn std::error::Error>> {
 	Ok(
 		fern::Dispatch::new()
-			.format(|out, message, record| {
+			.format(move |out, message, record| {
 				out.finish(format_args!(
 					"{} [{}] {}",
-					chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+					timestamp.now(),
 					record.level(),
 					message
 				))
@@ -83,10 +115,10 @@ fn create_file_logger(
 
 fn ensure_log_file_exists(path: &str) -> Result<(), Box<dyn std::error::Error>> {
 	// Create parent directories if they don't exist
-	if let Some(parent) = Path::new(path).parent() {
-		if !parent.exists() {
-			std::fs::create_dir_all(parent)?;
-		}
+	if let Some(parent) = Path::new(path).parent()
+		&& !parent.exists()
+	{
+		std::fs::create_dir_all(parent)?;
 	}
 
 	OpenOptions::new().create(true).append(true).open(path)?;
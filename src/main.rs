@@ -1,53 +1,237 @@
-mod config;
-mod logging;
-mod metrics;
-mod monitor;
-mod ups;
+mod cli;
 
-use log::info;
-use std::env;
+use clap::Parser;
+use log::{error, info, warn};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tokio::runtime::Runtime;
 
-use crate::config::Config;
-use crate::logging::setup_logging;
-use crate::monitor::UpsMonitor;
+use crate::cli::{Args, Command};
+use rabbitnut::command::find_program;
+use rabbitnut::config::{Config, SAMPLE_CONFIG};
+#[cfg(feature = "history")]
+use rabbitnut::history;
+use rabbitnut::logging::setup_logging;
+use rabbitnut::monitor::UpsMonitor;
+use rabbitnut::ups::{UpsClient, UpsError, UpsSource};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-	let args: Vec<String> = env::args().collect();
+	let args = Args::parse();
 
-	if args.iter().any(|a| a == "--version" || a == "-v") {
-		println!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
-		return Ok(());
+	if let Some(ref path) = args.generate_config {
+		return generate_config(path, args.force);
+	}
+	let config_path = args.config_path().to_string();
+
+	let mut config = Config::load(&config_path, args.config_dir.as_deref())?;
+
+	// `rabbitnut history [config]` lists recent outages instead of monitoring
+	if let Some(Command::History { .. }) = args.command {
+		return print_history(&config);
 	}
 
-	let config_path = env::args()
-		.nth(1)
-		.unwrap_or_else(|| "config.toml".to_string());
+	args.apply(&mut config);
 
-	let config = Config::from_file(&config_path)?;
+	config.validate()?;
+
+	if args.test_connection {
+		return test_connection(&config);
+	}
 
 	setup_logging(&config.logging)?;
 
 	info!("UPS Monitor started with config: {}", config_path);
 
-	if let Some(ref metrics) = config.metrics {
-		if metrics.enabled {
+	for warning in config.warnings() {
+		warn!("{}", warning);
+	}
+
+	if config.shutdown.dry_run {
+		warn!("Dry run mode enabled - shutdown commands will be logged but not executed");
+	}
+
+	if config.simulation.as_ref().is_some_and(|s| s.enabled) {
+		warn!("Simulation mode enabled - UPS readings are scripted, not read from the NUT server");
+
+		if !config.shutdown.dry_run && config.shutdown.enabled {
+			warn!("Simulation mode without dry run will execute the real shutdown command!");
+		}
+	}
+
+	if config.shutdown.enabled {
+		check_shutdown_commands(&config)?;
+	} else {
+		warn!("Shutdown DISABLED - monitoring/metrics only, no shutdown will ever be issued");
+	}
+
+	if let Some(ref metrics) = config.metrics
+		&& metrics.enabled
+	{
+		info!(
+			"Metrics API enabled on port {} at {} (format: {})",
+			metrics.port,
+			metrics.metrics_path(),
+			metrics
+				.format
+				.as_ref()
+				.unwrap_or(&"openmetrics".to_string())
+		);
+
+		let tokens = metrics.tokens().len();
+		if tokens > 0 {
 			info!(
-				"Metrics API enabled on port {} (format: {})",
-				metrics.port,
-				metrics
-					.format
-					.as_ref()
-					.unwrap_or(&"openmetrics".to_string())
+				"Bearer token authentication enabled for metrics endpoint ({} token(s))",
+				tokens
 			);
+		}
+	}
 
-			if metrics.bearer_token.is_some() {
-				info!("Bearer token authentication enabled for metrics endpoint");
-			}
+	if let Some(ref mqtt) = config.mqtt {
+		if cfg!(feature = "mqtt") {
+			info!(
+				"MQTT publishing enabled to {}:{} (base topic: {})",
+				mqtt.host, mqtt.port, mqtt.base_topic
+			);
+		} else {
+			warn!("MQTT is configured but this build was compiled without the 'mqtt' feature");
 		}
 	}
 
-	let mut monitor: UpsMonitor = UpsMonitor::new(config);
-	monitor.run();
+	if config.history.is_some() && !cfg!(feature = "history") {
+		warn!("History is configured but this build was compiled without the 'history' feature");
+	}
+
+	// The monitor, metrics server, notifications and MQTT client share one
+	// runtime; the history subcommand above runs without one
+	let shutdown_timeout = Duration::from_secs(config.monitoring.shutdown_timeout_secs);
+	let runtime = Runtime::new()?;
+
+	if args.once {
+		let code = runtime.block_on(async { UpsMonitor::new(config).check_once().await });
+		runtime.shutdown_timeout(shutdown_timeout);
+		std::process::exit(code);
+	}
+
+	runtime.block_on(async {
+		let mut monitor = UpsMonitor::new(config);
+		monitor.run().await;
+	});
+	runtime.shutdown_timeout(shutdown_timeout);
 
 	Ok(())
 }
+
+// Catch a mistyped or missing shutdown command at boot rather than during an outage
+fn check_shutdown_commands(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+	let shutdown = &config.shutdown;
+	let commands = [
+		("shutdown_command", Some(&shutdown.shutdown_command)),
+		("low_battery_command", shutdown.low_battery_command.as_ref()),
+		(
+			"pre_shutdown_command",
+			shutdown.pre_shutdown_command.as_ref(),
+		),
+		(
+			"on_battery_timeout_command",
+			shutdown.on_battery_timeout_command.as_ref(),
+		),
+		(
+			"fallback_shutdown_command",
+			shutdown.fallback_shutdown_command.as_ref(),
+		),
+		("countdown_command", shutdown.countdown_command.as_ref()),
+	];
+
+	let mut invalid = 0;
+	for (name, command) in commands {
+		let Some(command) = command else {
+			continue;
+		};
+
+		if find_program(command).is_none() {
+			error!(
+				"🔴 shutdown.{} '{}' is not an executable on this system - automatic shutdown will fail!",
+				name, command
+			);
+			invalid += 1;
+		}
+	}
+
+	if invalid > 0 && shutdown.strict {
+		return Err(
+			format!(
+				"{} shutdown command(s) can't be executed (shutdown.strict is set)",
+				invalid
+			)
+			.into(),
+		);
+	}
+
+	Ok(())
+}
+
+// Connect once like the monitor would and report the first problem found
+fn test_connection(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+	let client = UpsClient::from_config(&config.ups);
+	let address = client
+		.current_server()
+		.unwrap_or_else(|| config.ups.host.clone());
+
+	let runtime = Runtime::new()?;
+	match runtime.block_on(client.check_connection()) {
+		Ok(()) => {
+			println!("Connection OK: UPS '{}' on {}", config.ups.name, address);
+			Ok(())
+		}
+		Err(UpsError::Io(e)) => Err(format!("Can't reach the NUT server at {}: {}", address, e).into()),
+		Err(UpsError::UnknownUps) => {
+			let available = runtime
+				.block_on(client.list_ups())
+				.unwrap_or_default()
+				.into_iter()
+				.map(|(name, _)| name)
+				.collect::<Vec<_>>()
+				.join(", ");
+			Err(
+				format!(
+					"UPS '{}' does not exist on {}, check ups.name (available: {})",
+					config.ups.name, address, available
+				)
+				.into(),
+			)
+		}
+		Err(e) => Err(format!("NUT server at {} rejected the connection: {}", address, e).into()),
+	}
+}
+
+// Write the sample configuration, to stdout for "-"
+fn generate_config(path: &str, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+	if path == "-" {
+		writeln!(io::stdout(), "{}", SAMPLE_CONFIG)?;
+		return Ok(());
+	}
+
+	if Path::new(path).exists() && !force {
+		return Err(format!("{} already exists, pass --force to overwrite it", path).into());
+	}
+
+	fs::write(path, SAMPLE_CONFIG)?;
+	println!("Wrote sample configuration to {}", path);
+	Ok(())
+}
+
+#[cfg(feature = "history")]
+fn print_history(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+	let history = config
+		.history
+		.as_ref()
+		.ok_or("No [history] section in the configuration")?;
+	history::print_recent_outages(&history.database_path)
+}
+
+#[cfg(not(feature = "history"))]
+fn print_history(_config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+	Err("This build was compiled without the 'history' feature".into())
+}
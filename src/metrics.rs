@@ -1,29 +1,126 @@
 use axum::{
 	Json, Router,
-	extract::State,
-	http::{HeaderMap, StatusCode},
-	response::{IntoResponse, Response},
-	routing::get,
+	extract::{
+		ConnectInfo, Query, Request, State,
+		ws::{Message, WebSocket, WebSocketUpgrade},
+	},
+	http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+	middleware::{self, Next},
+	response::{
+		Html, IntoResponse, Response,
+		sse::{Event, KeepAlive, Sse},
+	},
+	routing::{get, post},
 };
-use log::info;
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_stream::{Stream, StreamExt, wrappers::WatchStream};
+use tower_http::compression::{CompressionLayer, predicate::SizeAbove};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
-use crate::config::MetricsConfig;
-use crate::ups::UpsStatus;
+use crate::config::{MetricsConfig, PushConfig};
+use crate::control::ShutdownControl;
+use crate::rate_limit::{RateLimiter, rate_limit};
+
+// Responses smaller than this aren't worth gzipping
+const MIN_COMPRESSION_SIZE: u16 = 1024;
+
+// Upper bound for each Pushgateway request
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// Longest outage `POST /admin/simulate-outage` may inject
+const MAX_SIMULATED_OUTAGE_SECS: u64 = 3600;
+
+/// Paths serving one rendering each, whatever `format` the bare metrics path uses.
+pub const FORMAT_PATHS: [(&str, &str); 4] = [
+	("/metrics.openmetrics", "openmetrics"),
+	("/metrics.prometheus", "prometheus"),
+	("/metrics.json", "json"),
+	("/metrics.influx", "influx"),
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Metrics {
 	pub ups_name: String,
 	pub ups_host: String,
-	pub battery_charge_percent: f64,
-	pub battery_runtime_seconds: u64,
+	pub nut_server: Option<String>,
+	pub server_failovers: u64,
+	pub battery_charge_percent: Option<f64>,
+	pub battery_runtime_seconds: Option<u64>,
 	pub ups_status: String,
 	pub on_battery: bool,
+	pub charging: bool,
+	pub replace_battery: bool,
+	pub overload: bool,
+	pub on_bypass: bool,
+	pub up: bool,
+	pub data_stale: bool,
+	pub consecutive_poll_failures: u32,
 	pub last_update: i64,
 	pub on_battery_duration_seconds: Option<u64>,
+	pub last_outage_duration_seconds: Option<u64>,
+	pub last_outage_timestamp: Option<i64>,
+	pub seconds_since_last_transfer: Option<u64>,
 	pub output_power_watts: Option<f64>,
+	pub battery_energy_remaining_watthours: Option<f64>,
+	pub battery_temperature_celsius: Option<f64>,
+	pub ups_temperature_celsius: Option<f64>,
+	pub input_frequency_hertz: Option<f64>,
+	pub battery_volts: Option<f64>,
+	pub battery_nominal_volts: Option<f64>,
+	pub battery_charge_restart_percent: Option<f64>,
+	pub battery_date: Option<String>,
+	pub battery_age_days: Option<i64>,
+	pub extra_vars: HashMap<String, String>,
+	pub poll_duration_seconds: Option<f64>,
+	pub poll_interval_seconds: u64,
+	pub seconds_until_shutdown: Option<f64>,
+	pub discharge_rate_percent_per_minute: Option<f64>,
+	pub shutdown: ShutdownThresholds,
+	pub lifetime: LifetimeStats,
+	// Static identity read at startup, exported as `ups_info` labels
+	pub device_info: BTreeMap<String, String>,
+}
+
+/// Lowest, highest and average value of a reading since startup.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ReadingStats {
+	pub min: f64,
+	pub max: f64,
+	pub avg: f64,
+}
+
+/// Aggregates of the key readings since the process started; each is None
+/// until the UPS has reported that value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LifetimeStats {
+	pub battery_charge_percent: Option<ReadingStats>,
+	pub battery_runtime_seconds: Option<ReadingStats>,
+	pub output_power_watts: Option<ReadingStats>,
+	pub battery_volts: Option<ReadingStats>,
+}
+
+/// The configured shutdown triggers, exported so dashboards can draw them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShutdownThresholds {
+	pub enabled: bool,
+	pub dry_run: bool,
+	pub battery_percent_threshold: f64,
+	// Effective value: the larger of runtime_threshold and runtime_percent_threshold
+	pub runtime_threshold: u64,
+	pub runtime_percent_threshold: Option<f64>,
+	pub on_battery_seconds: u64,
+	pub min_on_battery_seconds_before_action: u64,
+	pub grace_period_seconds: u64,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -33,48 +130,76 @@ pub struct JsonMetricsResponse {
 	pub metrics: Metrics,
 }
 
+/// Always-JSON body of `/status`, independent of the configured metrics format.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusResponse {
+	pub timestamp: i64,
+	#[serde(flatten)]
+	pub metrics: Metrics,
+}
+
 #[derive(Clone)]
 pub struct MetricsServer {
 	config: MetricsConfig,
-	metrics: Arc<RwLock<Option<Metrics>>>,
+	metrics: watch::Sender<Option<Metrics>>,
+	control: Arc<ShutdownControl>,
+	stop: watch::Sender<bool>,
+	startup: Arc<StartupState>,
+}
+
+/// What happened before the first successful poll, explaining why there are no metrics yet.
+#[derive(Debug)]
+struct StartupState {
+	started_at: i64,
+	failed_polls: AtomicU32,
+	last_error: Mutex<Option<String>>,
 }
 
 #[derive(Clone)]
 struct AppState {
-	metrics: Arc<RwLock<Option<Metrics>>>,
-	bearer_token: Option<String>,
+	metrics: watch::Receiver<Option<Metrics>>,
+	startup: Arc<StartupState>,
+	bearer_tokens: Arc<Vec<String>>,
+	admin_token: Option<String>,
+	allow_simulation: bool,
+	control: Arc<ShutdownControl>,
 	format: String,
+	influx_measurement: String,
+	// User-defined `labels`, sorted by name
+	labels: Arc<Vec<(String, String)>>,
+	websockets: Arc<AtomicUsize>,
+	max_websockets: usize,
 }
 
 impl MetricsServer {
-	pub fn new(config: MetricsConfig) -> Self {
+	pub fn new(config: MetricsConfig, control: Arc<ShutdownControl>) -> Self {
 		MetricsServer {
 			config,
-			metrics: Arc::new(RwLock::new(None)),
+			metrics: watch::channel(None).0,
+			control,
+			stop: watch::channel(false).0,
+			startup: Arc::new(StartupState {
+				started_at: chrono::Utc::now().timestamp(),
+				failed_polls: AtomicU32::new(0),
+				last_error: Mutex::new(None),
+			}),
 		}
 	}
 
-	pub async fn update_metrics(
-		&self,
-		ups_name: String,
-		ups_host: String,
-		status: UpsStatus,
-		on_battery_duration: Option<u64>,
-	) {
-		let metrics = Metrics {
-			ups_name,
-			ups_host,
-			battery_charge_percent: status.battery_charge,
-			battery_runtime_seconds: status.battery_runtime,
-			ups_status: status.ups_status,
-			on_battery: status.on_battery,
-			last_update: chrono::Utc::now().timestamp(),
-			on_battery_duration_seconds: on_battery_duration,
-			output_power_watts: status.output_power,
-		};
+	/// Records a failed poll, reported while no poll has succeeded yet.
+	pub fn record_poll_failure(&self, error: &str) {
+		self.startup.failed_polls.fetch_add(1, Ordering::Relaxed);
+		*self.startup.last_error.lock().unwrap() = Some(error.to_string());
+	}
 
-		let mut m = self.metrics.write().await;
-		*m = Some(metrics);
+	/// Publishes the latest reading; handlers always see the most recent value.
+	pub fn update_metrics(&self, metrics: Metrics) {
+		self.metrics.send_replace(Some(metrics));
+	}
+
+	/// Stops accepting connections and lets in-flight requests finish.
+	pub fn stop(&self) {
+		self.stop.send_replace(true);
 	}
 
 	pub async fn start(self: Arc<Self>) {
@@ -82,178 +207,1374 @@ impl MetricsServer {
 		info!("Starting metrics server on port {}", port);
 
 		let state = AppState {
-			metrics: self.metrics.clone(),
-			bearer_token: self.config.bearer_token.clone(),
+			metrics: self.metrics.subscribe(),
+			startup: self.startup.clone(),
+			bearer_tokens: Arc::new(self.config.tokens()),
+			admin_token: self.config.admin_token.clone(),
+			allow_simulation: self.config.allow_simulation,
+			control: self.control.clone(),
 			format: self
 				.config
 				.format
 				.clone()
 				.unwrap_or_else(|| "openmetrics".to_string()),
+			influx_measurement: self
+				.config
+				.influx_measurement
+				.clone()
+				.unwrap_or_else(|| "ups".to_string()),
+			labels: Arc::new({
+				let mut labels: Vec<_> = self
+					.config
+					.labels
+					.iter()
+					.map(|(name, value)| (name.clone(), value.clone()))
+					.collect();
+				labels.sort();
+				labels
+			}),
+			websockets: Arc::new(AtomicUsize::new(0)),
+			max_websockets: self.config.max_websocket_connections,
 		};
 
-		let app = Router::new()
-			.route("/metrics", get(handle_metrics))
-			.route("/health", get(handle_health))
-			.with_state(state);
+		let mut app = Router::new()
+			.route("/", get(handle_status_page))
+			.route("/events", get(handle_events))
+			.route("/ws", get(handle_websocket))
+			.route(
+				self.config.metrics_path(),
+				get(handle_metrics).layer(compression()),
+			)
+			.route("/status", get(handle_status))
+			.route("/admin/cancel-shutdown", post(handle_cancel_shutdown))
+			.route("/admin/disable", post(handle_disable_shutdown))
+			.route("/admin/enable", post(handle_enable_shutdown))
+			.route("/admin/simulate-outage", post(handle_simulate_outage));
+
+		for (path, format) in FORMAT_PATHS {
+			app = app.route(
+				path,
+				get(move |headers: HeaderMap, state: State<AppState>| {
+					render_metrics(headers, state, format)
+				})
+				.layer(compression()),
+			);
+		}
+
+		// Health checks are exempt from rate limiting so probes are never throttled
+		if let Some(per_minute) = self.config.rate_limit_per_minute {
+			info!(
+				"Metrics rate limit: {} requests per minute per client",
+				per_minute
+			);
+			let limiter = Arc::new(RateLimiter::new(per_minute));
+			app = app.route_layer(middleware::from_fn_with_state(limiter, rate_limit));
+		}
+
+		let mut app = app.route("/health", get(handle_health)).with_state(state);
+
+		// Outermost so preflight requests and rate-limited responses carry the headers too
+		if let Some(cors) = cors_layer(&self.config.cors_allowed_origins) {
+			app = app.layer(cors);
+		}
+
+		if self.config.access_log {
+			app = app.layer(middleware::from_fn(access_log));
+		}
 
 		let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
 			.await
 			.expect("Failed to bind to address");
 
-		axum::serve(listener, app)
-			.await
-			.expect("Failed to start server");
+		let mut stop = self.stop.subscribe();
+		axum::serve(
+			listener,
+			app.into_make_service_with_connect_info::<SocketAddr>(),
+		)
+		.with_graceful_shutdown(async move {
+			let _ = stop.wait_for(|stopped| *stopped).await;
+		})
+		.await
+		.expect("Failed to start server");
+	}
+}
+
+// One line per request through the regular logger, with any `?token=` redacted
+async fn access_log(
+	ConnectInfo(addr): ConnectInfo<SocketAddr>,
+	request: Request,
+	next: Next,
+) -> Response {
+	let method = request.method().clone();
+	let path = match request.uri().query() {
+		Some(query) => format!("{}?{}", request.uri().path(), redact_token(query)),
+		None => request.uri().path().to_string(),
+	};
+	let started = std::time::Instant::now();
+
+	let response = next.run(request).await;
+
+	info!(
+		"{} {} {} {} {:.1}ms",
+		addr.ip(),
+		method,
+		path,
+		response.status().as_u16(),
+		started.elapsed().as_secs_f64() * 1000.0
+	);
+	response
+}
+
+// Replace the value of every `token` parameter so it never reaches the logs
+fn redact_token(query: &str) -> String {
+	query
+		.split('&')
+		.map(|pair| match pair.split_once('=') {
+			Some(("token", _)) => "token=REDACTED",
+			_ => pair,
+		})
+		.collect::<Vec<_>>()
+		.join("&")
+}
+
+// Browser access from other origins, off unless origins are configured
+fn cors_layer(origins: &[String]) -> Option<CorsLayer> {
+	if origins.is_empty() {
+		return None;
 	}
+
+	let allow_origin = if origins.iter().any(|origin| origin == "*") {
+		AllowOrigin::any()
+	} else {
+		// Invalid origins are rejected by `Config::validate`
+		AllowOrigin::list(
+			origins
+				.iter()
+				.filter_map(|origin| origin.parse::<HeaderValue>().ok()),
+		)
+	};
+
+	info!("CORS enabled for origins: {}", origins.join(", "));
+	Some(
+		CorsLayer::new()
+			.allow_origin(allow_origin)
+			.allow_methods([Method::GET, Method::POST])
+			.allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE]),
+	)
 }
 
 async fn handle_health() -> impl IntoResponse {
 	(StatusCode::OK, "OK")
 }
 
-async fn handle_metrics(
+// Check the bearer token if any are configured. EventSource and WebSocket
+// connections can't set headers, so those two pass their query to allow `?token=`.
+fn is_authorized(
+	headers: &HeaderMap,
+	query: Option<&HashMap<String, String>>,
+	state: &AppState,
+) -> bool {
+	if state.bearer_tokens.is_empty() {
+		return true;
+	}
+
+	let header_token = headers
+		.get("authorization")
+		.and_then(|h| h.to_str().ok())
+		.and_then(|h| h.strip_prefix("Bearer "));
+	let query_token = query.and_then(|q| q.get("token")).map(String::as_str);
+
+	let Some(token) = header_token.or(query_token) else {
+		return false;
+	};
+
+	// Check every token so the response time doesn't reveal which one matched
+	state.bearer_tokens.iter().fold(false, |matched, required| {
+		constant_time_eq(token.as_bytes(), required.as_bytes()) | matched
+	})
+}
+
+// Admin routes use `admin_token` when set and the bearer tokens otherwise.
+// They are refused outright when no token is configured at all.
+fn check_admin(headers: &HeaderMap, state: &AppState) -> Result<(), (StatusCode, &'static str)> {
+	let tokens = match state.admin_token {
+		Some(ref admin_token) => std::slice::from_ref(admin_token),
+		None => state.bearer_tokens.as_slice(),
+	};
+
+	if tokens.is_empty() {
+		return Err((
+			StatusCode::FORBIDDEN,
+			"Admin endpoints require admin_token or bearer_token",
+		));
+	}
+
+	let token = headers
+		.get("authorization")
+		.and_then(|h| h.to_str().ok())
+		.and_then(|h| h.strip_prefix("Bearer "))
+		.unwrap_or_default();
+
+	let matched = tokens.iter().fold(false, |matched, required| {
+		constant_time_eq(token.as_bytes(), required.as_bytes()) | matched
+	});
+	if matched {
+		Ok(())
+	} else {
+		Err((StatusCode::UNAUTHORIZED, "Unauthorized"))
+	}
+}
+
+// Compare without short-circuiting on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+
+	a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// The page holds no readings, those come from `/events`, which checks the token
+async fn handle_status_page() -> Response {
+	Html(include_str!("status.html")).into_response()
+}
+
+async fn handle_events(
 	headers: HeaderMap,
+	Query(query): Query<HashMap<String, String>>,
 	State(state): State<AppState>,
-) -> Result<Response, StatusCode> {
-	// Check authorization if token is configured
-	if let Some(ref required_token) = state.bearer_token {
-		let auth_header = headers.get("authorization").and_then(|h| h.to_str().ok());
-
-		match auth_header {
-			Some(header) if header == format!("Bearer {}", required_token) => {
-				// Authorized
-			}
-			_ => {
-				return Ok((StatusCode::UNAUTHORIZED, "Unauthorized").into_response());
+) -> Response {
+	if !is_authorized(&headers, Some(&query), &state) {
+		return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+	}
+
+	Sse::new(metrics_events(state.metrics))
+		.keep_alive(KeepAlive::default())
+		.into_response()
+}
+
+// Emit the current metrics immediately and then on every update
+fn metrics_events(
+	metrics: watch::Receiver<Option<Metrics>>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+	WatchStream::new(metrics).filter_map(|metrics| {
+		let metrics = metrics?;
+		Event::default().json_data(metrics).ok().map(Ok)
+	})
+}
+
+async fn handle_websocket(
+	headers: HeaderMap,
+	Query(query): Query<HashMap<String, String>>,
+	State(state): State<AppState>,
+	upgrade: WebSocketUpgrade,
+) -> Response {
+	if !is_authorized(&headers, Some(&query), &state) {
+		return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+	}
+
+	let Some(slot) = WebSocketSlot::acquire(&state.websockets, state.max_websockets) else {
+		return (
+			StatusCode::SERVICE_UNAVAILABLE,
+			"Too many WebSocket connections",
+		)
+			.into_response();
+	};
+
+	upgrade.on_upgrade(move |socket| stream_metrics(socket, state.metrics, slot))
+}
+
+// Counts an open WebSocket towards `max_websocket_connections` until dropped
+struct WebSocketSlot(Arc<AtomicUsize>);
+
+impl WebSocketSlot {
+	fn acquire(open: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+		open
+			.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+				(count < max).then_some(count + 1)
+			})
+			.ok()
+			.map(|_| WebSocketSlot(open.clone()))
+	}
+}
+
+impl Drop for WebSocketSlot {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+// Send the current metrics and then every update as JSON text messages until
+// the client goes away
+async fn stream_metrics(
+	mut socket: WebSocket,
+	mut metrics: watch::Receiver<Option<Metrics>>,
+	_slot: WebSocketSlot,
+) {
+	loop {
+		let current = metrics.borrow_and_update().clone();
+		if let Some(current) = current
+			&& send_metrics(&mut socket, &current).await.is_err()
+		{
+			return;
+		}
+
+		// Wait for the next reading; incoming messages are ignored and pings
+		// are answered automatically
+		loop {
+			tokio::select! {
+				changed = metrics.changed() => match changed {
+					Ok(()) => break,
+					Err(_) => return,
+				},
+				message = socket.recv() => match message {
+					Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+					Some(Ok(_)) => {}
+				},
 			}
 		}
 	}
+}
 
-	let metrics_lock = state.metrics.read().await;
+async fn send_metrics(socket: &mut WebSocket, metrics: &Metrics) -> Result<(), axum::Error> {
+	let json = serde_json::to_string(metrics).map_err(axum::Error::new)?;
+	socket.send(Message::Text(json.into())).await
+}
 
-	match &*metrics_lock {
+async fn handle_status(headers: HeaderMap, State(state): State<AppState>) -> Response {
+	if !is_authorized(&headers, None, &state) {
+		return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+	}
+
+	let metrics = state.metrics.borrow().clone();
+
+	match metrics {
+		Some(metrics) => Json(StatusResponse {
+			timestamp: chrono::Utc::now().timestamp(),
+			metrics,
+		})
+		.into_response(),
+		None => no_metrics_response(&state),
+	}
+}
+
+// 503 telling a cold start apart from a NUT server that can't be polled
+fn no_metrics_response(state: &AppState) -> Response {
+	let failed_polls = state.startup.failed_polls.load(Ordering::Relaxed);
+	let reason = if failed_polls == 0 {
+		"awaiting_first_poll"
+	} else {
+		"polling_failed"
+	};
+
+	(
+		StatusCode::SERVICE_UNAVAILABLE,
+		Json(serde_json::json!({
+			"error": "No metrics available",
+			"reason": reason,
+			"started_at": state.startup.started_at,
+			"uptime_seconds": chrono::Utc::now().timestamp() - state.startup.started_at,
+			"failed_polls": failed_polls,
+			"last_error": *state.startup.last_error.lock().unwrap(),
+		})),
+	)
+		.into_response()
+}
+
+async fn handle_cancel_shutdown(headers: HeaderMap, State(state): State<AppState>) -> Response {
+	if let Err(error) = check_admin(&headers, &state) {
+		return error.into_response();
+	}
+
+	warn!("Shutdown cancel requested through the admin endpoint");
+	state.control.request_cancel();
+	admin_response(&state)
+}
+
+async fn handle_disable_shutdown(headers: HeaderMap, State(state): State<AppState>) -> Response {
+	if let Err(error) = check_admin(&headers, &state) {
+		return error.into_response();
+	}
+
+	warn!("Automatic shutdown disabled through the admin endpoint");
+	state.control.set_disabled(true);
+	state.control.request_cancel();
+	admin_response(&state)
+}
+
+async fn handle_enable_shutdown(headers: HeaderMap, State(state): State<AppState>) -> Response {
+	if let Err(error) = check_admin(&headers, &state) {
+		return error.into_response();
+	}
+
+	info!("Automatic shutdown re-enabled through the admin endpoint");
+	state.control.set_disabled(false);
+	admin_response(&state)
+}
+
+/// Body of `POST /admin/simulate-outage`; every field is optional.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct SimulateOutageRequest {
+	battery_charge: f64,
+	battery_runtime: u64,
+	duration_secs: u64,
+}
+
+impl Default for SimulateOutageRequest {
+	fn default() -> Self {
+		SimulateOutageRequest {
+			battery_charge: 50.0,
+			battery_runtime: 600,
+			duration_secs: 60,
+		}
+	}
+}
+
+async fn handle_simulate_outage(
+	headers: HeaderMap,
+	State(state): State<AppState>,
+	request: Option<Json<SimulateOutageRequest>>,
+) -> Response {
+	if let Err(error) = check_admin(&headers, &state) {
+		return error.into_response();
+	}
+
+	if !state.allow_simulation {
+		return (
+			StatusCode::FORBIDDEN,
+			"Outage simulation is disabled, set metrics.allow_simulation = true",
+		)
+			.into_response();
+	}
+
+	let request = request.map(|Json(request)| request).unwrap_or_default();
+	if !(0.0..=100.0).contains(&request.battery_charge) {
+		return (
+			StatusCode::BAD_REQUEST,
+			"battery_charge must be between 0 and 100",
+		)
+			.into_response();
+	}
+	if request.duration_secs == 0 || request.duration_secs > MAX_SIMULATED_OUTAGE_SECS {
+		return (
+			StatusCode::BAD_REQUEST,
+			format!(
+				"duration_secs must be between 1 and {}",
+				MAX_SIMULATED_OUTAGE_SECS
+			),
+		)
+			.into_response();
+	}
+
+	warn!(
+		"Simulated outage requested through the admin endpoint: {}% charge, {}s runtime for {}s",
+		request.battery_charge, request.battery_runtime, request.duration_secs
+	);
+	state.control.simulate_outage(
+		request.battery_charge,
+		request.battery_runtime,
+		std::time::Duration::from_secs(request.duration_secs),
+	);
+
+	Json(serde_json::json!({
+		"status": "ok",
+		"battery_charge": request.battery_charge,
+		"battery_runtime": request.battery_runtime,
+		"duration_secs": request.duration_secs,
+	}))
+	.into_response()
+}
+
+fn admin_response(state: &AppState) -> Response {
+	Json(serde_json::json!({
+		"status": "ok",
+		"shutdown_disabled": state.control.is_disabled(),
+	}))
+	.into_response()
+}
+
+fn compression() -> CompressionLayer<SizeAbove> {
+	CompressionLayer::new()
+		.gzip(true)
+		.compress_when(SizeAbove::new(MIN_COMPRESSION_SIZE))
+}
+
+async fn handle_metrics(headers: HeaderMap, state: State<AppState>) -> Response {
+	let format = state.format.clone();
+	render_metrics(headers, state, &format).await
+}
+
+async fn render_metrics(
+	headers: HeaderMap,
+	State(state): State<AppState>,
+	format: &str,
+) -> Response {
+	if !is_authorized(&headers, None, &state) {
+		return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+	}
+
+	let metrics = state.metrics.borrow().clone();
+
+	match &metrics {
 		Some(metrics) => {
-			if state.format == "json" {
+			if format == "json" {
 				let response = JsonMetricsResponse {
 					status: "ok".to_string(),
 					timestamp: chrono::Utc::now().timestamp(),
 					metrics: metrics.clone(),
 				};
-				Ok(Json(response).into_response())
+				Json(response).into_response()
+			} else if format == "influx" {
+				let output = format_influx(metrics, &state.influx_measurement, &state.labels);
+				(
+					StatusCode::OK,
+					[("content-type", "text/plain; charset=utf-8")],
+					output,
+				)
+					.into_response()
+			} else if format == "prometheus" {
+				let output = to_prometheus_text(&format_openmetrics(metrics, &state.labels));
+				(
+					StatusCode::OK,
+					[("content-type", PROMETHEUS_CONTENT_TYPE)],
+					output,
+				)
+					.into_response()
 			} else {
 				// OpenMetrics format
-				let output = format_openmetrics(metrics);
-				Ok(
-					(
-						StatusCode::OK,
-						[(
-							"content-type",
-							"application/openmetrics-text; version=1.0.0; charset=utf-8",
-						)],
-						output,
-					)
-						.into_response(),
+				let output = format_openmetrics(metrics, &state.labels);
+				(
+					StatusCode::OK,
+					[(
+						"content-type",
+						"application/openmetrics-text; version=1.0.0; charset=utf-8",
+					)],
+					output,
 				)
+					.into_response()
 			}
 		}
-		None => Ok((StatusCode::SERVICE_UNAVAILABLE, "No metrics available").into_response()),
+		None => no_metrics_response(&state),
 	}
 }
 
-fn format_openmetrics(metrics: &Metrics) -> String {
-	let mut output = String::new();
+/// Pushes each successful poll to a Prometheus Pushgateway, for hosts
+/// Prometheus can't scrape.
+pub struct Pushgateway {
+	client: reqwest::Client,
+	url: String,
+	labels: Vec<(String, String)>,
+	in_flight: Arc<AtomicBool>,
+	failing: Arc<AtomicBool>,
+}
+
+impl Pushgateway {
+	pub fn new(config: &MetricsConfig, push: &PushConfig) -> Self {
+		let client = reqwest::Client::builder()
+			.timeout(PUSH_TIMEOUT)
+			.build()
+			.unwrap_or_default();
+
+		let mut labels: Vec<_> = config
+			.labels
+			.iter()
+			.map(|(name, value)| (name.clone(), value.clone()))
+			.collect();
+		labels.sort();
+
+		Pushgateway {
+			client,
+			url: push_url(push, &crate::notify::hostname()),
+			labels,
+			in_flight: Arc::new(AtomicBool::new(false)),
+			failing: Arc::new(AtomicBool::new(false)),
+		}
+	}
+
+	/// PUTs the metrics in the Prometheus text format in the background,
+	/// replacing everything previously pushed for this job and instance.
+	pub fn push(&self, metrics: &Metrics) {
+		// A slow gateway must not pile up requests, the next poll pushes fresher data
+		if self.in_flight.swap(true, Ordering::AcqRel) {
+			debug!("Skipping Pushgateway push, the previous one is still running");
+			return;
+		}
+
+		let request = self
+			.client
+			.put(&self.url)
+			.header("content-type", PROMETHEUS_CONTENT_TYPE)
+			.body(to_prometheus_text(&format_openmetrics(
+				metrics,
+				&self.labels,
+			)));
+		let url = self.url.clone();
+		let in_flight = self.in_flight.clone();
+		let failing = self.failing.clone();
+
+		tokio::spawn(async move {
+			let error = match request.send().await {
+				Ok(response) if response.status().is_success() => None,
+				Ok(response) => Some(format!("HTTP {}", response.status())),
+				Err(e) => Some(e.to_string()),
+			};
+
+			// Only log changes, an unreachable gateway would otherwise warn every poll
+			match error {
+				None if failing.swap(false, Ordering::Relaxed) => {
+					info!("Pushing metrics to {} works again", url)
+				}
+				None => debug!("Pushed metrics to {}", url),
+				Some(e) if !failing.swap(true, Ordering::Relaxed) => {
+					warn!("Failed to push metrics to {}: {}", url, e)
+				}
+				Some(e) => debug!("Failed to push metrics to {}: {}", url, e),
+			}
+			in_flight.store(false, Ordering::Release);
+		});
+	}
+}
+
+// `<url>/metrics/job/<job>/instance/<instance>`, the grouping key of the push
+fn push_url(push: &PushConfig, hostname: &str) -> String {
+	format!(
+		"{}/metrics/job/{}/instance/{}",
+		push.pushgateway_url.trim_end_matches('/'),
+		push.job,
+		push.instance.as_deref().unwrap_or(hostname)
+	)
+}
+
+// Rewrites OpenMetrics output into the Prometheus 0.0.4 text format, which has
+// no UNIT lines, info type or EOF marker and names counters with their suffix
+fn to_prometheus_text(openmetrics: &str) -> String {
+	let mut counters = Vec::new();
+	let mut output = String::with_capacity(openmetrics.len());
+
+	for line in openmetrics.lines() {
+		if line.starts_with("# UNIT ") || line == "# EOF" {
+			continue;
+		}
+
+		if let Some(rest) = line.strip_prefix("# TYPE ")
+			&& let Some((name, kind)) = rest.split_once(' ')
+		{
+			match kind {
+				"counter" => {
+					counters.push(name);
+					output.push_str(&format!("# TYPE {}_total counter\n", name));
+				}
+				"info" => output.push_str(&format!("# TYPE {} gauge\n", name)),
+				_ => output.push_str(&format!("{}\n", line)),
+			}
+			continue;
+		}
+
+		if let Some(rest) = line.strip_prefix("# HELP ")
+			&& let Some((name, help)) = rest.split_once(' ')
+			&& counters.contains(&name)
+		{
+			output.push_str(&format!("# HELP {}_total {}\n", name, help));
+			continue;
+		}
+
+		output.push_str(line);
+		output.push('\n');
+	}
 
-	// Battery charge ratio
-	output.push_str("# TYPE ups_battery_charge_ratio gauge\n");
-	output.push_str("# UNIT ups_battery_charge_ratio ratio\n");
 	output
-		.push_str("# HELP ups_battery_charge_ratio Battery charge level as a ratio (0.0 to 1.0).\n");
-	output.push_str(&format!(
-		"ups_battery_charge_ratio{{ups_name=\"{}\",ups_host=\"{}\"}} {}\n",
-		escape_label(&metrics.ups_name),
-		escape_label(&metrics.ups_host),
-		metrics.battery_charge_percent / 100.0
-	));
+}
 
-	// Battery runtime
-	output.push_str("# TYPE ups_battery_runtime_seconds gauge\n");
-	output.push_str("# UNIT ups_battery_runtime_seconds seconds\n");
-	output.push_str("# HELP ups_battery_runtime_seconds Estimated battery runtime in seconds.\n");
-	output.push_str(&format!(
-		"ups_battery_runtime_seconds{{ups_name=\"{}\",ups_host=\"{}\"}} {}\n",
+fn format_openmetrics(metrics: &Metrics, extra_labels: &[(String, String)]) -> String {
+	let mut output = String::new();
+	let mut labels = format!(
+		"ups_name=\"{}\",ups_host=\"{}\"",
 		escape_label(&metrics.ups_name),
-		escape_label(&metrics.ups_host),
-		metrics.battery_runtime_seconds
-	));
+		escape_label(&metrics.ups_host)
+	);
+	for (name, value) in extra_labels {
+		labels.push_str(&format!(",{}=\"{}\"", name, escape_label(value)));
+	}
 
-	// On battery status
-	output.push_str("# TYPE ups_on_battery gauge\n");
-	output.push_str(
-		"# HELP ups_on_battery Whether UPS is running on battery (1 = on battery, 0 = on line power).\n",
+	push_gauge(
+		&mut output,
+		"ups_up",
+		None,
+		"Whether the UPS data is fresh (1 = polling succeeds, 0 = stale after repeated poll failures).",
+		&labels,
+		metrics.up as u8,
+	);
+
+	push_gauge(
+		&mut output,
+		"ups_data_stale",
+		None,
+		"Whether the NUT server is reachable but its data is stale (1 = ERR DATA-STALE or readings stopped changing).",
+		&labels,
+		metrics.data_stale as u8,
+	);
+
+	// Omitted rather than reported as 0 when the UPS value is unknown
+	if let Some(charge) = metrics.battery_charge_percent {
+		push_gauge(
+			&mut output,
+			"ups_battery_charge_ratio",
+			Some("ratio"),
+			"Battery charge level as a ratio (0.0 to 1.0).",
+			&labels,
+			charge / 100.0,
+		);
+	}
+
+	if let Some(runtime) = metrics.battery_runtime_seconds {
+		push_gauge(
+			&mut output,
+			"ups_battery_runtime_seconds",
+			Some("seconds"),
+			"Estimated battery runtime in seconds.",
+			&labels,
+			runtime,
+		);
+	}
+
+	push_gauge(
+		&mut output,
+		"ups_on_battery",
+		None,
+		"Whether UPS is running on battery (1 = on battery, 0 = on line power).",
+		&labels,
+		metrics.on_battery as u8,
+	);
+
+	push_gauge(
+		&mut output,
+		"ups_charging",
+		None,
+		"Whether UPS is charging its battery (1 = charging, 0 = not charging).",
+		&labels,
+		metrics.charging as u8,
+	);
+
+	push_gauge(
+		&mut output,
+		"ups_replace_battery",
+		None,
+		"Whether the UPS reports that its battery needs replacing (RB flag).",
+		&labels,
+		metrics.replace_battery as u8,
+	);
+
+	push_gauge(
+		&mut output,
+		"ups_overload",
+		None,
+		"Whether the UPS output is overloaded (OVER flag).",
+		&labels,
+		metrics.overload as u8,
+	);
+
+	push_gauge(
+		&mut output,
+		"ups_on_bypass",
+		None,
+		"Whether the UPS is running on bypass (BYPASS flag).",
+		&labels,
+		metrics.on_bypass as u8,
 	);
-	output.push_str(&format!(
-		"ups_on_battery{{ups_name=\"{}\",ups_host=\"{}\"}} {}\n",
-		escape_label(&metrics.ups_name),
-		escape_label(&metrics.ups_host),
-		if metrics.on_battery { 1 } else { 0 }
-	));
 
 	// On battery duration (if applicable)
 	if let Some(duration) = metrics.on_battery_duration_seconds {
-		output.push_str("# TYPE ups_on_battery_duration_seconds gauge\n");
-		output.push_str("# UNIT ups_on_battery_duration_seconds seconds\n");
-		output.push_str(
-			"# HELP ups_on_battery_duration_seconds Duration in seconds that UPS has been on battery.\n",
+		push_gauge(
+			&mut output,
+			"ups_on_battery_duration_seconds",
+			Some("seconds"),
+			"Duration in seconds that UPS has been on battery.",
+			&labels,
+			duration,
+		);
+	}
+
+	// Most recent completed outage, kept while on line power
+	if let Some(duration) = metrics.last_outage_duration_seconds {
+		push_gauge(
+			&mut output,
+			"ups_last_outage_duration_seconds",
+			Some("seconds"),
+			"Duration in seconds of the most recent completed outage.",
+			&labels,
+			duration,
+		);
+	}
+
+	if let Some(timestamp) = metrics.last_outage_timestamp {
+		push_gauge(
+			&mut output,
+			"ups_last_outage_timestamp_seconds",
+			Some("seconds"),
+			"Unix timestamp at which the most recent completed outage started.",
+			&labels,
+			timestamp,
+		);
+	}
+
+	// Omitted until a transfer to battery has been seen
+	if let Some(seconds) = metrics.seconds_since_last_transfer {
+		push_gauge(
+			&mut output,
+			"ups_seconds_since_last_transfer",
+			// OpenMetrics only allows a unit as the name's suffix
+			None,
+			"Seconds since the UPS last switched to battery power.",
+			&labels,
+			seconds,
 		);
-		output.push_str(&format!(
-			"ups_on_battery_duration_seconds{{ups_name=\"{}\",ups_host=\"{}\"}} {}\n",
-			escape_label(&metrics.ups_name),
-			escape_label(&metrics.ups_host),
-			duration
-		));
 	}
 
 	// Output power (if available)
 	if let Some(power) = metrics.output_power_watts {
-		output.push_str("# TYPE ups_output_power_watts gauge\n");
-		output.push_str("# UNIT ups_output_power_watts watts\n");
-		output.push_str("# HELP ups_output_power_watts Current UPS output power in watts.\n");
+		push_gauge(
+			&mut output,
+			"ups_output_power_watts",
+			Some("watts"),
+			"Current UPS output power in watts.",
+			&labels,
+			power,
+		);
+	}
+
+	// Remaining energy at the current load (needs both runtime and output power)
+	if let Some(energy) = metrics.battery_energy_remaining_watthours {
+		push_gauge(
+			&mut output,
+			"ups_battery_energy_remaining_watthours",
+			Some("watthours"),
+			"Energy left in the battery at the current load, from runtime and output power.",
+			&labels,
+			energy,
+		);
+	}
+
+	// Temperatures (if available)
+	if let Some(temperature) = metrics.battery_temperature_celsius {
+		push_gauge(
+			&mut output,
+			"ups_battery_temperature_celsius",
+			Some("celsius"),
+			"Battery temperature in degrees Celsius.",
+			&labels,
+			temperature,
+		);
+	}
+
+	if let Some(temperature) = metrics.ups_temperature_celsius {
+		push_gauge(
+			&mut output,
+			"ups_temperature_celsius",
+			Some("celsius"),
+			"UPS internal temperature in degrees Celsius.",
+			&labels,
+			temperature,
+		);
+	}
+
+	// Power quality (if available)
+	if let Some(frequency) = metrics.input_frequency_hertz {
+		push_gauge(
+			&mut output,
+			"ups_input_frequency_hertz",
+			Some("hertz"),
+			"Input line frequency in hertz.",
+			&labels,
+			frequency,
+		);
+	}
+
+	if let Some(volts) = metrics.battery_volts {
+		push_gauge(
+			&mut output,
+			"ups_battery_volts",
+			Some("volts"),
+			"Battery voltage in volts.",
+			&labels,
+			volts,
+		);
+	}
+
+	if let Some(volts) = metrics.battery_nominal_volts {
+		push_gauge(
+			&mut output,
+			"ups_battery_nominal_volts",
+			Some("volts"),
+			"Nominal battery voltage in volts.",
+			&labels,
+			volts,
+		);
+	}
+
+	if let Some(restart) = metrics.battery_charge_restart_percent {
+		push_gauge(
+			&mut output,
+			"ups_battery_charge_restart_percent",
+			Some("percent"),
+			"Battery charge required before the UPS restarts the load after a shutdown.",
+			&labels,
+			restart,
+		);
+	}
+
+	if let Some(age) = metrics.battery_age_days {
+		push_gauge(
+			&mut output,
+			"ups_battery_age_days",
+			None,
+			"Days since the battery date reported by the UPS.",
+			&labels,
+			age,
+		);
+	}
+
+	if let Some(ref date) = metrics.battery_date {
+		output.push_str("# TYPE ups_battery_info info\n");
+		output.push_str("# HELP ups_battery_info Battery date as reported by the UPS.\n");
 		output.push_str(&format!(
-			"ups_output_power_watts{{ups_name=\"{}\",ups_host=\"{}\"}} {}\n",
-			escape_label(&metrics.ups_name),
-			escape_label(&metrics.ups_host),
-			power
+			"ups_battery_info{{{},battery_date=\"{}\"}} 1\n",
+			labels,
+			escape_label(date)
 		));
 	}
 
-	// Last update timestamp
-	output.push_str("# TYPE ups_last_update_timestamp_seconds gauge\n");
-	output.push_str("# UNIT ups_last_update_timestamp_seconds seconds\n");
-	output.push_str(
-		"# HELP ups_last_update_timestamp_seconds Unix timestamp of last successful UPS status update.\n",
+	// Sagging cells show up as a ratio drifting below 1
+	if let (Some(volts), Some(nominal)) = (metrics.battery_volts, metrics.battery_nominal_volts)
+		&& nominal > 0.0
+	{
+		push_gauge(
+			&mut output,
+			"ups_battery_voltage_ratio",
+			Some("ratio"),
+			"Battery voltage relative to its nominal voltage.",
+			&labels,
+			volts / nominal,
+		);
+	}
+
+	// Battery discharge rate (only while on battery)
+	if let Some(rate) = metrics.discharge_rate_percent_per_minute {
+		push_gauge(
+			&mut output,
+			"ups_battery_discharge_percent_per_minute",
+			None,
+			"Smoothed battery discharge rate in percent per minute.",
+			&labels,
+			rate,
+		);
+	}
+
+	// Estimated time until shutdown (only while on battery with shutdown enabled)
+	if let Some(seconds) = metrics.seconds_until_shutdown {
+		push_gauge(
+			&mut output,
+			"ups_seconds_until_shutdown",
+			Some("seconds"),
+			"Estimated seconds until a shutdown trigger fires.",
+			&labels,
+			seconds,
+		);
+	}
+
+	// Configured shutdown thresholds, for dashboard annotations
+	push_gauge(
+		&mut output,
+		"ups_shutdown_enabled",
+		None,
+		"Whether automatic shutdown is enabled (1 = enabled, 0 = disabled).",
+		&labels,
+		metrics.shutdown.enabled as u8,
 	);
-	output.push_str(&format!(
-		"ups_last_update_timestamp_seconds{{ups_name=\"{}\",ups_host=\"{}\"}} {}\n",
-		escape_label(&metrics.ups_name),
-		escape_label(&metrics.ups_host),
-		metrics.last_update
-	));
+
+	push_gauge(
+		&mut output,
+		"ups_shutdown_charge_threshold_ratio",
+		Some("ratio"),
+		"Battery charge ratio below which a shutdown is triggered.",
+		&labels,
+		metrics.shutdown.battery_percent_threshold / 100.0,
+	);
+
+	push_gauge(
+		&mut output,
+		"ups_shutdown_runtime_threshold_seconds",
+		Some("seconds"),
+		"Battery runtime below which a shutdown is triggered.",
+		&labels,
+		metrics.shutdown.runtime_threshold,
+	);
+
+	push_gauge(
+		&mut output,
+		"ups_shutdown_on_battery_threshold_seconds",
+		Some("seconds"),
+		"Time on battery after which a shutdown is triggered.",
+		&labels,
+		metrics.shutdown.on_battery_seconds,
+	);
+
+	push_gauge(
+		&mut output,
+		"ups_consecutive_poll_failures",
+		None,
+		"Number of consecutive failed polls, reset to 0 on success.",
+		&labels,
+		metrics.consecutive_poll_failures,
+	);
+
+	// Time taken by the last poll (if available)
+	if let Some(duration) = metrics.poll_duration_seconds {
+		push_gauge(
+			&mut output,
+			"ups_poll_duration_seconds",
+			Some("seconds"),
+			"Time taken by the last poll of the NUT server, including connecting.",
+			&labels,
+			duration,
+		);
+	}
+
+	push_gauge(
+		&mut output,
+		"ups_last_update_timestamp_seconds",
+		Some("seconds"),
+		"Unix timestamp of last successful UPS status update.",
+		&labels,
+		metrics.last_update,
+	);
+
+	// NUT server currently in use (changes on failover)
+	if let Some(ref server) = metrics.nut_server {
+		output.push_str("# TYPE ups_nut_server_info info\n");
+		output.push_str("# HELP ups_nut_server_info NUT server currently used to poll the UPS.\n");
+		output.push_str(&format!(
+			"ups_nut_server_info{{{},server=\"{}\"}} 1\n",
+			labels,
+			escape_label(server)
+		));
+
+		output.push_str("# TYPE ups_server_failovers counter\n");
+		output.push_str("# HELP ups_server_failovers Times polling switched to another NUT server.\n");
+		output.push_str(&format!(
+			"ups_server_failovers_total{{{}}} {}\n",
+			labels, metrics.server_failovers
+		));
+	}
+
+	// User-configured extra variables: numeric ones as gauges, the rest as info
+	let mut extra_vars: Vec<_> = metrics.extra_vars.iter().collect();
+	extra_vars.sort();
+	let (numeric, text): (Vec<_>, Vec<_>) = extra_vars
+		.into_iter()
+		.partition(|(_, value)| value.parse::<f64>().is_ok_and(f64::is_finite));
+
+	if !numeric.is_empty() {
+		output.push_str("# TYPE ups_extra gauge\n");
+		output.push_str("# HELP ups_extra Numeric value of an additional NUT variable.\n");
+		for (name, value) in numeric {
+			output.push_str(&format!(
+				"ups_extra{{{},var=\"{}\"}} {}\n",
+				labels,
+				escape_label(name),
+				value
+			));
+		}
+	}
+
+	if !text.is_empty() {
+		output.push_str("# TYPE ups_extra_info info\n");
+		output.push_str("# HELP ups_extra_info Non-numeric value of an additional NUT variable.\n");
+		for (name, value) in text {
+			output.push_str(&format!(
+				"ups_extra_info{{{},var=\"{}\",value=\"{}\"}} 1\n",
+				labels,
+				escape_label(name),
+				escape_label(value)
+			));
+		}
+	}
+
+	// One info metric with every identity label, for joins such as
+	// `ups_battery_charge_ratio * on(ups_name) group_left(model) ups_info`.
+	// User labels win over identity labels of the same name.
+	let mut info_labels = labels.clone();
+	for (name, value) in &metrics.device_info {
+		if !extra_labels.iter().any(|(extra, _)| extra == name) {
+			info_labels.push_str(&format!(",{}=\"{}\"", name, escape_label(value)));
+		}
+	}
+	output.push_str("# TYPE ups_info info\n");
+	output.push_str("# HELP ups_info UPS identity reported by NUT at startup.\n");
+	output.push_str(&format!("ups_info{{{}}} 1\n", info_labels));
 
 	// UPS status info
 	output.push_str("# TYPE ups_status_info info\n");
 	output.push_str("# HELP ups_status_info UPS status information.\n");
 	output.push_str(&format!(
-		"ups_status_info{{ups_name=\"{}\",ups_host=\"{}\",status=\"{}\"}} 1\n",
-		escape_label(&metrics.ups_name),
-		escape_label(&metrics.ups_host),
+		"ups_status_info{{{},status=\"{}\"}} 1\n",
+		labels,
 		escape_label(&metrics.ups_status)
 	));
 
+	let lifetime = &metrics.lifetime;
+	push_reading_stats(
+		&mut output,
+		"ups_battery_charge",
+		"ratio",
+		"battery charge ratio",
+		&labels,
+		lifetime.battery_charge_percent,
+		0.01,
+	);
+	push_reading_stats(
+		&mut output,
+		"ups_battery_runtime",
+		"seconds",
+		"battery runtime",
+		&labels,
+		lifetime.battery_runtime_seconds,
+		1.0,
+	);
+	push_reading_stats(
+		&mut output,
+		"ups_output_power",
+		"watts",
+		"output power",
+		&labels,
+		lifetime.output_power_watts,
+		1.0,
+	);
+	push_reading_stats(
+		&mut output,
+		"ups_battery",
+		"volts",
+		"battery voltage",
+		&labels,
+		lifetime.battery_volts,
+		1.0,
+	);
+
 	// OpenMetrics EOF marker
 	output.push_str("# EOF\n");
 
 	output
 }
 
+// Append min, max and avg gauges named `{base}_{stat}_{unit}`, scaling the values
+// like the gauge of the current reading
+fn push_reading_stats(
+	output: &mut String,
+	base: &str,
+	unit: &str,
+	what: &str,
+	labels: &str,
+	stats: Option<ReadingStats>,
+	scale: f64,
+) {
+	let Some(stats) = stats else {
+		return;
+	};
+
+	for (stat, description, value) in [
+		("min", "Lowest", stats.min),
+		("max", "Highest", stats.max),
+		("avg", "Average", stats.avg),
+	] {
+		push_gauge(
+			output,
+			&format!("{}_{}_{}", base, stat, unit),
+			Some(unit),
+			&format!("{} {} since RabbitNUT started.", description, what),
+			labels,
+			value * scale,
+		);
+	}
+}
+
+// Append a gauge with its TYPE, UNIT and HELP metadata
+fn push_gauge(
+	output: &mut String,
+	name: &str,
+	unit: Option<&str>,
+	help: &str,
+	labels: &str,
+	value: impl std::fmt::Display,
+) {
+	output.push_str(&format!("# TYPE {} gauge\n", name));
+	if let Some(unit) = unit {
+		output.push_str(&format!("# UNIT {} {}\n", name, unit));
+	}
+	output.push_str(&format!("# HELP {} {}\n", name, help));
+	output.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+}
+
+fn format_influx(metrics: &Metrics, measurement: &str, extra_tags: &[(String, String)]) -> String {
+	let mut fields = vec![
+		format!("on_battery={}", metrics.on_battery),
+		format!("charging={}", metrics.charging),
+		format!("replace_battery={}", metrics.replace_battery),
+		format!("overload={}", metrics.overload),
+		format!("on_bypass={}", metrics.on_bypass),
+		format!("up={}", metrics.up),
+		format!(
+			"consecutive_poll_failures={}i",
+			metrics.consecutive_poll_failures
+		),
+		format!("status=\"{}\"", escape_influx_string(&metrics.ups_status)),
+	];
+
+	if let Some(charge) = metrics.battery_charge_percent {
+		fields.push(format!("battery_charge={}", charge));
+	}
+
+	if let Some(runtime) = metrics.battery_runtime_seconds {
+		fields.push(format!("battery_runtime={}i", runtime));
+	}
+
+	if let Some(duration) = metrics.on_battery_duration_seconds {
+		fields.push(format!("on_battery_duration={}i", duration));
+	}
+
+	if let Some(duration) = metrics.last_outage_duration_seconds {
+		fields.push(format!("last_outage_duration={}i", duration));
+	}
+
+	if let Some(timestamp) = metrics.last_outage_timestamp {
+		fields.push(format!("last_outage_timestamp={}i", timestamp));
+	}
+
+	if let Some(seconds) = metrics.seconds_since_last_transfer {
+		fields.push(format!("seconds_since_last_transfer={}i", seconds));
+	}
+
+	fields.push(format!("data_stale={}", metrics.data_stale));
+
+	if let Some(power) = metrics.output_power_watts {
+		fields.push(format!("output_power={}", power));
+	}
+
+	if let Some(energy) = metrics.battery_energy_remaining_watthours {
+		fields.push(format!("battery_energy_remaining={}", energy));
+	}
+
+	if let Some(temperature) = metrics.battery_temperature_celsius {
+		fields.push(format!("battery_temperature={}", temperature));
+	}
+
+	if let Some(temperature) = metrics.ups_temperature_celsius {
+		fields.push(format!("ups_temperature={}", temperature));
+	}
+
+	if let Some(frequency) = metrics.input_frequency_hertz {
+		fields.push(format!("input_frequency={}", frequency));
+	}
+
+	if let Some(volts) = metrics.battery_volts {
+		fields.push(format!("battery_voltage={}", volts));
+	}
+
+	if let Some(volts) = metrics.battery_nominal_volts {
+		fields.push(format!("battery_voltage_nominal={}", volts));
+	}
+
+	if let Some(restart) = metrics.battery_charge_restart_percent {
+		fields.push(format!("battery_charge_restart={}", restart));
+	}
+
+	if let Some(age) = metrics.battery_age_days {
+		fields.push(format!("battery_age_days={}i", age));
+	}
+
+	let lifetime = [
+		("battery_charge", metrics.lifetime.battery_charge_percent),
+		("battery_runtime", metrics.lifetime.battery_runtime_seconds),
+		("output_power", metrics.lifetime.output_power_watts),
+		("battery_voltage", metrics.lifetime.battery_volts),
+	];
+	for (name, stats) in lifetime {
+		if let Some(stats) = stats {
+			fields.push(format!("{}_min={}", name, stats.min));
+			fields.push(format!("{}_max={}", name, stats.max));
+			fields.push(format!("{}_avg={}", name, stats.avg));
+		}
+	}
+
+	let mut extra_vars: Vec<_> = metrics.extra_vars.iter().collect();
+	extra_vars.sort();
+	for (name, value) in extra_vars {
+		let key = escape_influx_key(&format!("extra.{}", name));
+		match value
+			.parse::<f64>()
+			.ok()
+			.filter(|number| number.is_finite())
+		{
+			Some(number) => fields.push(format!("{}={}", key, number)),
+			None => fields.push(format!("{}=\"{}\"", key, escape_influx_string(value))),
+		}
+	}
+
+	if let Some(duration) = metrics.poll_duration_seconds {
+		fields.push(format!("poll_duration={}", duration));
+	}
+
+	if let Some(rate) = metrics.discharge_rate_percent_per_minute {
+		fields.push(format!("discharge_rate={}", rate));
+	}
+
+	if let Some(seconds) = metrics.seconds_until_shutdown {
+		fields.push(format!("seconds_until_shutdown={}", seconds));
+	}
+
+	fields.push(format!("shutdown_enabled={}", metrics.shutdown.enabled));
+	fields.push(format!(
+		"shutdown_charge_threshold={}",
+		metrics.shutdown.battery_percent_threshold
+	));
+	fields.push(format!(
+		"shutdown_runtime_threshold={}i",
+		metrics.shutdown.runtime_threshold
+	));
+	fields.push(format!(
+		"shutdown_on_battery_threshold={}i",
+		metrics.shutdown.on_battery_seconds
+	));
+
+	let tags: String = extra_tags
+		.iter()
+		.map(|(name, value)| format!(",{}={}", name, escape_influx_key(value)))
+		.collect();
+
+	format!(
+		"{},ups_name={},ups_host={}{} {} {}\n",
+		escape_influx_key(measurement),
+		escape_influx_key(&metrics.ups_name),
+		escape_influx_key(&metrics.ups_host),
+		tags,
+		fields.join(","),
+		metrics.last_update * 1_000_000_000
+	)
+}
+
+// Escape measurement names and tag values according to the line protocol
+fn escape_influx_key(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace(',', "\\,")
+		.replace('=', "\\=")
+		.replace(' ', "\\ ")
+}
+
+// Escape string field values according to the line protocol
+fn escape_influx_string(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
 // Escape label values according to OpenMetrics specification
 fn escape_label(value: &str) -> String {
 	value
@@ -266,3 +1587,63 @@ fn escape_label(value: &str) -> String {
 		})
 		.collect()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn push_url_groups_by_job_and_instance() {
+		let mut push = PushConfig {
+			pushgateway_url: "http://pushgateway:9091/".to_string(),
+			job: "rabbitnut".to_string(),
+			instance: None,
+		};
+		assert_eq!(
+			push_url(&push, "nas"),
+			"http://pushgateway:9091/metrics/job/rabbitnut/instance/nas"
+		);
+
+		push.instance = Some("rack-1".to_string());
+		assert_eq!(
+			push_url(&push, "nas"),
+			"http://pushgateway:9091/metrics/job/rabbitnut/instance/rack-1"
+		);
+	}
+
+	#[test]
+	fn access_log_redacts_tokens() {
+		assert_eq!(redact_token("token=secret"), "token=REDACTED");
+		assert_eq!(
+			redact_token("since=5&token=secret&x"),
+			"since=5&token=REDACTED&x"
+		);
+		assert_eq!(redact_token("tokens=1"), "tokens=1");
+	}
+
+	#[test]
+	fn prometheus_text_drops_openmetrics_only_lines() {
+		let openmetrics = "# TYPE ups_battery_runtime_seconds gauge\n\
+			# UNIT ups_battery_runtime_seconds seconds\n\
+			# HELP ups_battery_runtime_seconds Runtime.\n\
+			ups_battery_runtime_seconds{ups_name=\"ups\"} 600\n\
+			# TYPE ups_server_failovers counter\n\
+			# HELP ups_server_failovers Failovers.\n\
+			ups_server_failovers_total{ups_name=\"ups\"} 2\n\
+			# TYPE ups_status_info info\n\
+			ups_status_info{ups_name=\"ups\",status=\"OL\"} 1\n\
+			# EOF\n";
+
+		assert_eq!(
+			to_prometheus_text(openmetrics),
+			"# TYPE ups_battery_runtime_seconds gauge\n\
+			# HELP ups_battery_runtime_seconds Runtime.\n\
+			ups_battery_runtime_seconds{ups_name=\"ups\"} 600\n\
+			# TYPE ups_server_failovers_total counter\n\
+			# HELP ups_server_failovers_total Failovers.\n\
+			ups_server_failovers_total{ups_name=\"ups\"} 2\n\
+			# TYPE ups_status_info gauge\n\
+			ups_status_info{ups_name=\"ups\",status=\"OL\"} 1\n"
+		);
+	}
+}
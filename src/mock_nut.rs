@@ -0,0 +1,300 @@
+//! A minimal in-process NUT server used by the protocol tests.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone)]
+struct MockState {
+	ups_name: String,
+	vars: Vec<(String, String)>,
+	// Answered by GET VAR but left out of LIST VAR
+	unlisted_vars: Vec<(String, String)>,
+	credentials: Option<(String, String)>,
+	version: Option<(String, String)>,
+	legacy_master: bool,
+	byte_by_byte: bool,
+	blank_lines: bool,
+	restricted_user: bool,
+	// Variables SET VAR accepts; other known variables are read-only
+	writable_vars: Vec<String>,
+	list_var_refused: bool,
+	// GET VAR requests received over all connections
+	get_var_requests: Arc<AtomicUsize>,
+}
+
+pub struct MockNutServer {
+	state: MockState,
+}
+
+pub struct MockNutHandle {
+	port: u16,
+	get_var_requests: Arc<AtomicUsize>,
+}
+
+impl MockNutServer {
+	pub fn new(ups_name: &str) -> Self {
+		MockNutServer {
+			state: MockState {
+				ups_name: ups_name.to_string(),
+				vars: Vec::new(),
+				unlisted_vars: Vec::new(),
+				credentials: None,
+				version: None,
+				legacy_master: false,
+				byte_by_byte: false,
+				blank_lines: false,
+				restricted_user: false,
+				writable_vars: Vec::new(),
+				list_var_refused: false,
+				get_var_requests: Arc::new(AtomicUsize::new(0)),
+			},
+		}
+	}
+
+	pub fn with_var(mut self, name: &str, value: &str) -> Self {
+		self.state.vars.push((name.to_string(), value.to_string()));
+		self
+	}
+
+	/// Adds a variable only `GET VAR` returns, missing from `LIST VAR`.
+	pub fn with_unlisted_var(mut self, name: &str, value: &str) -> Self {
+		self
+			.state
+			.unlisted_vars
+			.push((name.to_string(), value.to_string()));
+		self
+	}
+
+	pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+		self.state.credentials = Some((username.to_string(), password.to_string()));
+		self
+	}
+
+	/// Answers `VER` and `NETVER`; without this both return ERR UNKNOWN-COMMAND.
+	pub fn with_version(mut self, server: &str, protocol: &str) -> Self {
+		self.state.version = Some((server.to_string(), protocol.to_string()));
+		self
+	}
+
+	/// Rejects `PRIMARY` like servers older than NUT 2.8, which only know `MASTER`.
+	pub fn with_legacy_master(mut self) -> Self {
+		self.state.legacy_master = true;
+		self
+	}
+
+	/// Writes responses one byte at a time, as a slow or TLS-wrapped server may.
+	pub fn with_byte_by_byte(mut self) -> Self {
+		self.state.byte_by_byte = true;
+		self
+	}
+
+	/// Precedes every response with a stray blank line.
+	pub fn with_blank_lines(mut self) -> Self {
+		self.state.blank_lines = true;
+		self
+	}
+
+	/// Accepts the credentials but denies everything beyond LOGIN, like a
+	/// `upsmon secondary` user without `instcmds`.
+	pub fn with_restricted_user(mut self) -> Self {
+		self.state.restricted_user = true;
+		self
+	}
+
+	/// Lets `SET VAR` change the variable instead of answering ERR READONLY.
+	pub fn with_writable_var(mut self, name: &str) -> Self {
+		self.state.writable_vars.push(name.to_string());
+		self
+	}
+
+	/// Answers `LIST VAR` with ERR ACCESS-DENIED while `GET VAR` still works.
+	pub fn with_list_var_refused(mut self) -> Self {
+		self.state.list_var_refused = true;
+		self
+	}
+
+	/// Binds an ephemeral port and serves every connection on its own thread.
+	pub fn start(self) -> MockNutHandle {
+		let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind mock NUT server");
+		let port = listener.local_addr().unwrap().port();
+		let get_var_requests = self.state.get_var_requests.clone();
+		let state = Arc::new(self.state);
+
+		thread::spawn(move || {
+			for stream in listener.incoming().flatten() {
+				let state = state.clone();
+				thread::spawn(move || handle_connection(stream, &state));
+			}
+		});
+
+		MockNutHandle {
+			port,
+			get_var_requests,
+		}
+	}
+}
+
+impl MockNutHandle {
+	pub fn port(&self) -> u16 {
+		self.port
+	}
+
+	/// How many `GET VAR` requests the server has answered so far.
+	pub fn get_var_requests(&self) -> usize {
+		self.get_var_requests.load(Ordering::SeqCst)
+	}
+}
+
+fn handle_connection(stream: TcpStream, state: &MockState) {
+	let mut writer = stream.try_clone().unwrap();
+	let reader = BufReader::new(stream);
+	let mut username: Option<String> = None;
+	let mut authenticated = false;
+
+	for line in reader.lines() {
+		let Ok(line) = line else {
+			return;
+		};
+		let parts: Vec<&str> = line.split_whitespace().collect();
+		if parts.starts_with(&["GET", "VAR"]) {
+			state.get_var_requests.fetch_add(1, Ordering::SeqCst);
+		}
+
+		let response = match parts.as_slice() {
+			["USERNAME", user] => {
+				username = Some(user.to_string());
+				"OK\n".to_string()
+			}
+			["PASSWORD", password] => match state.credentials {
+				Some((ref expected_user, ref expected_password))
+					if username.as_deref() != Some(expected_user) || password != expected_password =>
+				{
+					"ERR ACCESS-DENIED\n".to_string()
+				}
+				_ => {
+					authenticated = true;
+					"OK\n".to_string()
+				}
+			},
+			["GET", "VAR", ups, _] if *ups != state.ups_name => "ERR UNKNOWN-UPS\n".to_string(),
+			["GET", "VAR", ups, var] => match state
+				.vars
+				.iter()
+				.chain(&state.unlisted_vars)
+				.find(|(name, _)| name == var)
+			{
+				Some((name, value)) => format!("VAR {} {} \"{}\"\n", ups, name, quote(value)),
+				None => "ERR VAR-NOT-SUPPORTED\n".to_string(),
+			},
+			["LIST", "UPS"] => format!(
+				"BEGIN LIST UPS\nUPS {} \"Mock UPS\"\nEND LIST UPS\n",
+				state.ups_name
+			),
+			["LIST", "VAR", ups] if *ups != state.ups_name => "ERR UNKNOWN-UPS\n".to_string(),
+			["LIST", "VAR", _] if state.list_var_refused => "ERR ACCESS-DENIED\n".to_string(),
+			["LIST", "VAR", ups] => {
+				let mut response = format!("BEGIN LIST VAR {}\n", ups);
+				for (name, value) in &state.vars {
+					response.push_str(&format!("VAR {} {} \"{}\"\n", ups, name, quote(value)));
+				}
+				response.push_str(&format!("END LIST VAR {}\n", ups));
+				response
+			}
+			["INSTCMD", ups, _]
+			| ["FSD", ups]
+			| ["SET", "VAR", ups, _, _]
+			| ["LOGIN", ups]
+			| ["PRIMARY", ups]
+			| ["MASTER", ups]
+				if *ups != state.ups_name =>
+			{
+				"ERR UNKNOWN-UPS\n".to_string()
+			}
+			// Like upsd, commands need a login whenever users are configured
+			["INSTCMD", _, _]
+			| ["FSD", _]
+			| ["SET", "VAR", _, _, _]
+			| ["LOGIN", _]
+			| ["PRIMARY", _]
+			| ["MASTER", _]
+				if state.credentials.is_some() && username.is_none() =>
+			{
+				"ERR USERNAME-REQUIRED\n".to_string()
+			}
+			["INSTCMD", _, _]
+			| ["FSD", _]
+			| ["SET", "VAR", _, _, _]
+			| ["LOGIN", _]
+			| ["PRIMARY", _]
+			| ["MASTER", _]
+				if state.credentials.is_some() && !authenticated =>
+			{
+				"ERR ACCESS-DENIED\n".to_string()
+			}
+			["INSTCMD", _, _] | ["FSD", _] | ["SET", "VAR", _, _, _] | ["PRIMARY", _] | ["MASTER", _]
+				if state.restricted_user =>
+			{
+				"ERR ACCESS-DENIED\n".to_string()
+			}
+			["LOGIN", _] => "OK\n".to_string(),
+			["PRIMARY", _] if state.legacy_master => "ERR UNKNOWN-COMMAND\n".to_string(),
+			["PRIMARY", _] => "OK PRIMARY-GRANTED\n".to_string(),
+			["MASTER", _] => "OK MASTER-GRANTED\n".to_string(),
+			["GET", "NUMLOGINS", ups] => format!("NUMLOGINS {} 1\n", ups),
+			["INSTCMD", _, _] => "OK\n".to_string(),
+			["FSD", _] => "OK FSD-SET\n".to_string(),
+			["SET", "VAR", _, var, _] if state.writable_vars.iter().any(|name| name == var) => {
+				"OK\n".to_string()
+			}
+			["SET", "VAR", _, var, _] if state.vars.iter().any(|(name, _)| name == var) => {
+				"ERR READONLY\n".to_string()
+			}
+			["SET", "VAR", _, _, _] => "ERR VAR-NOT-SUPPORTED\n".to_string(),
+			["VER"] => match state.version {
+				Some((ref server, _)) => format!("{}\n", server),
+				None => "ERR UNKNOWN-COMMAND\n".to_string(),
+			},
+			["NETVER"] => match state.version {
+				Some((_, ref protocol)) => format!("{}\n", protocol),
+				None => "ERR UNKNOWN-COMMAND\n".to_string(),
+			},
+			["LOGOUT"] => {
+				let _ = writer.write_all(b"OK Goodbye\n");
+				return;
+			}
+			_ => "ERR UNKNOWN-COMMAND\n".to_string(),
+		};
+
+		let response = if state.blank_lines {
+			format!("\r\n{}", response)
+		} else {
+			response
+		};
+
+		if write_response(&mut writer, &response, state.byte_by_byte).is_err() {
+			return;
+		}
+	}
+}
+
+fn write_response(writer: &mut TcpStream, response: &str, byte_by_byte: bool) -> io::Result<()> {
+	if !byte_by_byte {
+		return writer.write_all(response.as_bytes());
+	}
+
+	for byte in response.as_bytes() {
+		writer.write_all(&[*byte])?;
+		writer.flush()?;
+		thread::sleep(Duration::from_micros(100));
+	}
+	Ok(())
+}
+
+// Escape a value the way upsd does inside double quotes
+fn quote(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -1,63 +1,401 @@
 use log::{debug, error, info, warn};
-use std::process::Command;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
 use std::sync::Arc;
-use std::thread;
 use std::time::{Duration, Instant};
-use tokio::runtime::Runtime;
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 
-use crate::config::Config;
-use crate::metrics::MetricsServer;
-use crate::ups::{UpsClient, UpsStatus};
+use crate::config::{Config, UpsShutdownMethod};
+use crate::control::ShutdownControl;
+use crate::events::{EventHandler, EventLog, EventType, PowerEvent};
+#[cfg(feature = "history")]
+use crate::history::History;
+use crate::metrics::{
+	LifetimeStats, Metrics, MetricsServer, Pushgateway, ReadingStats, ShutdownThresholds,
+};
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttPublisher;
+use crate::notify::{Notifier, ShutdownAnnouncement, announce_shutdown};
+use crate::simulation::SimulatedUpsSource;
+use crate::state_file::StateFile;
+use crate::ups::{UpsClient, UpsError, UpsSource, UpsStatus, device_info, or_unknown};
+use crate::watchdog::Watchdog;
 
+/// Polls the UPS and shuts the machine down according to the configuration,
+/// while serving metrics and sending notifications when those are enabled.
 pub struct UpsMonitor {
 	config: Config,
-	client: UpsClient,
+	source: Arc<dyn UpsSource>,
 	state: MonitorState,
 	metrics_server: Option<Arc<MetricsServer>>,
-	runtime: Option<Runtime>,
+	pushgateway: Option<Pushgateway>,
+	control: Arc<ShutdownControl>,
+	event_log: Option<EventLog>,
+	// Kept to flush pending requests on exit; also registered in `handlers`
+	notifier: Option<Arc<Notifier>>,
+	#[cfg(feature = "history")]
+	history: Option<History>,
+	#[cfg(feature = "mqtt")]
+	mqtt: Option<MqttPublisher>,
+	// Set to true when SIGINT or SIGTERM arrives
+	stop_signal: watch::Receiver<bool>,
+	// Armed in `run` once the startup connection attempts are over
+	watchdog: Option<Watchdog>,
+	state_file: Option<StateFile>,
+	handlers: Vec<Box<dyn EventHandler>>,
+}
+
+// Adapters behind the single-callback builder methods
+struct OnBattery<F>(F);
+struct OnLine<F>(F);
+struct PreShutdown<F>(F);
+
+impl<F: Fn(&UpsStatus) + Send + Sync> EventHandler for OnBattery<F> {
+	fn on_battery(&self, status: &UpsStatus) {
+		(self.0)(status)
+	}
+}
+
+impl<F: Fn(&UpsStatus) + Send + Sync> EventHandler for OnLine<F> {
+	fn on_line(&self, status: &UpsStatus) {
+		(self.0)(status)
+	}
+}
+
+impl<F: Fn(ShutdownReason) + Send + Sync> EventHandler for PreShutdown<F> {
+	fn pre_shutdown(&self, reason: ShutdownReason) {
+		(self.0)(reason)
+	}
 }
 
 struct MonitorState {
 	on_battery_since: Option<Instant>,
 	shutdown_scheduled: bool,
+	last_good_status: Option<UpsStatus>,
+	last_update: i64,
+	consecutive_failures: u32,
+	previous_charge: Option<(f64, Instant)>,
+	discharge_rates: VecDeque<f64>,
+	last_heartbeat: Option<Instant>,
+	// Set when a shutdown was cancelled through the admin endpoint; suppresses
+	// further shutdowns until the UPS is back on line power
+	shutdown_cancelled: bool,
+	temperature_alert_active: bool,
+	// When the RB flag was last notified; None while the flag is clear
+	replace_battery_notified: Option<Instant>,
+	overload_active: bool,
+	bypass_active: bool,
+	// Set while an admin-triggered simulated outage replaces the readings
+	simulated_outage: bool,
+	// Last ups.status without any known NUT state, warned about once
+	unrecognized_status: Option<String>,
+	// When each event type was last notified, for the cooldown
+	last_notified: HashMap<EventType, Instant>,
+	// Latest event held back by the cooldown, sent once it expires
+	pending_notification: Option<PowerEvent>,
+	// Tightest countdown milestone already notified during this outage
+	countdown_milestone: Option<u64>,
+	// Runtime reported on line power, preferably at full charge, which
+	// `runtime_percent_threshold` is relative to
+	runtime_baseline: Option<u64>,
+	// Lowest `warn_levels` entry already warned about during this outage
+	warn_level: Option<f64>,
+	// How long the last `get_status` took, including the connection
+	poll_duration: Option<Duration>,
+	// Duration and start (Unix timestamp) of the most recent completed outage
+	last_outage: Option<(u64, i64)>,
+	// When the UPS last switched to battery; None if not seen since startup
+	last_transfer: Option<Instant>,
+	// Outage start read from the state file, applied if the first reading is on battery
+	restored_on_battery_since: Option<Instant>,
+	// Aggregates of the key readings since startup
+	lifetime: LifetimeAggregates,
+	// Model, serial and so on from the startup LIST VAR; they don't change
+	device_info: BTreeMap<String, String>,
+	// When upsd started answering DATA-STALE or the readings froze; None while fresh
+	data_stale_since: Option<Instant>,
+	// Consecutive polls returning exactly the previous reading
+	unchanged_polls: u32,
+}
+
+#[derive(Default)]
+struct LifetimeAggregates {
+	battery_charge: RunningStats,
+	battery_runtime: RunningStats,
+	output_power: RunningStats,
+	battery_voltage: RunningStats,
+}
+
+impl LifetimeAggregates {
+	fn record(&mut self, status: &UpsStatus) {
+		self.battery_charge.record(status.battery_charge);
+		self
+			.battery_runtime
+			.record(status.battery_runtime.map(|runtime| runtime as f64));
+		self.output_power.record(status.output_power);
+		self.battery_voltage.record(status.battery_voltage);
+	}
+
+	fn stats(&self) -> LifetimeStats {
+		LifetimeStats {
+			battery_charge_percent: self.battery_charge.stats(),
+			battery_runtime_seconds: self.battery_runtime.stats(),
+			output_power_watts: self.output_power.stats(),
+			battery_volts: self.battery_voltage.stats(),
+		}
+	}
+}
+
+// Min, max and mean of a reading without keeping the samples
+#[derive(Default)]
+struct RunningStats {
+	min: f64,
+	max: f64,
+	sum: f64,
+	count: u64,
+}
+
+impl RunningStats {
+	fn record(&mut self, value: Option<f64>) {
+		let Some(value) = value else {
+			return;
+		};
+
+		if self.count == 0 {
+			self.min = value;
+			self.max = value;
+		} else {
+			self.min = self.min.min(value);
+			self.max = self.max.max(value);
+		}
+		self.sum += value;
+		self.count += 1;
+	}
+
+	fn stats(&self) -> Option<ReadingStats> {
+		(self.count > 0).then(|| ReadingStats {
+			min: self.min,
+			max: self.max,
+			avg: self.sum / self.count as f64,
+		})
+	}
+}
+
+// Number of charge-drop samples averaged to smooth the discharge rate
+const DISCHARGE_RATE_SAMPLES: usize = 5;
+
+// Estimated seconds until shutdown at which a countdown notification is sent
+const COUNTDOWN_MILESTONES: [u64; 3] = [300, 60, 30];
+
+// Upper bound for the delay between startup connection attempts
+const MAX_STARTUP_BACKOFF: Duration = Duration::from_secs(30);
+
+// How often a persisting RB (replace battery) flag is notified again
+const REPLACE_BATTERY_REMINDER: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Pause between attempts when `retry_shutdown_command` is set
+const SHUTDOWN_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// `[hooks]` commands still running after this are killed, so a hung one
+// doesn't pile up with every power flap
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Saved outage starts older than this are assumed to be left over from an
+// outage that ended while RabbitNUT wasn't running
+const MAX_RESTORED_OUTAGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The shutdown trigger that matched in `should_shutdown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+	OnBatteryTimeout,
+	LowBattery,
+	LowRuntime,
+	StaleData,
+}
+
+impl fmt::Display for ShutdownReason {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let reason = match self {
+			ShutdownReason::OnBatteryTimeout => "on battery timeout",
+			ShutdownReason::LowBattery => "low battery charge",
+			ShutdownReason::LowRuntime => "low battery runtime",
+			ShutdownReason::StaleData => "stale UPS data",
+		};
+		write!(f, "{}", reason)
+	}
 }
 
 impl UpsMonitor {
+	/// Builds the monitor; must be called within the Tokio runtime that will
+	/// drive `run`, as the signal handler and MQTT client are spawned on it.
 	pub fn new(config: Config) -> Self {
-		let client = UpsClient::new(
-			config.ups.host.clone(),
-			config.ups.port,
-			config.ups.name.clone(),
-			config.ups.username.clone(),
-			config.ups.password.clone(),
-		);
+		let source: Arc<dyn UpsSource> = match config.simulation {
+			Some(ref simulation) if simulation.enabled => {
+				Arc::new(SimulatedUpsSource::new(simulation.clone()))
+			}
+			_ => Arc::new(UpsClient::from_config(&config.ups)),
+		};
+
+		let control = Arc::new(ShutdownControl::default());
+
+		let event_log =
+			config
+				.logging
+				.event_log_file
+				.as_ref()
+				.and_then(|path| match EventLog::open(path) {
+					Ok(event_log) => Some(event_log),
+					Err(e) => {
+						error!("Failed to open event log {}: {}", path, e);
+						None
+					}
+				});
+
+		#[cfg(feature = "history")]
+		let history =
+			config
+				.history
+				.as_ref()
+				.and_then(|history| match History::open(&history.database_path) {
+					Ok(history) => Some(history),
+					Err(e) => {
+						error!(
+							"Failed to open history database {}: {}",
+							history.database_path, e
+						);
+						None
+					}
+				});
 
 		// Initialize metrics server if enabled
-		let (metrics_server, runtime) = if let Some(ref metrics_config) = config.metrics {
-			if metrics_config.enabled {
-				let runtime = Runtime::new().expect("Failed to create Tokio runtime");
-				let server = Arc::new(MetricsServer::new(metrics_config.clone()));
-				(Some(server), Some(runtime))
-			} else {
-				(None, None)
-			}
-		} else {
-			(None, None)
+		let metrics_server = match config.metrics {
+			Some(ref metrics_config) if metrics_config.enabled => Some(Arc::new(MetricsServer::new(
+				metrics_config.clone(),
+				control.clone(),
+			))),
+			_ => None,
 		};
 
+		// Pushing works without the HTTP endpoint, for hosts Prometheus can't reach
+		let pushgateway = config.metrics.as_ref().and_then(|metrics_config| {
+			metrics_config
+				.push
+				.as_ref()
+				.map(|push| Pushgateway::new(metrics_config, push))
+		});
+
+		let (stop_sender, stop_signal) = watch::channel(false);
+		tokio::spawn(async move {
+			wait_for_signal().await;
+			info!("Termination signal received, stopping monitor");
+			let _ = stop_sender.send(true);
+		});
+
+		let state_file = config.monitoring.state_file.as_deref().map(StateFile::new);
+		let restored_on_battery_since = state_file.as_ref().and_then(restore_on_battery_since);
+
+		let notifier = (!config.notifications.is_empty()).then(|| {
+			Arc::new(Notifier::new(
+				config.notifications.clone(),
+				config.ups.name.clone(),
+			))
+		});
+		let mut handlers: Vec<Box<dyn EventHandler>> = Vec::new();
+		if let Some(ref notifier) = notifier {
+			handlers.push(Box::new(notifier.clone()));
+		}
+
+		#[cfg(feature = "mqtt")]
+		let mqtt = config
+			.mqtt
+			.as_ref()
+			.map(|mqtt_config| MqttPublisher::new(mqtt_config, &config.ups.name));
+
 		UpsMonitor {
 			config,
-			client,
+			source,
 			state: MonitorState {
 				on_battery_since: None,
 				shutdown_scheduled: false,
+				last_good_status: None,
+				last_update: 0,
+				consecutive_failures: 0,
+				previous_charge: None,
+				discharge_rates: VecDeque::with_capacity(DISCHARGE_RATE_SAMPLES),
+				last_heartbeat: None,
+				shutdown_cancelled: false,
+				temperature_alert_active: false,
+				replace_battery_notified: None,
+				overload_active: false,
+				bypass_active: false,
+				simulated_outage: false,
+				unrecognized_status: None,
+				last_notified: HashMap::new(),
+				pending_notification: None,
+				countdown_milestone: None,
+				runtime_baseline: None,
+				warn_level: None,
+				poll_duration: None,
+				last_outage: None,
+				last_transfer: None,
+				restored_on_battery_since,
+				lifetime: LifetimeAggregates::default(),
+				device_info: BTreeMap::new(),
+				data_stale_since: None,
+				unchanged_polls: 0,
 			},
 			metrics_server,
-			runtime,
+			pushgateway,
+			control,
+			event_log,
+			notifier,
+			#[cfg(feature = "history")]
+			history,
+			#[cfg(feature = "mqtt")]
+			mqtt,
+			stop_signal,
+			watchdog: None,
+			state_file,
+			handlers,
+		}
+	}
+
+	/// Registers a handler, called after the built-in notifications.
+	pub fn with_handler(mut self, handler: impl EventHandler + 'static) -> Self {
+		self.handlers.push(Box::new(handler));
+		self
+	}
+
+	/// Calls `hook` with the reading whenever the UPS switches to battery.
+	pub fn on_battery(self, hook: impl Fn(&UpsStatus) + Send + Sync + 'static) -> Self {
+		self.with_handler(OnBattery(hook))
+	}
+
+	/// Calls `hook` with the reading whenever line power returns.
+	pub fn on_line(self, hook: impl Fn(&UpsStatus) + Send + Sync + 'static) -> Self {
+		self.with_handler(OnLine(hook))
+	}
+
+	/// Calls `hook` after the grace period, right before `pre_shutdown_command`
+	/// and the shutdown command run. Not called in dry-run mode.
+	pub fn pre_shutdown(self, hook: impl Fn(ShutdownReason) + Send + Sync + 'static) -> Self {
+		self.with_handler(PreShutdown(hook))
+	}
+
+	// Handlers run on the monitor task, so a panicking one is logged rather
+	// than taking the monitor down with it
+	fn dispatch(&self, name: &str, call: impl Fn(&dyn EventHandler)) {
+		for handler in &self.handlers {
+			let handler = handler.as_ref();
+			if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| call(handler))).is_err() {
+				error!("An event handler panicked in {}", name);
+			}
 		}
 	}
 
-	pub fn run(&mut self) {
+	pub async fn run(&mut self) {
 		info!(
 			"Starting UPS monitor for {}@{}",
 			self.config.ups.name, self.config.ups.host
@@ -65,223 +403,2486 @@ impl UpsMonitor {
 
 		// Start metrics server if enabled
 		if let Some(ref server) = self.metrics_server {
-			if let Some(ref runtime) = self.runtime {
-				let server_clone = server.clone();
-				runtime.spawn(async move {
-					server_clone.start().await;
-				});
-				info!("Metrics server started");
-			}
+			let server_clone = server.clone();
+			tokio::spawn(async move {
+				server_clone.start().await;
+			});
+			info!("Metrics server started");
 		}
 
-		self.print_ups_info();
+		// Counted from the start so startup retries are included
+		let deadline = self
+			.config
+			.monitoring
+			.max_runtime_secs
+			.map(|secs| (secs, Instant::now() + Duration::from_secs(secs)));
+
+		self.print_ups_info().await;
+		self.apply_set_vars().await;
+
+		let watchdog_secs = self.config.monitoring.cycle_watchdog_secs;
+		if watchdog_secs > 0 {
+			self.watchdog = Some(Watchdog::start(Duration::from_secs(watchdog_secs)));
+		}
 
 		loop {
-			if let Err(e) = self.monitor_cycle() {
-				error!("Monitor cycle error: {}", e);
+			self.touch_watchdog();
+			let result = self.monitor_cycle().await;
+			if let Err(ref e) = result {
+				self.record_failure(e);
+			}
+
+			match result {
+				Ok(()) => {}
+				Err(e @ (UpsError::AccessDenied | UpsError::InvalidCredentials(_))) => {
+					error!(
+						"Monitor cycle error: {} - check the NUT credentials and upsd.users permissions",
+						e
+					);
+				}
+				Err(e @ UpsError::DataStale) => {
+					error!(
+						"Monitor cycle error: {} - upsd is reachable but its driver lost contact with the UPS, check the UPS cable and driver",
+						e
+					);
+				}
+				Err(e @ UpsError::UnknownUps) => {
+					error!(
+						"Monitor cycle error: {} - check that ups.name '{}' matches the NUT server",
+						e, self.config.ups.name
+					);
+				}
+				Err(e) => error!("Monitor cycle error: {}", e),
 			}
 
+			self.check_stale_data_shutdown().await;
+
 			if self.state.shutdown_scheduled {
 				break;
 			}
 
-			thread::sleep(Duration::from_secs(self.config.monitoring.poll_interval));
+			let mut interval = Duration::from_secs(self.config.monitoring.poll_interval);
+			if let Some((_, deadline)) = deadline {
+				interval = interval.min(deadline.saturating_duration_since(Instant::now()));
+			}
+			if self.idle(interval).await {
+				break;
+			}
+
+			if let Some((secs, deadline)) = deadline
+				&& Instant::now() >= deadline
+			{
+				info!("Maximum runtime of {}s reached, stopping monitor", secs);
+				break;
+			}
 		}
+
+		self.stop().await;
 	}
 
-	fn print_ups_info(&self) {
-		info!("Attempting to connect to UPS and retrieve variables...");
+	/// Polls once for `--once`, prints a check-plugin style summary and returns
+	/// its exit code: 0 OK, 1 WARNING, 2 CRITICAL or 3 UNKNOWN. Never shuts down.
+	pub async fn check_once(&mut self) -> i32 {
+		let status = match self.source.get_status().await {
+			Ok(status) => status,
+			Err(e) => {
+				println!("UNKNOWN - {}: {}", self.config.ups.name, e);
+				return 3;
+			}
+		};
 
-		match self.client.list_vars() {
-			Ok(vars) => {
-				info!("Connected successfully");
-				debug!("UPS variables:");
-				for (name, value) in vars {
-					debug!("  {}: {}", name, value);
-				}
+		let (code, summary) = self.check_summary(&status);
+		let label = ["OK", "WARNING", "CRITICAL"][code as usize];
+		let charge = status.battery_charge.map_or(String::new(), |charge| {
+			format!(
+				" charge={}%;;{};0;100",
+				charge, self.config.shutdown.battery_percent_threshold
+			)
+		});
+		let runtime = status.battery_runtime.map_or(String::new(), |runtime| {
+			format!(" runtime={}s;;{};0;", runtime, self.runtime_threshold())
+		});
+
+		println!(
+			"{} - {}: {}, charge {}%, runtime {}s |{}{}",
+			label,
+			self.config.ups.name,
+			summary,
+			or_unknown(status.battery_charge),
+			or_unknown(status.battery_runtime),
+			charge,
+			runtime
+		);
+		code
+	}
+
+	// Health of a single reading for `--once`: critical when a charge or
+	// runtime threshold is crossed on battery, warning for any other outage
+	// or a UPS fault flag
+	fn check_summary(&self, status: &UpsStatus) -> (i32, String) {
+		if status.on_battery {
+			if let Some(charge) = status.battery_charge
+				&& charge <= self.config.shutdown.battery_percent_threshold
+			{
+				return (
+					2,
+					format!(
+						"on battery, charge below {}%",
+						self.config.shutdown.battery_percent_threshold
+					),
+				);
 			}
-			Err(e) => {
-				warn!("Failed to list UPS variables: {}", e);
+			if let Some(runtime) = status.battery_runtime
+				&& runtime <= self.runtime_threshold()
+			{
+				return (
+					2,
+					format!("on battery, runtime below {}s", self.runtime_threshold()),
+				);
+			}
+			return (1, "on battery".to_string());
+		}
+
+		for (flag, problem) in [
+			("OVER", "output overloaded"),
+			("BYPASS", "on bypass"),
+			("RB", "battery needs replacing"),
+		] {
+			if status.has_flag(flag) {
+				return (1, problem.to_string());
 			}
 		}
+
+		(0, "on line power".to_string())
 	}
 
-	fn monitor_cycle(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-		let status = self.client.get_status()?;
+	fn touch_watchdog(&self) {
+		if let Some(ref watchdog) = self.watchdog {
+			watchdog.touch();
+		}
+	}
 
-		debug!("UPS Status: {}", status);
+	// Wait until the next poll, pinging the NUT server every `keepalive_secs`
+	// so a silently dropped idle session is replaced before it is needed
+	async fn idle(&self, duration: Duration) -> bool {
+		let Some(keepalive) = self.config.ups.keepalive_secs.map(Duration::from_secs) else {
+			return self.wait(duration).await;
+		};
 
-		// Update metrics if server is enabled
-		if let Some(ref server) = self.metrics_server {
-			if let Some(ref runtime) = self.runtime {
-				let on_battery_duration = self
-					.state
-					.on_battery_since
-					.map(|since| since.elapsed().as_secs());
-
-				let server_clone = server.clone();
-				let ups_name = self.config.ups.name.clone();
-				let ups_host = self.config.ups.host.clone();
-				let status_clone = status.clone();
-
-				runtime.spawn(async move {
-					server_clone
-						.update_metrics(ups_name, ups_host, status_clone, on_battery_duration)
-						.await;
-				});
+		let deadline = Instant::now() + duration;
+		loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			if remaining <= keepalive {
+				return self.wait(remaining).await;
+			}
+			if self.wait(keepalive).await {
+				return true;
 			}
+			self.source.keep_alive().await;
 		}
+	}
 
-		self.update_battery_state(&status);
+	/// Sleeps for `duration`, returning early with `true` if a stop was requested.
+	async fn wait(&self, duration: Duration) -> bool {
+		let mut stop_signal = self.stop_signal.clone();
+		tokio::select! {
+			_ = tokio::time::sleep(duration) => false,
+			_ = stop_signal.wait_for(|stopped| *stopped) => true,
+		}
+	}
 
-		if self.should_shutdown(&status) {
-			self.execute_shutdown();
+	// Let the metrics server close its socket and pending notifications go out,
+	// bounded by `shutdown_timeout_secs`
+	async fn stop(&mut self) {
+		if let Some(ref server) = self.metrics_server {
+			server.stop();
 		}
 
-		Ok(())
+		let timeout = Duration::from_secs(self.config.monitoring.shutdown_timeout_secs);
+		if let Some(ref notifier) = self.notifier
+			&& tokio::time::timeout(timeout, notifier.flush())
+				.await
+				.is_err()
+		{
+			warn!("Timed out waiting for pending notifications");
+		}
+
+		if self.config.monitoring.log_lifetime_stats {
+			self.log_lifetime_stats();
+		}
+
+		info!("UPS monitor stopped");
 	}
 
-	fn update_battery_state(&mut self, status: &UpsStatus) {
-		if status.on_battery {
-			if self.state.on_battery_since.is_none() {
-				self.state.on_battery_since = Some(Instant::now());
-				warn!("⚠️  UPS switched to battery power!");
-				self.log_battery_status(status);
+	fn log_lifetime_stats(&self) {
+		let stats = self.state.lifetime.stats();
+		let readings = [
+			("Battery charge", "%", stats.battery_charge_percent),
+			("Battery runtime", "s", stats.battery_runtime_seconds),
+			("Output power", "W", stats.output_power_watts),
+			("Battery voltage", "V", stats.battery_volts),
+		];
+
+		for (name, unit, stats) in readings {
+			if let Some(stats) = stats {
+				info!(
+					"{} since startup: min {:.1}{unit}, avg {:.1}{unit}, max {:.1}{unit}",
+					name,
+					stats.min,
+					stats.avg,
+					stats.max,
+					unit = unit
+				);
 			}
-		} else if self.state.on_battery_since.is_some() {
-			info!("✓ UPS back on line power");
-			self.state.on_battery_since = None;
 		}
 	}
 
-	fn log_battery_status(&self, status: &UpsStatus) {
-		info!(
-			"Battery status - Charge: {}%, Runtime: {} minutes",
-			status.battery_charge,
-			status.battery_runtime / 60
-		);
+	// Push `[ups.set_vars]` to the UPS; failures are logged and monitoring
+	// carries on with whatever the UPS has configured
+	async fn apply_set_vars(&self) {
+		let mut vars: Vec<_> = self.config.ups.set_vars.iter().collect();
+		vars.sort();
 
-		if self.config.shutdown.enabled {
-			info!("Shutdown thresholds:");
-			info!(
-				"  - After {} seconds on battery",
-				self.config.shutdown.on_battery_seconds
-			);
-			info!(
-				"  - Below {}% charge",
-				self.config.shutdown.battery_percent_threshold
-			);
-			info!(
-				"  - Below {} seconds runtime",
-				self.config.shutdown.runtime_threshold
-			);
+		for (var, value) in vars {
+			match self.source.set_var(var, value).await {
+				Ok(()) => info!("Set {} to {}", var, value),
+				Err(e) => error!("Failed to set {} to {}: {}", var, value, e),
+			}
 		}
 	}
 
-	fn should_shutdown(&mut self, status: &UpsStatus) -> bool {
-		if !self.config.shutdown.enabled || self.state.shutdown_scheduled {
-			return false;
-		}
+	// Retry with exponential backoff until `startup_timeout_secs` runs out, so a
+	// NUT server that is still starting at boot doesn't fail the first polls
+	async fn print_ups_info(&mut self) {
+		info!("Attempting to connect to UPS and retrieve variables...");
 
-		if !status.on_battery {
-			return false;
+		let deadline =
+			Instant::now() + Duration::from_secs(self.config.monitoring.startup_timeout_secs);
+		let mut backoff = Duration::from_secs(1);
+		let mut attempt = 1;
+
+		loop {
+			match self.source.list_vars().await {
+				Ok(vars) => {
+					info!("Connected successfully");
+					self.state.device_info = device_info(&vars);
+					self.log_server_version().await;
+					debug!("UPS variables:");
+					for (name, value) in vars {
+						debug!("  {}: {}", name, value);
+					}
+					return;
+				}
+				// upsd reads its UPS list at startup, so retrying won't help
+				Err(UpsError::UnknownUps) => {
+					self.report_unknown_ups().await;
+					return;
+				}
+				Err(e) => {
+					self.report_poll_failure(&e);
+					let remaining = deadline.saturating_duration_since(Instant::now());
+					if remaining.is_zero() {
+						warn!(
+							"Failed to list UPS variables after {} attempts: {}",
+							attempt, e
+						);
+						return;
+					}
+
+					let delay = backoff.min(remaining);
+					warn!(
+						"Connection attempt {} failed: {} - retrying in {:.0}s",
+						attempt,
+						e,
+						delay.as_secs_f64()
+					);
+					if self.wait(delay).await {
+						return;
+					}
+					backoff = (backoff * 2).min(MAX_STARTUP_BACKOFF);
+					attempt += 1;
+				}
+			}
 		}
+	}
 
-		// Check time on battery
-		if let Some(since) = self.state.on_battery_since {
-			let elapsed = since.elapsed().as_secs();
-			if elapsed >= self.config.shutdown.on_battery_seconds {
+	// Name the UPSes the server does have, as `ups.name` is most likely a typo
+	async fn report_unknown_ups(&self) {
+		let name = &self.config.ups.name;
+		match self.source.list_ups().await {
+			Ok(upses) if upses.is_empty() => error!(
+				"UPS '{}' does not exist and the NUT server has no UPS configured, check ups.conf",
+				name
+			),
+			Ok(upses) => {
+				let available: Vec<String> = upses
+					.iter()
+					.map(|(ups, description)| {
+						if description.is_empty() {
+							ups.clone()
+						} else {
+							format!("{} ({})", ups, description)
+						}
+					})
+					.collect();
 				error!(
-					"🔴 UPS on battery for {} seconds (threshold: {}), triggering shutdown",
-					elapsed, self.config.shutdown.on_battery_seconds
+					"UPS '{}' does not exist on the NUT server, set ups.name to one of: {}",
+					name,
+					available.join(", ")
 				);
-				return true;
 			}
+			Err(e) => error!(
+				"UPS '{}' does not exist on the NUT server, and listing its UPSes failed: {}",
+				name, e
+			),
+		}
+	}
 
-			// Log remaining time periodically
-			let remaining = self.config.shutdown.on_battery_seconds - elapsed;
-			if remaining % 60 == 0 || remaining <= 30 {
-				warn!("Time until shutdown: {} seconds", remaining);
-			}
+	async fn log_server_version(&self) {
+		match self.source.server_version().await {
+			Ok(version) => info!(
+				"NUT server version: {}, protocol version: {}",
+				version.server.as_deref().unwrap_or("unknown"),
+				version.protocol.as_deref().unwrap_or("unknown")
+			),
+			Err(e) => warn!("Failed to query NUT server version: {}", e),
 		}
+	}
 
-		// Check battery charge threshold
-		if status.battery_charge <= self.config.shutdown.battery_percent_threshold {
-			error!(
-				"🔴 Battery charge {}% below threshold {}%, triggering shutdown",
-				status.battery_charge, self.config.shutdown.battery_percent_threshold
+	async fn monitor_cycle(&mut self) -> Result<(), UpsError> {
+		let poll_started = Instant::now();
+		let result = self.source.get_status().await;
+		self.state.poll_duration = Some(poll_started.elapsed());
+		let mut status = result?;
+		self.apply_simulated_outage(&mut status);
+
+		debug!("UPS Status: {}", status);
+
+		let failures = self.state.consecutive_failures;
+		if failures > 0 && failures >= self.config.monitoring.max_failures_before_stale {
+			info!(
+				"UPS polling recovered after {} consecutive failures",
+				failures
 			);
-			return true;
 		}
+		self.state.consecutive_failures = 0;
+		self.track_frozen_readings(&status);
+		self.state.last_good_status = Some(status.clone());
 
-		// Check runtime threshold
-		if status.battery_runtime <= self.config.shutdown.runtime_threshold {
-			error!(
-				"🔴 Battery runtime {} seconds below threshold {}, triggering shutdown",
-				status.battery_runtime, self.config.shutdown.runtime_threshold
+		if self
+			.config
+			.monitoring
+			.failure_alert_threshold
+			.is_some_and(|threshold| failures >= threshold)
+		{
+			self.record_event(
+				EventType::PollRecovered,
+				Some(format!(
+					"Recovered after {} consecutive poll failures",
+					failures
+				)),
 			);
-			return true;
+		}
+		self.state.last_update = chrono::Utc::now().timestamp();
+
+		// Injected readings would distort the lowest charge ever seen
+		if !self.state.simulated_outage {
+			self.state.lifetime.record(&status);
+		}
+
+		self.check_status_recognized(&status);
+		self.update_battery_state(&status);
+		self.update_runtime_baseline(&status);
+		self.update_discharge_rate(&status);
+		self.log_heartbeat(&status);
+		self.check_temperature(&status);
+		self.check_replace_battery(&status);
+		self.check_output_state(&status);
+		self.check_warn_levels(&status);
+		self.check_countdown_milestone(&status);
+		self.flush_pending_notification();
+		self.dispatch("on_poll", |handler| handler.on_poll(&status));
+
+		self.publish_metrics(&status, true);
+
+		#[cfg(feature = "mqtt")]
+		if let Some(ref mqtt) = self.mqtt {
+			let on_battery_duration = self
+				.state
+				.on_battery_since
+				.map(|since| since.elapsed().as_secs());
+			mqtt.publish_status(&status, on_battery_duration);
 		}
 
-		false
+		if let Some(reason) = self.should_shutdown(&status) {
+			self.execute_shutdown(reason).await;
+		}
+
+		Ok(())
 	}
 
-	fn execute_shutdown(&mut self) {
-		if self.state.shutdown_scheduled {
+	// Periodic info-level summary so quiet logs still show the daemon is alive
+	fn log_heartbeat(&mut self, status: &UpsStatus) {
+		let interval = self.config.monitoring.heartbeat_interval_secs;
+		if interval == 0 {
 			return;
 		}
 
-		self.state.shutdown_scheduled = true;
+		let due = self
+			.state
+			.last_heartbeat
+			.is_none_or(|last| last.elapsed() >= Duration::from_secs(interval));
+		if due {
+			info!("Heartbeat: {}", status);
+			self.state.last_heartbeat = Some(Instant::now());
+		}
+	}
 
-		error!(
-			"🚨 INITIATING SYSTEM SHUTDOWN IN {} SECONDS! 🚨",
-			self.config.shutdown.shutdown_grace_period
-		);
+	// Warn once when a reported temperature crosses the alert threshold and
+	// again when it drops back below it
+	fn check_temperature(&mut self, status: &UpsStatus) {
+		let Some(threshold) = self.config.monitoring.temperature_alert_celsius else {
+			return;
+		};
 
-		// Log countdown
-		for i in (1..=self.config.shutdown.shutdown_grace_period).rev() {
-			if i <= 10 || i % 10 == 0 {
-				warn!("Shutdown in {} seconds...", i);
+		let hottest = [
+			("Battery", status.battery_temperature),
+			("UPS", status.ups_temperature),
+		]
+		.into_iter()
+		.filter_map(|(name, temperature)| temperature.map(|t| (name, t)))
+		.max_by(|a, b| a.1.total_cmp(&b.1));
+
+		let Some((name, temperature)) = hottest else {
+			return;
+		};
+
+		if temperature >= threshold && !self.state.temperature_alert_active {
+			warn!(
+				"🌡️  {} temperature {}°C is above the alert threshold of {}°C",
+				name, temperature, threshold
+			);
+			self.state.temperature_alert_active = true;
+			self.record_event(
+				EventType::TemperatureHigh,
+				Some(format!("{} temperature {}°C", name, temperature)),
+			);
+		} else if temperature < threshold && self.state.temperature_alert_active {
+			info!(
+				"{} temperature back to {}°C, below the alert threshold of {}°C",
+				name, temperature, threshold
+			);
+			self.state.temperature_alert_active = false;
+		}
+	}
+
+	// A failing battery is reported when the RB flag appears and then once a
+	// day for as long as it stays set
+	fn check_replace_battery(&mut self, status: &UpsStatus) {
+		if !status.has_flag("RB") {
+			if self.state.replace_battery_notified.take().is_some() {
+				info!("UPS no longer reports that its battery needs replacing");
 			}
-			thread::sleep(Duration::from_secs(1));
+			return;
 		}
 
-		// Parse and execute shutdown command
-		let parts: Vec<&str> = self
-			.config
-			.shutdown
-			.shutdown_command
-			.split_whitespace()
-			.collect();
+		let due = self
+			.state
+			.replace_battery_notified
+			.is_none_or(|notified| notified.elapsed() >= REPLACE_BATTERY_REMINDER);
+		if due {
+			warn!(
+				"🪫 UPS reports that its battery needs replacing (RB); it may not provide the rated runtime"
+			);
+			self.state.replace_battery_notified = Some(Instant::now());
+			self.record_event(EventType::ReplaceBattery, None);
+		}
+	}
 
-		if parts.is_empty() {
-			error!("Shutdown command is empty!");
+	// Replace the reading with the outage injected through the admin endpoint,
+	// so thresholds and notifications run end to end
+	fn apply_simulated_outage(&mut self, status: &mut UpsStatus) {
+		let outage = self.control.simulated_outage();
+		if outage.is_some() != self.state.simulated_outage {
+			if outage.is_some() {
+				warn!("🧪 Simulated outage started, shutdowns are forced into dry-run");
+			} else {
+				info!("🧪 Simulated outage ended, using real UPS readings again");
+			}
+			self.state.simulated_outage = outage.is_some();
+		}
+
+		if let Some(outage) = outage {
+			status.battery_charge = Some(outage.battery_charge);
+			status.battery_runtime = Some(outage.battery_runtime);
+			status.ups_status = "OB DISCHRG".to_string();
+			status.on_battery = true;
+			status.charging = false;
+		}
+	}
+
+	// Warn once per distinct value when ups.status has no state NUT defines;
+	// such a status never counts as on battery
+	fn check_status_recognized(&mut self, status: &UpsStatus) {
+		if status.is_recognized() {
+			self.state.unrecognized_status = None;
 			return;
 		}
 
-		info!(
-			"Executing shutdown command: {}",
-			self.config.shutdown.shutdown_command
-		);
+		if self.state.unrecognized_status.as_deref() != Some(status.ups_status.as_str()) {
+			warn!(
+				"Unrecognized ups.status '{}', treating the UPS as not on battery",
+				status.ups_status
+			);
+			self.state.unrecognized_status = Some(status.ups_status.clone());
+		}
+	}
 
-		match Command::new(parts[0]).args(&parts[1..]).output() {
-			Ok(output) => {
-				if output.status.success() {
-					info!("Shutdown command executed successfully");
-				} else {
-					error!(
-						"Shutdown command failed: {:?}",
-						String::from_utf8_lossy(&output.stderr)
-					);
-				}
-			}
-			Err(e) => {
-				error!("Failed to execute shutdown command: {}", e);
-				error!(
-					"Please ensure the command '{}' is valid and accessible",
-					parts[0]
-				);
-			}
+	// OVER and BYPASS put the load at risk even on line power, so they are
+	// reported independently of the battery state
+	fn check_output_state(&mut self, status: &UpsStatus) {
+		let overload = status.has_flag("OVER");
+		if overload && !self.state.overload_active {
+			warn!("⚡ UPS output is overloaded (OVER); reduce the connected load");
+			self.record_event(EventType::Overload, None);
+		} else if !overload && self.state.overload_active {
+			info!("UPS output is no longer overloaded");
 		}
+		self.state.overload_active = overload;
+
+		let bypass = status.has_flag("BYPASS");
+		if bypass && !self.state.bypass_active {
+			warn!("⚠️  UPS is on bypass (BYPASS); the battery is not protecting the load");
+			self.record_event(EventType::OnBypass, None);
+		} else if !bypass && self.state.bypass_active {
+			info!("UPS is no longer on bypass");
+		}
+		self.state.bypass_active = bypass;
+	}
+
+	// Warn once per outage for each `warn_levels` entry the charge drops below.
+	// Levels crossed within a single poll collapse into one warning for the lowest.
+	fn check_warn_levels(&mut self, status: &UpsStatus) {
+		let Some(charge) = status.battery_charge.filter(|_| status.on_battery) else {
+			return;
+		};
+
+		let Some(level) = crossed_warn_level(
+			&self.config.monitoring.warn_levels,
+			charge,
+			self.state.warn_level,
+		) else {
+			return;
+		};
+
+		warn!(
+			"🔋 Battery charge {}% dropped below the {}% warning level",
+			charge, level
+		);
+		self.state.warn_level = Some(level);
+		self.record_event(
+			EventType::BatteryLow,
+			Some(format!("Charge dropped below {}%", level)),
+		);
+	}
+
+	// Tell dependent hosts about the shutdown before the countdown starts
+	async fn notify_hosts(&self, reason: ShutdownReason) {
+		let hosts = &self.config.shutdown.notify_hosts;
+		if hosts.is_empty() {
+			return;
+		}
+
+		let status = self.state.last_good_status.as_ref();
+		let announcement = ShutdownAnnouncement {
+			event: "shutdown",
+			ups_name: self.config.ups.name.clone(),
+			ups_host: self.config.ups.host.clone(),
+			reason: reason.to_string(),
+			grace_period_seconds: self.config.shutdown.shutdown_grace_period,
+			battery_charge: status.and_then(|s| s.battery_charge),
+			battery_runtime: status.and_then(|s| s.battery_runtime),
+		};
+
+		info!("Notifying {} host(s) of the shutdown", hosts.len());
+		let hosts = hosts.clone();
+		run_guarded(
+			"Shutdown announcement",
+			self.pre_shutdown_timeout(),
+			async move {
+				announce_shutdown(&hosts, &announcement).await;
+				Ok(())
+			},
+		)
+		.await;
+	}
+
+	fn pre_shutdown_timeout(&self) -> Duration {
+		Duration::from_secs(self.config.shutdown.pre_shutdown_timeout_secs)
+	}
+
+	// Append to the event log and history, using the latest reading for the UPS fields
+	fn record_event(&mut self, event: EventType, reason: Option<String>) {
+		let status = self.state.last_good_status.as_ref();
+		let event = PowerEvent {
+			timestamp: chrono::Utc::now().to_rfc3339(),
+			event,
+			battery_charge: status.and_then(|s| s.battery_charge),
+			battery_runtime: status.and_then(|s| s.battery_runtime),
+			ups_status: status.map(|s| s.ups_status.clone()),
+			on_battery_duration_seconds: self
+				.state
+				.on_battery_since
+				.map(|since| since.elapsed().as_secs()),
+			reason,
+		};
+
+		if let Some(ref mut event_log) = self.event_log
+			&& let Err(e) = event_log.append(&event)
+		{
+			error!("Failed to write event log: {}", e);
+		}
+
+		#[cfg(feature = "history")]
+		if let Some(ref history) = self.history
+			&& let Err(e) = history.insert(&event)
+		{
+			error!("Failed to write event to history database: {}", e);
+		}
+
+		self.notify(event);
+	}
+
+	// Send a notification, holding back flapping-prone events inside the cooldown.
+	// Only the latest held-back event is kept, and opposite power transitions
+	// cancel out, so a burst of changes collapses into the final state.
+	fn notify(&mut self, event: PowerEvent) {
+		if self.handlers.is_empty() {
+			return;
+		}
+
+		let cooldown = Duration::from_secs(self.config.monitoring.notification_cooldown_secs);
+		let rate_limited = matches!(
+			event.event,
+			EventType::OnBattery | EventType::OnLine | EventType::TemperatureHigh
+		);
+		let in_cooldown = self
+			.state
+			.last_notified
+			.get(&event.event)
+			.is_some_and(|sent| sent.elapsed() < cooldown);
+
+		if rate_limited && in_cooldown {
+			let cancels_pending = self
+				.state
+				.pending_notification
+				.as_ref()
+				.is_some_and(|pending| event.event.opposite() == Some(pending.event));
+
+			debug!(
+				"Holding back {} notification (cooldown)",
+				event.event.as_str()
+			);
+			self.state.pending_notification = if cancels_pending { None } else { Some(event) };
+			return;
+		}
+
+		// A newer transition supersedes any held-back one
+		if event.event.opposite().is_some() {
+			self.state.pending_notification = None;
+		}
+
+		self.dispatch("on_event", |handler| handler.on_event(&event));
+		self.state.last_notified.insert(event.event, Instant::now());
+	}
+
+	fn flush_pending_notification(&mut self) {
+		let cooldown = Duration::from_secs(self.config.monitoring.notification_cooldown_secs);
+		let ready = self
+			.state
+			.pending_notification
+			.as_ref()
+			.is_some_and(|pending| {
+				self
+					.state
+					.last_notified
+					.get(&pending.event)
+					.is_none_or(|sent| sent.elapsed() >= cooldown)
+			});
+
+		if ready && let Some(pending) = self.state.pending_notification.take() {
+			self.notify(pending);
+		}
+	}
+
+	// Notify when the shutdown estimate drops below 5 minutes, 1 minute and 30
+	// seconds rather than on every poll
+	fn check_countdown_milestone(&mut self, status: &UpsStatus) {
+		let Some(estimate) = self.estimate_seconds_until_shutdown(status) else {
+			return;
+		};
+
+		let Some(milestone) = COUNTDOWN_MILESTONES
+			.into_iter()
+			.filter(|milestone| estimate <= *milestone as f64)
+			.min()
+		else {
+			return;
+		};
+
+		if self
+			.state
+			.countdown_milestone
+			.is_none_or(|notified| milestone < notified)
+		{
+			self.state.countdown_milestone = Some(milestone);
+			let reason = if milestone >= 60 {
+				format!("Shutdown in less than {} minute(s)", milestone / 60)
+			} else {
+				format!("Shutdown in less than {} seconds", milestone)
+			};
+			self.record_event(EventType::ShutdownCountdown, Some(reason));
+		}
+	}
+
+	// Some drivers keep serving their last values instead of DATA-STALE after
+	// losing the UPS, so identical readings for `stale_after_unchanged_polls`
+	// polls in a row count as stale too. Must run before `last_good_status`
+	// is replaced.
+	fn track_frozen_readings(&mut self, status: &UpsStatus) {
+		// Injected readings never change, and a healthy UPS on line power can
+		// report the same values for hours
+		let limit = self.config.monitoring.stale_after_unchanged_polls;
+		let Some(limit) = limit.filter(|_| status.on_battery && !self.state.simulated_outage) else {
+			self.state.unchanged_polls = 0;
+			self.set_data_stale(None);
+			return;
+		};
+
+		// Any changed reading or status flag, a transfer included, shows the driver is live
+		if self.state.last_good_status.as_ref() == Some(status) {
+			self.state.unchanged_polls += 1;
+		} else {
+			self.state.unchanged_polls = 0;
+		}
+
+		let frozen = self.state.unchanged_polls + 1 >= limit;
+		self.set_data_stale(frozen.then(|| {
+			format!(
+				"readings unchanged for {} polls",
+				self.state.unchanged_polls + 1
+			)
+		}));
+	}
+
+	// Flag the data stale with its cause, or fresh again with None; the charge
+	// and runtime triggers are suspended while it is stale
+	fn set_data_stale(&mut self, cause: Option<String>) {
+		match (cause, self.state.data_stale_since) {
+			(Some(cause), None) => {
+				warn!(
+					"⚠️ UPS data is stale ({}): the NUT server is reachable but the driver has lost contact with the UPS, charge and runtime thresholds are suspended",
+					cause
+				);
+				self.state.data_stale_since = Some(Instant::now());
+			}
+			(None, Some(since)) => {
+				info!(
+					"UPS data is fresh again after {} seconds",
+					since.elapsed().as_secs()
+				);
+				self.state.data_stale_since = None;
+			}
+			_ => {}
+		}
+	}
+
+	// With `stale_data_shutdown_secs` set, data that stays stale that long is
+	// treated as an outage we can no longer see and shuts down conservatively
+	async fn check_stale_data_shutdown(&mut self) {
+		let (Some(limit), Some(since)) = (
+			self.config.shutdown.stale_data_shutdown_secs,
+			self.state.data_stale_since,
+		) else {
+			return;
+		};
+
+		if !self.config.shutdown.enabled
+			|| self.state.shutdown_scheduled
+			|| self.state.shutdown_cancelled
+			|| self.control.is_disabled()
+		{
+			return;
+		}
+
+		let stale_for = since.elapsed().as_secs();
+		if stale_for >= limit {
+			error!(
+				"🔴 UPS data stale for {} seconds (threshold: {}), triggering shutdown",
+				stale_for, limit
+			);
+			self.execute_shutdown(ShutdownReason::StaleData).await;
+		}
+	}
+
+	// Count a failed poll and mark the metrics stale once the limit is reached,
+	// so a single dropped packet doesn't flap dashboards
+	fn record_failure(&mut self, error: &UpsError) {
+		self.state.consecutive_failures += 1;
+
+		if matches!(error, UpsError::DataStale) {
+			self.set_data_stale(Some("the NUT server answered ERR DATA-STALE".to_string()));
+			// upsd decides staleness until it serves data again, then counting restarts
+			self.state.unchanged_polls = 0;
+		}
+
+		if self.state.consecutive_failures == self.config.monitoring.max_failures_before_stale {
+			warn!(
+				"UPS data is stale after {} consecutive poll failures",
+				self.state.consecutive_failures
+			);
+		}
+
+		if Some(self.state.consecutive_failures) == self.config.monitoring.failure_alert_threshold {
+			self.record_event(
+				EventType::PollFailing,
+				Some(format!(
+					"{} consecutive poll failures: {}",
+					self.state.consecutive_failures, error
+				)),
+			);
+		}
+
+		// Keep serving the last good reading, flagged stale once the limit is reached
+		if let Some(status) = self.state.last_good_status.clone() {
+			let up = self.state.consecutive_failures < self.config.monitoring.max_failures_before_stale;
+			self.publish_metrics(&status, up);
+		} else {
+			self.report_poll_failure(error);
+		}
+	}
+
+	// Explain the missing metrics until the first poll succeeds
+	fn report_poll_failure(&self, error: &UpsError) {
+		if let Some(ref server) = self.metrics_server {
+			server.record_poll_failure(&error.to_string());
+		}
+	}
+
+	fn publish_metrics(&self, status: &UpsStatus, up: bool) {
+		if self.metrics_server.is_none() && self.pushgateway.is_none() {
+			return;
+		}
+
+		let metrics = self.build_metrics(status, up);
+		if up && let Some(ref pushgateway) = self.pushgateway {
+			pushgateway.push(&metrics);
+		}
+		if let Some(ref server) = self.metrics_server {
+			server.update_metrics(metrics);
+		}
+	}
+
+	fn build_metrics(&self, status: &UpsStatus, up: bool) -> Metrics {
+		Metrics {
+			ups_name: self.config.ups.name.clone(),
+			ups_host: self.config.ups.host.clone(),
+			nut_server: self.source.current_server(),
+			server_failovers: self.source.failovers(),
+			battery_charge_percent: status.battery_charge,
+			battery_runtime_seconds: status.battery_runtime,
+			ups_status: status.ups_status.clone(),
+			on_battery: status.on_battery,
+			charging: status.charging,
+			replace_battery: status.has_flag("RB"),
+			overload: status.has_flag("OVER"),
+			on_bypass: status.has_flag("BYPASS"),
+			up,
+			data_stale: self.state.data_stale_since.is_some(),
+			consecutive_poll_failures: self.state.consecutive_failures,
+			last_update: self.state.last_update,
+			on_battery_duration_seconds: self
+				.state
+				.on_battery_since
+				.map(|since| since.elapsed().as_secs()),
+			last_outage_duration_seconds: self.state.last_outage.map(|(duration, _)| duration),
+			last_outage_timestamp: self.state.last_outage.map(|(_, started)| started),
+			seconds_since_last_transfer: self
+				.state
+				.last_transfer
+				.map(|since| since.elapsed().as_secs()),
+			output_power_watts: status.output_power,
+			battery_energy_remaining_watthours: status.energy_remaining_watthours(),
+			battery_temperature_celsius: status.battery_temperature,
+			ups_temperature_celsius: status.ups_temperature,
+			input_frequency_hertz: status.input_frequency,
+			battery_volts: status.battery_voltage,
+			battery_nominal_volts: status.battery_voltage_nominal,
+			battery_charge_restart_percent: status.battery_charge_restart,
+			battery_date: status.battery_date.clone(),
+			battery_age_days: status.battery_age_days(chrono::Local::now().date_naive()),
+			extra_vars: status.extra_vars.clone(),
+			poll_duration_seconds: self.state.poll_duration.map(|d| d.as_secs_f64()),
+			seconds_until_shutdown: self.estimate_seconds_until_shutdown(status),
+			discharge_rate_percent_per_minute: self.discharge_rate().map(|rate| rate * 60.0),
+			poll_interval_seconds: self.config.monitoring.poll_interval,
+			lifetime: self.state.lifetime.stats(),
+			device_info: self.state.device_info.clone(),
+			shutdown: ShutdownThresholds {
+				enabled: self.config.shutdown.enabled,
+				dry_run: self.config.shutdown.dry_run || self.state.simulated_outage,
+				battery_percent_threshold: self.config.shutdown.battery_percent_threshold,
+				runtime_threshold: self.runtime_threshold(),
+				runtime_percent_threshold: self.config.shutdown.runtime_percent_threshold,
+				on_battery_seconds: self.config.shutdown.on_battery_seconds,
+				min_on_battery_seconds_before_action: self
+					.config
+					.shutdown
+					.min_on_battery_seconds_before_action,
+				grace_period_seconds: self.config.shutdown.shutdown_grace_period,
+			},
+		}
+	}
+
+	// Remember the runtime on line power; a full-charge reading replaces any
+	// earlier one, a partial charge reading only fills in a missing baseline
+	fn update_runtime_baseline(&mut self, status: &UpsStatus) {
+		if status.on_battery {
+			return;
+		}
+
+		let Some(runtime) = status.battery_runtime else {
+			return;
+		};
+
+		let full_charge = status.battery_charge.is_some_and(|charge| charge >= 99.0);
+		if full_charge || self.state.runtime_baseline.is_none() {
+			self.state.runtime_baseline = Some(runtime);
+		}
+	}
+
+	/// The effective runtime threshold: the larger of `runtime_threshold` and
+	/// `runtime_percent_threshold` of the baseline runtime, when one is known.
+	fn runtime_threshold(&self) -> u64 {
+		let shutdown = &self.config.shutdown;
+		let relative = shutdown
+			.runtime_percent_threshold
+			.zip(self.state.runtime_baseline)
+			.map_or(0, |(percent, baseline)| {
+				(baseline as f64 * percent / 100.0) as u64
+			});
+
+		shutdown.runtime_threshold.max(relative)
+	}
+
+	// Track the charge slope between polls while on battery
+	fn update_discharge_rate(&mut self, status: &UpsStatus) {
+		if !status.on_battery {
+			self.state.previous_charge = None;
+			self.state.discharge_rates.clear();
+			return;
+		}
+
+		// Keep the previous sample across a poll with an unknown charge
+		let Some(charge) = status.battery_charge else {
+			return;
+		};
+
+		let now = Instant::now();
+		if let Some((previous_charge, previous_time)) = self.state.previous_charge {
+			let elapsed = now.duration_since(previous_time).as_secs_f64();
+			let dropped = previous_charge - charge;
+
+			// Charge is often reported in whole percent, so keep the previous sample
+			// until the charge actually changes
+			if dropped == 0.0 {
+				return;
+			}
+
+			if elapsed > 0.0 {
+				if self.state.discharge_rates.len() == DISCHARGE_RATE_SAMPLES {
+					self.state.discharge_rates.pop_front();
+				}
+				self.state.discharge_rates.push_back(dropped / elapsed);
+			}
+		}
+
+		self.state.previous_charge = Some((charge, now));
+	}
+
+	/// Moving average of the recent discharge rate in percent per second.
+	fn discharge_rate(&self) -> Option<f64> {
+		let samples = &self.state.discharge_rates;
+		if samples.is_empty() {
+			return None;
+		}
+
+		Some(samples.iter().sum::<f64>() / samples.len() as f64)
+	}
+
+	/// Estimates how long until a shutdown trigger fires: the minimum of the time
+	/// left before the on-battery timeout, the projected time until the charge
+	/// threshold is reached and the runtime left above the runtime threshold.
+	fn estimate_seconds_until_shutdown(&self, status: &UpsStatus) -> Option<f64> {
+		let shutdown = &self.config.shutdown;
+		if !shutdown.enabled || !status.on_battery {
+			return None;
+		}
+
+		let runtime_threshold = self.runtime_threshold();
+		let mut estimate = status.battery_runtime.map_or(f64::INFINITY, |runtime| {
+			runtime.saturating_sub(runtime_threshold) as f64
+		});
+
+		if let Some(since) = self.state.on_battery_since {
+			let remaining = shutdown
+				.on_battery_seconds
+				.saturating_sub(since.elapsed().as_secs());
+			estimate = estimate.min(remaining as f64);
+		}
+
+		if let Some(rate) = self.discharge_rate().filter(|rate| *rate > 0.0)
+			&& let Some(charge) = status.battery_charge
+		{
+			let charge_left = charge - shutdown.battery_percent_threshold;
+			estimate = estimate.min((charge_left / rate).max(0.0));
+		}
+
+		// No trigger fires before the minimum time on battery has passed
+		if let Some(since) = self.state.on_battery_since {
+			let floor = shutdown
+				.min_on_battery_seconds_before_action
+				.saturating_sub(since.elapsed().as_secs());
+			estimate = estimate.max(floor as f64);
+		}
+
+		Some(estimate).filter(|estimate| estimate.is_finite())
+	}
+
+	fn update_battery_state(&mut self, status: &UpsStatus) {
+		// Only the first reading after startup can continue a saved outage
+		let restored = self.state.restored_on_battery_since.take();
+
+		if status.on_battery {
+			if self.state.on_battery_since.is_some() {
+				return;
+			}
+
+			if let Some(since) = restored {
+				self.state.on_battery_since = Some(since);
+				self.state.last_transfer = Some(since);
+				warn!(
+					"⚠️  UPS still on battery, resuming the outage that started {} seconds ago",
+					since.elapsed().as_secs()
+				);
+				self.log_battery_status(status);
+				self.dispatch("on_battery", |handler| handler.on_battery(status));
+				return;
+			}
+
+			self.state.on_battery_since = Some(Instant::now());
+			warn!("⚠️  UPS switched to battery power!");
+			if let Some(previous) = self.state.last_transfer.replace(Instant::now()) {
+				info!(
+					"Previous transfer to battery was {} seconds ago",
+					previous.elapsed().as_secs()
+				);
+			}
+			self.log_battery_status(status);
+			self.record_event(EventType::OnBattery, None);
+			self.save_on_battery_since();
+			self.dispatch("on_battery", |handler| handler.on_battery(status));
+			self.run_battery_hook(status);
+		} else if restored.is_some() {
+			info!("UPS is on line power, discarding the outage saved before the restart");
+			self.clear_state_file();
+		} else if let Some(since) = self.state.on_battery_since {
+			let duration = since.elapsed().as_secs();
+			self.state.last_outage = Some((duration, chrono::Utc::now().timestamp() - duration as i64));
+			info!("✓ UPS back on line power");
+			if status.charging {
+				match status.battery_charge_restart {
+					Some(restart) => info!(
+						"🔌 UPS charging, currently {}% (restart threshold {}%)",
+						or_unknown(status.battery_charge),
+						restart
+					),
+					None => info!(
+						"🔌 UPS charging, currently {}%",
+						or_unknown(status.battery_charge)
+					),
+				}
+			}
+			self.record_event(EventType::OnLine, None);
+			self.dispatch("on_line", |handler| handler.on_line(status));
+			self.run_restore_hook(duration);
+			self.state.on_battery_since = None;
+			self.state.countdown_milestone = None;
+			self.state.warn_level = None;
+			self.state.shutdown_cancelled = false;
+			self.clear_state_file();
+		}
+	}
+
+	// Run `hooks.on_battery_command` with the charge and runtime, when known,
+	// in UPS_BATTERY_CHARGE and UPS_BATTERY_RUNTIME
+	fn run_battery_hook(&self, status: &UpsStatus) {
+		let command = self
+			.config
+			.hooks
+			.as_ref()
+			.and_then(|hooks| hooks.on_battery_command.as_ref());
+
+		let mut env = Vec::new();
+		if let Some(charge) = status.battery_charge {
+			env.push(("UPS_BATTERY_CHARGE", charge.to_string()));
+		}
+		if let Some(runtime) = status.battery_runtime {
+			env.push(("UPS_BATTERY_RUNTIME", runtime.to_string()));
+		}
+		run_hook("On-battery", command, env);
+	}
+
+	// Run `hooks.on_restore_command` with the outage length in UPS_OUTAGE_DURATION
+	fn run_restore_hook(&self, outage_duration: u64) {
+		let command = self
+			.config
+			.hooks
+			.as_ref()
+			.and_then(|hooks| hooks.on_restore_command.as_ref());
+
+		run_hook(
+			"On-restore",
+			command,
+			vec![("UPS_OUTAGE_DURATION", outage_duration.to_string())],
+		);
+	}
+
+	fn save_on_battery_since(&self) {
+		if let Some(ref state_file) = self.state_file
+			&& let Err(e) = state_file.save(chrono::Utc::now().timestamp())
+		{
+			error!(
+				"Failed to write state file {}: {}",
+				state_file.path().display(),
+				e
+			);
+		}
+	}
+
+	fn clear_state_file(&self) {
+		if let Some(ref state_file) = self.state_file
+			&& let Err(e) = state_file.clear()
+		{
+			error!(
+				"Failed to remove state file {}: {}",
+				state_file.path().display(),
+				e
+			);
+		}
+	}
+
+	fn log_battery_status(&self, status: &UpsStatus) {
+		info!(
+			"Battery status - Charge: {}%, Runtime: {} minutes",
+			or_unknown(status.battery_charge),
+			or_unknown(status.battery_runtime.map(|runtime| runtime / 60))
+		);
+
+		if self.config.shutdown.enabled {
+			info!("Shutdown thresholds:");
+			info!(
+				"  - After {} seconds on battery",
+				self.config.shutdown.on_battery_seconds
+			);
+			info!(
+				"  - Below {}% charge",
+				self.config.shutdown.battery_percent_threshold
+			);
+			info!(
+				"  - Below {} seconds runtime",
+				self.config.shutdown.runtime_threshold
+			);
+			if let Some(percent) = self.config.shutdown.runtime_percent_threshold {
+				match self.state.runtime_baseline {
+					Some(baseline) => info!(
+						"  - Below {}% of the {} second baseline runtime",
+						percent, baseline
+					),
+					None => info!(
+						"  - Below {}% of the baseline runtime (no baseline recorded yet)",
+						percent
+					),
+				}
+			}
+		}
+	}
+
+	fn should_shutdown(&mut self, status: &UpsStatus) -> Option<ShutdownReason> {
+		if !self.config.shutdown.enabled || self.state.shutdown_scheduled {
+			return None;
+		}
+
+		if !status.on_battery {
+			// Nothing pending to cancel on line power
+			self.control.take_cancel();
+			return None;
+		}
+
+		if self.control.take_cancel() && !self.state.shutdown_cancelled {
+			warn!("Shutdown cancelled for the current outage");
+			self.state.shutdown_cancelled = true;
+			self.record_event(EventType::ShutdownCancelled, None);
+		}
+
+		if self.state.shutdown_cancelled || self.control.is_disabled() {
+			debug!("Shutdown checks skipped: disabled through the admin endpoint");
+			return None;
+		}
+
+		// Ignore the first readings after the transfer to battery
+		let on_battery_for = self
+			.state
+			.on_battery_since
+			.map_or(0, |since| since.elapsed().as_secs());
+		if on_battery_for < self.config.shutdown.min_on_battery_seconds_before_action {
+			debug!(
+				"Shutdown checks skipped: on battery for {}s, minimum is {}s",
+				on_battery_for, self.config.shutdown.min_on_battery_seconds_before_action
+			);
+			return None;
+		}
+
+		// Check time on battery
+		if let Some(since) = self.state.on_battery_since {
+			let elapsed = since.elapsed().as_secs();
+			if elapsed >= self.config.shutdown.on_battery_seconds {
+				error!(
+					"🔴 UPS on battery for {} seconds (threshold: {}), triggering shutdown",
+					elapsed, self.config.shutdown.on_battery_seconds
+				);
+				return Some(ShutdownReason::OnBatteryTimeout);
+			}
+
+			// Log remaining time periodically
+			let remaining = self.config.shutdown.on_battery_seconds - elapsed;
+			if remaining.is_multiple_of(60) || remaining <= 30 {
+				warn!("Time until shutdown: {} seconds", remaining);
+			}
+		}
+
+		// Stale charge and runtime can't be trusted either way, unlike the clock above
+		if self.state.data_stale_since.is_some() {
+			debug!("Charge and runtime checks skipped: UPS data is stale");
+			return None;
+		}
+
+		// Check battery charge threshold, skipped while the charge is unknown
+		if let Some(charge) = status.battery_charge
+			&& charge <= self.config.shutdown.battery_percent_threshold
+		{
+			error!(
+				"🔴 Battery charge {}% below threshold {}%, triggering shutdown",
+				charge, self.config.shutdown.battery_percent_threshold
+			);
+			return Some(ShutdownReason::LowBattery);
+		}
+
+		// Check runtime threshold, skipped while the runtime is unknown
+		let runtime_threshold = self.runtime_threshold();
+		if let Some(runtime) = status.battery_runtime
+			&& runtime <= runtime_threshold
+		{
+			match (
+				self.config.shutdown.runtime_percent_threshold,
+				self.state.runtime_baseline,
+			) {
+				(Some(percent), Some(baseline))
+					if runtime_threshold > self.config.shutdown.runtime_threshold =>
+				{
+					error!(
+						"🔴 Battery runtime {} seconds below {}% of the {} second baseline, triggering shutdown",
+						runtime, percent, baseline
+					);
+				}
+				_ => error!(
+					"🔴 Battery runtime {} seconds below threshold {}, triggering shutdown",
+					runtime, runtime_threshold
+				),
+			}
+			return Some(ShutdownReason::LowRuntime);
+		}
+
+		None
+	}
+
+	/// Picks the command configured for the matched trigger, falling back to
+	/// the generic `shutdown_command`.
+	fn shutdown_command_for(&self, reason: ShutdownReason) -> &str {
+		let shutdown = &self.config.shutdown;
+		let command = match reason {
+			ShutdownReason::OnBatteryTimeout => shutdown.on_battery_timeout_command.as_deref(),
+			ShutdownReason::LowBattery | ShutdownReason::LowRuntime => {
+				shutdown.low_battery_command.as_deref()
+			}
+			ShutdownReason::StaleData => None,
+		};
+
+		command.unwrap_or(&shutdown.shutdown_command)
+	}
+
+	async fn execute_shutdown(&mut self, reason: ShutdownReason) {
+		// Checked here too so no path can reach the commands below with
+		// shutdown disabled
+		if !self.config.shutdown.enabled {
+			error!("Refusing to shut down ({}): shutdown is disabled", reason);
+			return;
+		}
+
+		if self.state.shutdown_scheduled {
+			return;
+		}
+
+		self.state.shutdown_scheduled = true;
+		self.record_event(EventType::ShutdownTriggered, Some(reason.to_string()));
+		self.dispatch("on_threshold", |handler| handler.on_threshold(reason));
+
+		// A simulated outage must never power anything off
+		let dry_run_enabled = self.config.shutdown.dry_run || self.state.simulated_outage;
+		let dry_run = if dry_run_enabled { "[DRY RUN] " } else { "" };
+
+		error!(
+			"{}🚨 INITIATING SYSTEM SHUTDOWN IN {} SECONDS! 🚨",
+			dry_run, self.config.shutdown.shutdown_grace_period
+		);
+
+		// Dependent hosts act on the announcement, so a drill must not send it
+		if dry_run_enabled {
+			let hosts = self.config.shutdown.notify_hosts.len();
+			if hosts > 0 {
+				warn!("[DRY RUN] would notify {} host(s) of the shutdown", hosts);
+			}
+		} else {
+			self.notify_hosts(reason).await;
+		}
+
+		// Log the countdown and keep polling, aborting if cancelled through the
+		// admin endpoint or when line power returns
+		let poll_interval = Duration::from_secs(self.config.monitoring.poll_interval.max(1));
+		let mut next_poll = Instant::now() + poll_interval;
+		let mut countdown_task = None;
+		for i in (1..=self.config.shutdown.shutdown_grace_period).rev() {
+			if self.control.take_cancel() {
+				warn!("{}Shutdown cancelled during the grace period", dry_run);
+				self.state.shutdown_scheduled = false;
+				self.state.shutdown_cancelled = true;
+				self.record_event(EventType::ShutdownCancelled, Some(reason.to_string()));
+				return;
+			}
+			if Instant::now() >= next_poll {
+				next_poll = Instant::now() + poll_interval;
+				if self.poll_during_countdown().await == Some(false) {
+					warn!(
+						"{}🔌 Line power returned, shutdown cancelled during the grace period",
+						dry_run
+					);
+					self.state.shutdown_scheduled = false;
+					self.record_event(
+						EventType::ShutdownCancelled,
+						Some(format!("line power returned after {}", reason)),
+					);
+					return;
+				}
+			}
+			if self.is_countdown_log_due(i) {
+				warn!("{}Shutdown in {} seconds...", dry_run, i);
+				self.start_countdown_command(i, &mut countdown_task);
+			}
+			self.touch_watchdog();
+			tokio::time::sleep(Duration::from_secs(1)).await;
+		}
+
+		let command = self.shutdown_command_for(reason).to_string();
+		if command.split_whitespace().next().is_none() {
+			error!("Shutdown command is empty!");
+			return;
+		}
+
+		// Everything before the OS shutdown command is best effort: failures,
+		// panics and hangs are logged and the shutdown goes ahead regardless
+		if !dry_run_enabled {
+			self.dispatch("pre_shutdown", |handler| handler.pre_shutdown(reason));
+		}
+
+		if let Some(ref hook) = self.config.shutdown.pre_shutdown_command {
+			if dry_run_enabled {
+				warn!("[DRY RUN] would run pre-shutdown command: {}", hook);
+			} else {
+				info!("Running pre-shutdown command: {}", hook);
+				self.touch_watchdog();
+				run_guarded(
+					"Pre-shutdown command",
+					self.pre_shutdown_timeout(),
+					run_command(hook.clone()),
+				)
+				.await;
+			}
+		}
+
+		// Tell the UPS last so it cuts power only after its own delay, by which
+		// time the local shutdown below has halted the OS
+		if self.config.shutdown.command_ups_shutdown {
+			self.touch_watchdog();
+			run_guarded(
+				"UPS shutdown command",
+				self.pre_shutdown_timeout(),
+				command_ups_shutdown(
+					self.source.clone(),
+					self.config.shutdown.ups_shutdown_method,
+					dry_run_enabled,
+				),
+			)
+			.await;
+		}
+
+		if dry_run_enabled {
+			warn!("[DRY RUN] would execute ({}): {}", reason, command);
+			// A drill through the admin endpoint must leave the host monitored;
+			// the rest of the simulated outage is skipped like a cancelled shutdown
+			if self.state.simulated_outage {
+				info!("🧪 Simulated shutdown finished, monitoring continues");
+				self.state.shutdown_scheduled = false;
+				self.state.shutdown_cancelled = true;
+			}
+			return;
+		}
+
+		if self.run_shutdown_command(reason, &command).await {
+			self.watch_abort_window(reason).await;
+		} else {
+			// Still running on a draining battery, so let the next poll trigger again
+			error!("Every shutdown command failed, retrying on the next poll");
+			self.state.shutdown_scheduled = false;
+		}
+	}
+
+	// Runs the shutdown command, retrying it when configured and then trying
+	// `fallback_shutdown_command`; returns whether any of them succeeded
+	async fn run_shutdown_command(&mut self, reason: ShutdownReason, command: &str) -> bool {
+		let shutdown = &self.config.shutdown;
+		let attempts = if shutdown.retry_shutdown_command {
+			shutdown.shutdown_command_retries.saturating_add(1)
+		} else {
+			1
+		};
+		let fallback = shutdown.fallback_shutdown_command.clone();
+
+		for attempt in 1..=attempts {
+			if attempt > 1 {
+				self.touch_watchdog();
+				tokio::time::sleep(SHUTDOWN_RETRY_DELAY).await;
+				warn!(
+					"Retrying shutdown command (attempt {} of {})",
+					attempt, attempts
+				);
+			}
+
+			info!("Executing shutdown command ({}): {}", reason, command);
+			match run_command(command.to_string()).await {
+				Ok(()) => {
+					info!("Shutdown command executed successfully");
+					return true;
+				}
+				Err(e) => error!("Shutdown command failed: {}", e),
+			}
+		}
+
+		error!(
+			"Please ensure the command '{}' is valid and accessible",
+			command
+		);
+
+		let Some(fallback) = fallback else {
+			return false;
+		};
+
+		self.touch_watchdog();
+		warn!("Executing fallback shutdown command: {}", fallback);
+		match run_command(fallback).await {
+			Ok(()) => {
+				info!("Fallback shutdown command executed successfully");
+				true
+			}
+			Err(e) => {
+				error!("Fallback shutdown command failed: {}", e);
+				false
+			}
+		}
+	}
+
+	// Every `countdown_log_interval_secs`, or by default each of the last 10
+	// seconds and every 10 seconds before that
+	fn is_countdown_log_due(&self, remaining: u64) -> bool {
+		match self.config.shutdown.countdown_log_interval_secs {
+			Some(interval) => {
+				remaining == self.config.shutdown.shutdown_grace_period
+					|| remaining.is_multiple_of(interval)
+			}
+			None => remaining <= 10 || remaining.is_multiple_of(10),
+		}
+	}
+
+	// Run `countdown_command` in the background at a logged countdown step, so
+	// a slow or failing buzzer or display can't hold up the shutdown. Steps
+	// are skipped while the previous run is still going.
+	fn start_countdown_command(&self, remaining: u64, running: &mut Option<JoinHandle<()>>) {
+		let Some(ref command) = self.config.shutdown.countdown_command else {
+			return;
+		};
+
+		if running.as_ref().is_some_and(|task| !task.is_finished()) {
+			debug!(
+				"Skipping countdown command at {} seconds, the previous one is still running",
+				remaining
+			);
+			return;
+		}
+
+		let command = command.clone();
+		*running = Some(tokio::spawn(async move {
+			let remaining = remaining.to_string();
+			if let Err(e) = run_command_with(
+				&command,
+				std::slice::from_ref(&remaining),
+				&[("UPS_SHUTDOWN_REMAINING", &remaining)],
+			)
+			.await
+			{
+				warn!("Countdown command failed: {}", e);
+			}
+		}));
+	}
+
+	// Refresh the metrics during the grace period; returns whether the UPS is
+	// still on battery, or None when the poll failed
+	async fn poll_during_countdown(&mut self) -> Option<bool> {
+		let mut status = match self.source.get_status().await {
+			Ok(status) => status,
+			Err(e) => {
+				debug!("Poll during the grace period failed: {}", e);
+				return None;
+			}
+		};
+		self.apply_simulated_outage(&mut status);
+		self.state.last_update = chrono::Utc::now().timestamp();
+		self.state.last_good_status = Some(status.clone());
+		self.publish_metrics(&status, true);
+		Some(status.on_battery)
+	}
+
+	// Keep polling for `abort_window_secs` after the shutdown command and run
+	// `shutdown_cancel_command` if line power returns before the OS halts
+	async fn watch_abort_window(&mut self, reason: ShutdownReason) {
+		let window = self.config.shutdown.abort_window_secs;
+		if window == 0 {
+			return;
+		}
+
+		info!(
+			"Watching for line power for {}s before the system halts",
+			window
+		);
+		let interval = Duration::from_secs(self.config.monitoring.poll_interval.clamp(1, window));
+		let deadline = Instant::now() + Duration::from_secs(window);
+
+		while Instant::now() < deadline {
+			self.touch_watchdog();
+			tokio::time::sleep(interval).await;
+
+			let on_battery = match self.source.get_status().await {
+				Ok(status) => status.on_battery,
+				Err(e) => {
+					debug!("Poll during the abort window failed: {}", e);
+					continue;
+				}
+			};
+			if on_battery {
+				continue;
+			}
+
+			let cancel = self.config.shutdown.shutdown_cancel_command.clone();
+			warn!("🔌 Line power returned, aborting the shutdown: {}", cancel);
+			match run_command(cancel).await {
+				Ok(()) => {
+					info!("Shutdown aborted");
+					self.state.shutdown_scheduled = false;
+					self.record_event(
+						EventType::ShutdownCancelled,
+						Some(format!("line power returned after {}", reason)),
+					);
+				}
+				Err(e) => error!("Shutdown cancel command failed: {}", e),
+			}
+			return;
+		}
+	}
+}
+
+/// Runs a non-essential shutdown step on its own task, so an error, panic or
+/// hang inside it is logged instead of keeping the OS shutdown from running.
+async fn run_guarded<F>(step: &str, timeout: Duration, future: F)
+where
+	F: Future<Output = Result<(), String>> + Send + 'static,
+{
+	match tokio::time::timeout(timeout, tokio::spawn(future)).await {
+		Ok(Ok(Ok(()))) => {}
+		Ok(Ok(Err(e))) => error!("{} failed: {}", step, e),
+		Ok(Err(e)) if e.is_panic() => error!("{} panicked, continuing with the shutdown", step),
+		Ok(Err(e)) => error!("{} was aborted: {}", step, e),
+		Err(_) => error!(
+			"{} did not finish within {}s, continuing with the shutdown",
+			step,
+			timeout.as_secs()
+		),
+	}
+}
+
+// Turn the saved outage start into an `Instant`, ignoring unreadable, future
+// and stale entries
+fn restore_on_battery_since(state_file: &StateFile) -> Option<Instant> {
+	let saved = match state_file.load() {
+		Ok(saved) => saved?,
+		Err(e) => {
+			warn!(
+				"Ignoring unreadable state file {}: {}",
+				state_file.path().display(),
+				e
+			);
+			return None;
+		}
+	};
+
+	let age = chrono::Utc::now().timestamp() - saved;
+	let Some(age) = u64::try_from(age)
+		.ok()
+		.map(Duration::from_secs)
+		.filter(|age| *age <= MAX_RESTORED_OUTAGE)
+	else {
+		warn!(
+			"Ignoring stale outage start in state file {}",
+			state_file.path().display()
+		);
+		return None;
+	};
+
+	Instant::now().checked_sub(age)
+}
+
+// Run a `[hooks]` command in the background, so a slow one doesn't delay the
+// next poll; failures are only logged
+fn run_hook(name: &'static str, command: Option<&String>, env: Vec<(&'static str, String)>) {
+	let Some(command) = command.cloned() else {
+		return;
+	};
+
+	info!("Running {} command: {}", name.to_lowercase(), command);
+	tokio::spawn(async move {
+		let env: Vec<(&str, &str)> = env
+			.iter()
+			.map(|(name, value)| (*name, value.as_str()))
+			.collect();
+		if let Err(e) = run_command_until(&command, &[], &env, Some(HOOK_TIMEOUT)).await {
+			error!("{} command failed: {}", name, e);
+		}
+	});
+}
+
+// Run a whitespace-separated command line, failing on a non-zero exit status
+async fn run_command(command: String) -> Result<(), String> {
+	run_command_with(&command, &[], &[]).await
+}
+
+// Like `run_command`, appending `extra_args` and setting `env` for the command
+async fn run_command_with(
+	command: &str,
+	extra_args: &[String],
+	env: &[(&str, &str)],
+) -> Result<(), String> {
+	run_command_until(command, extra_args, env, None).await
+}
+
+// Like `run_command_with`, killing the command if it outlives `timeout`
+async fn run_command_until(
+	command: &str,
+	extra_args: &[String],
+	env: &[(&str, &str)],
+	timeout: Option<Duration>,
+) -> Result<(), String> {
+	let parts: Vec<&str> = command.split_whitespace().collect();
+	let Some((program, args)) = parts.split_first() else {
+		return Err("command is empty".to_string());
+	};
+
+	let mut process = Command::new(program);
+	process
+		.args(args)
+		.args(extra_args)
+		.envs(env.iter().copied());
+
+	let output = match timeout {
+		None => process.output().await,
+		// Dropping the output future at the timeout kills the process
+		Some(timeout) => match tokio::time::timeout(timeout, process.kill_on_drop(true).output()).await
+		{
+			Ok(output) => output,
+			Err(_) => {
+				return Err(format!(
+					"did not finish within {}s and was killed",
+					timeout.as_secs()
+				));
+			}
+		},
+	}
+	.map_err(|e| format!("failed to execute '{}': {}", program, e))?;
+
+	if output.status.success() {
+		Ok(())
+	} else {
+		Err(format!(
+			"{}: {:?}",
+			output.status,
+			String::from_utf8_lossy(&output.stderr)
+		))
+	}
+}
+
+// Report the UPS's own delays so the log shows how long the OS has to halt,
+// then send the configured power-cycle command
+async fn command_ups_shutdown(
+	source: Arc<dyn UpsSource>,
+	method: UpsShutdownMethod,
+	dry_run: bool,
+) -> Result<(), String> {
+	match source.list_vars().await {
+		Ok(vars) => {
+			for (name, value) in vars
+				.iter()
+				.filter(|(name, _)| name == "ups.delay.shutdown" || name == "ups.timer.shutdown")
+			{
+				info!("UPS reports {} = {}", name, value);
+			}
+		}
+		Err(e) => debug!("Could not read UPS shutdown delays: {}", e),
+	}
+
+	let command = match method {
+		UpsShutdownMethod::Instcmd => "INSTCMD shutdown.return",
+		UpsShutdownMethod::Fsd => "FSD",
+	};
+
+	if dry_run {
+		warn!("[DRY RUN] would send {} to the UPS", command);
+		return Ok(());
+	}
+
+	info!("Sending {} to the UPS", command);
+	let result = match method {
+		UpsShutdownMethod::Instcmd => source.instant_command("shutdown.return").await,
+		UpsShutdownMethod::Fsd => source.forced_shutdown().await,
+	};
+
+	result.map_err(|e| e.to_string())?;
+	info!("UPS accepted the shutdown command");
+	Ok(())
+}
+
+// Lowest warning level below the charge that is tighter than the one already warned about
+fn crossed_warn_level(levels: &[f64], charge: f64, warned: Option<f64>) -> Option<f64> {
+	levels
+		.iter()
+		.copied()
+		.filter(|level| charge < *level)
+		.filter(|level| warned.is_none_or(|warned| *level < warned))
+		.min_by(f64::total_cmp)
+}
+
+// Resolve on SIGINT (Ctrl+C) or, on Unix, SIGTERM as sent by systemd
+async fn wait_for_signal() {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{SignalKind, signal};
+
+		let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => {}
+			_ = terminate.recv() => {}
+		}
+	}
+
+	#[cfg(not(unix))]
+	{
+		let _ = tokio::signal::ctrl_c().await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::{HooksConfig, SimulationConfig};
+	use std::path::PathBuf;
+
+	// Simulation mode, so the monitor polls a simulated UPS instead of a NUT server
+	fn simulated_config() -> Config {
+		Config {
+			simulation: Some(SimulationConfig {
+				enabled: true,
+				..Default::default()
+			}),
+			..Default::default()
+		}
+	}
+
+	// A script in its own temp dir appending `echo "<line>"` to a log, removed
+	// on drop so a failed assertion doesn't leave it behind
+	struct HookScript {
+		dir: PathBuf,
+		script: PathBuf,
+		log: PathBuf,
+	}
+
+	impl HookScript {
+		fn new(name: &str, line: &str) -> Self {
+			let dir = std::env::temp_dir().join(format!("rabbitnut-{}-{}", name, std::process::id()));
+			std::fs::create_dir_all(&dir).unwrap();
+			let script = dir.join(format!("{}.sh", name));
+			let log = dir.join("log");
+			std::fs::write(&script, format!("echo \"{}\" >> {}\n", line, log.display())).unwrap();
+			HookScript { dir, script, log }
+		}
+
+		fn command(&self) -> String {
+			format!("sh {}", self.script.display())
+		}
+
+		// The log once it has `lines` lines, or as it is after 5 seconds
+		async fn wait_for_lines(&self, lines: usize) -> String {
+			let mut log = String::new();
+			for _ in 0..50 {
+				log = std::fs::read_to_string(&self.log).unwrap_or_default();
+				if log.lines().count() >= lines {
+					break;
+				}
+				tokio::time::sleep(Duration::from_millis(100)).await;
+			}
+			log
+		}
+	}
+
+	impl Drop for HookScript {
+		fn drop(&mut self) {
+			let _ = std::fs::remove_dir_all(&self.dir);
+		}
+	}
+
+	// Full charge and runtime with no optional readings; `on_battery` follows an OB flag
+	fn status(ups_status: &str) -> UpsStatus {
+		UpsStatus {
+			battery_charge: Some(100.0),
+			battery_runtime: Some(1800),
+			ups_status: ups_status.to_string(),
+			on_battery: ups_status.split_whitespace().any(|flag| flag == "OB"),
+			charging: false,
+			output_power: None,
+			battery_temperature: None,
+			ups_temperature: None,
+			input_frequency: None,
+			battery_voltage: None,
+			battery_voltage_nominal: None,
+			battery_charge_restart: None,
+			battery_date: None,
+			extra_vars: HashMap::new(),
+		}
+	}
+
+	#[test]
+	fn warn_levels_fire_once_per_level() {
+		let levels = [50.0, 30.0, 20.0];
+
+		assert_eq!(crossed_warn_level(&levels, 80.0, None), None);
+		assert_eq!(crossed_warn_level(&levels, 49.0, None), Some(50.0));
+		assert_eq!(crossed_warn_level(&levels, 45.0, Some(50.0)), None);
+		// Skipping past several levels in one poll only warns for the lowest
+		assert_eq!(crossed_warn_level(&levels, 15.0, Some(50.0)), Some(20.0));
+		assert_eq!(crossed_warn_level(&levels, 10.0, Some(20.0)), None);
+	}
+
+	#[tokio::test]
+	async fn overload_and_bypass_are_tracked_separately_from_battery() {
+		let mut monitor = UpsMonitor::new(Config::default());
+		let mut status = status("OL OVER");
+
+		monitor.check_output_state(&status);
+		assert!(monitor.state.overload_active);
+		assert!(!monitor.state.bypass_active);
+
+		status.ups_status = "OL BYPASS".to_string();
+		monitor.check_output_state(&status);
+		assert!(!monitor.state.overload_active);
+		assert!(monitor.state.bypass_active);
+		assert!(monitor.state.on_battery_since.is_none());
+	}
+
+	#[tokio::test]
+	async fn simulated_outage_replaces_readings() {
+		let mut monitor = UpsMonitor::new(Config::default());
+		let mut status = status("OL");
+
+		monitor.apply_simulated_outage(&mut status);
+		assert!(!status.on_battery);
+
+		monitor
+			.control
+			.simulate_outage(20.0, 120, Duration::from_secs(60));
+		monitor.apply_simulated_outage(&mut status);
+		assert!(status.on_battery);
+		assert_eq!(status.battery_charge, Some(20.0));
+		assert_eq!(status.battery_runtime, Some(120));
+		assert!(monitor.state.simulated_outage);
+	}
+
+	#[tokio::test]
+	async fn garbage_status_does_not_trigger_shutdown() {
+		let mut config = Config::default();
+		config.shutdown.enabled = true;
+		config.shutdown.min_on_battery_seconds_before_action = 0;
+
+		let mut monitor = UpsMonitor::new(config);
+		let status = UpsStatus {
+			battery_charge: Some(1.0),
+			battery_runtime: Some(10),
+			..status("NOBODY")
+		};
+
+		monitor.check_status_recognized(&status);
+		assert_eq!(monitor.state.unrecognized_status.as_deref(), Some("NOBODY"));
+		assert!(monitor.should_shutdown(&status).is_none());
+	}
+
+	#[tokio::test]
+	async fn frozen_readings_suspend_shutdown_decisions() {
+		let mut config = Config::default();
+		config.shutdown.enabled = true;
+		config.monitoring.stale_after_unchanged_polls = Some(3);
+
+		let mut monitor = UpsMonitor::new(config);
+		let mut status = UpsStatus {
+			battery_charge: Some(5.0),
+			battery_runtime: Some(60),
+			..status("OB DISCHRG")
+		};
+
+		for poll in 1..=3 {
+			monitor.track_frozen_readings(&status);
+			assert_eq!(monitor.state.data_stale_since.is_some(), poll == 3);
+			monitor.state.last_good_status = Some(status.clone());
+		}
+		assert!(monitor.should_shutdown(&status).is_none());
+		assert!(monitor.build_metrics(&status, true).data_stale);
+
+		status.battery_charge = Some(4.0);
+		monitor.track_frozen_readings(&status);
+		assert!(monitor.state.data_stale_since.is_none());
+		assert_eq!(
+			monitor.should_shutdown(&status),
+			Some(ShutdownReason::LowBattery)
+		);
+	}
+
+	#[tokio::test]
+	async fn frozen_readings_keep_the_on_battery_timeout() {
+		let mut config = Config::default();
+		config.shutdown.enabled = true;
+		config.shutdown.on_battery_seconds = 300;
+		config.shutdown.min_on_battery_seconds_before_action = 0;
+		config.monitoring.stale_after_unchanged_polls = Some(2);
+
+		let mut monitor = UpsMonitor::new(config);
+		let status = status("OB DISCHRG");
+		monitor.update_battery_state(&status);
+		for _ in 0..2 {
+			monitor.track_frozen_readings(&status);
+			monitor.state.last_good_status = Some(status.clone());
+		}
+		assert!(monitor.state.data_stale_since.is_some());
+		assert!(monitor.should_shutdown(&status).is_none());
+
+		monitor.state.on_battery_since = Instant::now().checked_sub(Duration::from_secs(301));
+		assert_eq!(
+			monitor.should_shutdown(&status),
+			Some(ShutdownReason::OnBatteryTimeout)
+		);
+	}
+
+	#[tokio::test]
+	async fn steady_readings_on_line_power_are_not_stale() {
+		let server = crate::mock_nut::MockNutServer::new("ups")
+			.with_var("battery.charge", "100")
+			.with_var("battery.runtime", "1800")
+			.with_var("ups.status", "OL")
+			.start();
+
+		let mut config = Config::default();
+		config.ups.host = "127.0.0.1".to_string();
+		config.ups.port = server.port();
+		config.monitoring.poll_interval = 1;
+		config.monitoring.max_runtime_secs = Some(4);
+		config.monitoring.stale_after_unchanged_polls = Some(2);
+		config.shutdown.enabled = true;
+		config.shutdown.dry_run = true;
+		config.shutdown.shutdown_grace_period = 0;
+		config.shutdown.stale_data_shutdown_secs = Some(1);
+
+		let mut monitor = UpsMonitor::new(config);
+		tokio::time::timeout(Duration::from_secs(10), monitor.run())
+			.await
+			.expect("monitor kept running past max_runtime_secs");
+
+		assert!(monitor.state.data_stale_since.is_none());
+		assert!(!monitor.state.shutdown_scheduled);
+	}
+
+	#[tokio::test]
+	async fn unknown_charge_and_runtime_do_not_trigger_shutdown() {
+		let mut config = simulated_config();
+		config.shutdown.enabled = true;
+		config.shutdown.min_on_battery_seconds_before_action = 0;
+
+		let mut monitor = UpsMonitor::new(config);
+		let status = UpsStatus {
+			battery_charge: None,
+			battery_runtime: None,
+			..status("OB DISCHRG")
+		};
+
+		assert!(monitor.should_shutdown(&status).is_none());
+	}
+
+	#[tokio::test]
+	async fn runtime_percent_threshold_uses_line_power_baseline() {
+		let mut config = simulated_config();
+		config.shutdown.runtime_threshold = 60;
+		config.shutdown.runtime_percent_threshold = Some(15.0);
+
+		let mut monitor = UpsMonitor::new(config);
+		assert_eq!(monitor.runtime_threshold(), 60);
+
+		let mut status = monitor.source.get_status().await.unwrap();
+		status.battery_charge = Some(100.0);
+		status.battery_runtime = Some(2000);
+		monitor.update_runtime_baseline(&status);
+		assert_eq!(monitor.runtime_threshold(), 300);
+
+		// A reading while recharging doesn't replace the full-charge baseline
+		status.battery_charge = Some(50.0);
+		status.battery_runtime = Some(1000);
+		monitor.update_runtime_baseline(&status);
+		assert_eq!(monitor.runtime_threshold(), 300);
+	}
+
+	#[tokio::test]
+	async fn outage_start_is_restored_from_state_file() {
+		let path = std::env::temp_dir().join(format!("rabbitnut-state-{}.json", std::process::id()));
+		let state_file = StateFile::new(&path.display().to_string());
+		state_file
+			.save(chrono::Utc::now().timestamp() - 120)
+			.unwrap();
+
+		let mut config = simulated_config();
+		config.monitoring.state_file = Some(path.display().to_string());
+
+		let mut monitor = UpsMonitor::new(config);
+		let mut status = monitor.source.get_status().await.unwrap();
+		status.on_battery = true;
+		monitor.update_battery_state(&status);
+
+		let since = monitor.state.on_battery_since.unwrap();
+		assert!(since.elapsed() >= Duration::from_secs(120));
+
+		status.on_battery = false;
+		monitor.update_battery_state(&status);
+		assert!(!path.exists());
+	}
+
+	#[tokio::test]
+	async fn hooks_see_power_transitions() {
+		use std::sync::atomic::{AtomicU32, Ordering};
+
+		let transfers = Arc::new(AtomicU32::new(0));
+		let returns = Arc::new(AtomicU32::new(0));
+		let config = simulated_config();
+
+		let mut monitor = UpsMonitor::new(config)
+			.on_battery({
+				let transfers = transfers.clone();
+				move |_| {
+					transfers.fetch_add(1, Ordering::Relaxed);
+				}
+			})
+			.on_line({
+				let returns = returns.clone();
+				move |_| panic!("hook failure {}", returns.fetch_add(1, Ordering::Relaxed))
+			});
+
+		let mut status = monitor.source.get_status().await.unwrap();
+		status.on_battery = true;
+		monitor.update_battery_state(&status);
+		monitor.update_battery_state(&status);
+		assert_eq!(transfers.load(Ordering::Relaxed), 1);
+
+		// A panicking hook must not break the monitor
+		status.on_battery = false;
+		monitor.update_battery_state(&status);
+		assert_eq!(returns.load(Ordering::Relaxed), 1);
+		assert!(monitor.state.on_battery_since.is_none());
+	}
+
+	#[tokio::test]
+	async fn handlers_see_thresholds_and_notified_events() {
+		#[derive(Default)]
+		struct Recorder(std::sync::Mutex<Vec<String>>);
+
+		impl EventHandler for Recorder {
+			fn on_threshold(&self, reason: ShutdownReason) {
+				self
+					.0
+					.lock()
+					.unwrap()
+					.push(format!("threshold: {}", reason));
+			}
+
+			fn pre_shutdown(&self, reason: ShutdownReason) {
+				self
+					.0
+					.lock()
+					.unwrap()
+					.push(format!("pre_shutdown: {}", reason));
+			}
+
+			fn on_event(&self, event: &PowerEvent) {
+				self
+					.0
+					.lock()
+					.unwrap()
+					.push(event.event.as_str().to_string());
+			}
+		}
+
+		let recorder = Arc::new(Recorder::default());
+		let mut config = Config::default();
+		config.shutdown.enabled = true;
+		config.shutdown.dry_run = true;
+		config.shutdown.shutdown_grace_period = 0;
+
+		let mut monitor = UpsMonitor::new(config).with_handler(recorder.clone());
+		monitor.execute_shutdown(ShutdownReason::LowBattery).await;
+
+		// Dry runs skip pre_shutdown
+		assert_eq!(
+			*recorder.0.lock().unwrap(),
+			["shutdown_triggered", "threshold: low battery charge"]
+		);
+	}
+
+	#[test]
+	fn running_stats_skip_unknown_readings() {
+		let mut stats = RunningStats::default();
+		assert!(stats.stats().is_none());
+
+		for value in [Some(80.0), None, Some(20.0), Some(50.0)] {
+			stats.record(value);
+		}
+
+		let stats = stats.stats().unwrap();
+		assert_eq!((stats.min, stats.max, stats.avg), (20.0, 80.0, 50.0));
+	}
+
+	#[test]
+	fn invalid_state_file_is_ignored() {
+		let path =
+			std::env::temp_dir().join(format!("rabbitnut-bad-state-{}.json", std::process::id()));
+		std::fs::write(&path, "not json").unwrap();
+
+		assert!(restore_on_battery_since(&StateFile::new(&path.display().to_string())).is_none());
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[tokio::test]
+	async fn panicking_step_does_not_propagate() {
+		run_guarded("Test step", Duration::from_secs(5), async {
+			panic!("hook exploded");
+		})
+		.await;
+	}
+
+	#[tokio::test]
+	async fn shutdown_command_runs_after_failing_pre_shutdown_hook() {
+		let marker = std::env::temp_dir().join(format!("rabbitnut-shutdown-{}", std::process::id()));
+		let _ = std::fs::remove_file(&marker);
+
+		let mut config = simulated_config();
+		config.shutdown.enabled = true;
+		config.shutdown.shutdown_grace_period = 0;
+		config.shutdown.pre_shutdown_command = Some("/nonexistent/pre-shutdown-hook".to_string());
+		config.shutdown.pre_shutdown_timeout_secs = 5;
+		config.shutdown.command_ups_shutdown = true;
+		config.shutdown.shutdown_command = format!("touch {}", marker.display());
+
+		let mut monitor = UpsMonitor::new(config);
+		monitor.execute_shutdown(ShutdownReason::LowBattery).await;
+
+		assert!(marker.exists(), "shutdown command did not run");
+		let _ = std::fs::remove_file(&marker);
+	}
+
+	#[tokio::test]
+	async fn countdown_command_runs_at_each_logged_step() {
+		let hook = HookScript::new("countdown", "$1 $UPS_SHUTDOWN_REMAINING");
+
+		let mut config = simulated_config();
+		config.shutdown.enabled = true;
+		config.shutdown.dry_run = true;
+		config.shutdown.shutdown_grace_period = 2;
+		config.shutdown.countdown_log_interval_secs = Some(1);
+		config.shutdown.countdown_command = Some(hook.command());
+
+		let mut monitor = UpsMonitor::new(config);
+		monitor.execute_shutdown(ShutdownReason::LowBattery).await;
+
+		// The last step runs in the background
+		assert_eq!(hook.wait_for_lines(2).await, "2 2\n1 1\n");
+	}
+
+	#[tokio::test]
+	async fn restore_hook_gets_the_outage_duration() {
+		let hook = HookScript::new("restore", "$UPS_OUTAGE_DURATION");
+
+		let config = Config {
+			hooks: Some(HooksConfig {
+				on_battery_command: None,
+				on_restore_command: Some(hook.command()),
+			}),
+			..Default::default()
+		};
+
+		let mut monitor = UpsMonitor::new(config);
+		let mut status = UpsStatus {
+			battery_charge: Some(90.0),
+			battery_runtime: Some(1200),
+			..status("OB DISCHRG")
+		};
+		monitor.update_battery_state(&status);
+		monitor.state.on_battery_since = Instant::now().checked_sub(Duration::from_secs(42));
+
+		status.ups_status = "OL CHRG".to_string();
+		status.on_battery = false;
+		monitor.update_battery_state(&status);
+
+		assert_eq!(hook.wait_for_lines(1).await, "42\n");
+	}
+
+	#[tokio::test]
+	async fn battery_hook_gets_the_readings() {
+		let hook = HookScript::new("battery", "$UPS_BATTERY_CHARGE $UPS_BATTERY_RUNTIME");
+
+		let config = Config {
+			hooks: Some(HooksConfig {
+				on_battery_command: Some(hook.command()),
+				on_restore_command: None,
+			}),
+			..Default::default()
+		};
+
+		let mut monitor = UpsMonitor::new(config);
+		let status = UpsStatus {
+			battery_charge: Some(90.0),
+			battery_runtime: Some(1200),
+			..status("OB DISCHRG")
+		};
+		monitor.update_battery_state(&status);
+		// Only the transfer runs it, not every poll on battery
+		monitor.update_battery_state(&status);
+
+		hook.wait_for_lines(1).await;
+		// Give a second run, if any, time to append its line
+		tokio::time::sleep(Duration::from_millis(200)).await;
+		assert_eq!(hook.wait_for_lines(1).await, "90 1200\n");
+	}
+
+	#[tokio::test]
+	async fn commands_past_their_timeout_are_killed() {
+		let started = Instant::now();
+		let result = run_command_until("sleep 10", &[], &[], Some(Duration::from_millis(200))).await;
+		assert!(result.unwrap_err().contains("killed"));
+		assert!(started.elapsed() < Duration::from_secs(5));
+	}
+
+	#[tokio::test]
+	async fn line_power_during_grace_period_cancels_shutdown() {
+		let marker = std::env::temp_dir().join(format!("rabbitnut-restored-{}", std::process::id()));
+		let _ = std::fs::remove_file(&marker);
+		let server = crate::mock_nut::MockNutServer::new("ups")
+			.with_var("battery.charge", "40")
+			.with_var("battery.runtime", "600")
+			.with_var("ups.status", "OL CHRG")
+			.start();
+
+		let mut config = Config::default();
+		config.ups.host = "127.0.0.1".to_string();
+		config.ups.port = server.port();
+		config.monitoring.poll_interval = 1;
+		config.shutdown.enabled = true;
+		config.shutdown.shutdown_grace_period = 3;
+		config.shutdown.shutdown_command = format!("touch {}", marker.display());
+
+		let mut monitor = UpsMonitor::new(config);
+		monitor.execute_shutdown(ShutdownReason::LowBattery).await;
+
+		assert!(!marker.exists(), "shutdown ran after line power returned");
+		assert!(!monitor.state.shutdown_scheduled);
+	}
+
+	#[tokio::test]
+	async fn run_stops_after_max_runtime() {
+		let mut config = simulated_config();
+		config.monitoring.poll_interval = 1;
+		config.monitoring.max_runtime_secs = Some(2);
+
+		let mut monitor = UpsMonitor::new(config);
+		tokio::time::timeout(Duration::from_secs(10), monitor.run())
+			.await
+			.expect("monitor kept running past max_runtime_secs");
+		assert!(monitor.state.last_update > 0);
+	}
+
+	#[tokio::test]
+	async fn simulated_outage_shutdown_keeps_monitoring() {
+		let server = crate::mock_nut::MockNutServer::new("ups")
+			.with_var("battery.charge", "100")
+			.with_var("battery.runtime", "1800")
+			.with_var("ups.status", "OL")
+			.start();
+
+		let mut config = Config::default();
+		config.ups.host = "127.0.0.1".to_string();
+		config.ups.port = server.port();
+		config.monitoring.poll_interval = 1;
+		config.monitoring.max_runtime_secs = Some(3);
+		config.shutdown.enabled = true;
+		config.shutdown.shutdown_grace_period = 0;
+		config.shutdown.min_on_battery_seconds_before_action = 0;
+		config.shutdown.battery_percent_threshold = 20.0;
+
+		let mut monitor = UpsMonitor::new(config);
+		monitor
+			.control
+			.simulate_outage(5.0, 60, Duration::from_secs(60));
+
+		let started = Instant::now();
+		tokio::time::timeout(Duration::from_secs(10), monitor.run())
+			.await
+			.expect("monitor kept running past max_runtime_secs");
+
+		// Only the runtime limit ends the loop, the drill doesn't
+		assert!(started.elapsed() >= Duration::from_secs(2));
+		assert!(monitor.state.shutdown_cancelled);
+		assert!(!monitor.state.shutdown_scheduled);
+	}
+
+	#[tokio::test]
+	async fn dry_run_does_not_notify_hosts() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+		let mut config = Config::default();
+		config.shutdown.enabled = true;
+		config.shutdown.dry_run = true;
+		config.shutdown.shutdown_grace_period = 0;
+		config.shutdown.notify_hosts = vec![listener.local_addr().unwrap().to_string()];
+
+		let mut monitor = UpsMonitor::new(config);
+		monitor.execute_shutdown(ShutdownReason::LowBattery).await;
+
+		let accepted = tokio::time::timeout(Duration::from_millis(500), listener.accept()).await;
+		assert!(accepted.is_err(), "a dry run announced the shutdown");
+	}
+
+	#[tokio::test]
+	async fn unknown_ups_name_is_not_retried_at_startup() {
+		let server = crate::mock_nut::MockNutServer::new("apc").start();
+
+		let mut config = Config::default();
+		config.ups.host = "127.0.0.1".to_string();
+		config.ups.port = server.port();
+		config.ups.name = "ups".to_string();
+		config.monitoring.startup_timeout_secs = 30;
+
+		let mut monitor = UpsMonitor::new(config);
+		tokio::time::timeout(Duration::from_secs(5), monitor.print_ups_info())
+			.await
+			.expect("startup kept retrying an unknown UPS name");
+	}
+
+	#[tokio::test]
+	async fn check_summary_reflects_thresholds() {
+		let mut config = Config::default();
+		config.shutdown.battery_percent_threshold = 30.0;
+		config.shutdown.runtime_threshold = 300;
+		let monitor = UpsMonitor::new(config);
+		let mut status = status("OL");
+		assert_eq!(monitor.check_summary(&status).0, 0);
+
+		status.ups_status = "OL RB".to_string();
+		assert_eq!(monitor.check_summary(&status).0, 1);
+
+		status.ups_status = "OB DISCHRG".to_string();
+		status.on_battery = true;
+		status.battery_charge = Some(60.0);
+		assert_eq!(monitor.check_summary(&status).0, 1);
+
+		status.battery_runtime = Some(200);
+		assert_eq!(monitor.check_summary(&status).0, 2);
+	}
+
+	#[tokio::test]
+	async fn disabled_shutdown_never_runs_the_command() {
+		let marker = std::env::temp_dir().join(format!("rabbitnut-disabled-{}", std::process::id()));
+		let _ = std::fs::remove_file(&marker);
+
+		let mut config = simulated_config();
+		config.shutdown.enabled = false;
+		config.shutdown.shutdown_grace_period = 0;
+		config.shutdown.shutdown_command = format!("touch {}", marker.display());
+
+		let mut monitor = UpsMonitor::new(config);
+		monitor.execute_shutdown(ShutdownReason::LowBattery).await;
+
+		assert!(!marker.exists(), "shutdown command ran while disabled");
+		assert!(!monitor.state.shutdown_scheduled);
+	}
+
+	#[tokio::test]
+	async fn failed_shutdown_command_falls_back_or_stays_pending() {
+		let mut config = Config::default();
+		config.shutdown.enabled = true;
+		config.shutdown.shutdown_grace_period = 0;
+		config.shutdown.shutdown_command = "false".to_string();
+
+		let mut monitor = UpsMonitor::new(config.clone());
+		monitor.execute_shutdown(ShutdownReason::LowBattery).await;
+		assert!(!monitor.state.shutdown_scheduled);
+
+		config.shutdown.fallback_shutdown_command = Some("true".to_string());
+		let mut monitor = UpsMonitor::new(config);
+		monitor.execute_shutdown(ShutdownReason::LowBattery).await;
+		assert!(monitor.state.shutdown_scheduled);
 	}
 }
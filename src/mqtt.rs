@@ -0,0 +1,113 @@
+use log::{debug, info, warn};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS, Transport};
+use std::time::Duration;
+
+use crate::config::MqttConfig;
+use crate::ups::UpsStatus;
+
+pub struct MqttPublisher {
+	client: AsyncClient,
+	topic_prefix: String,
+}
+
+impl MqttPublisher {
+	/// Connects to the broker and spawns the MQTT event loop on the current runtime.
+	///
+	/// The availability topic is set to `online` on every (re)connect and to
+	/// `offline` by the broker through the last will when the connection drops.
+	pub fn new(config: &MqttConfig, ups_name: &str) -> Self {
+		let topic_prefix = format!("{}/{}", config.base_topic.trim_end_matches('/'), ups_name);
+		let availability_topic = format!("{}/availability", topic_prefix);
+
+		let client_id = config
+			.client_id
+			.clone()
+			.unwrap_or_else(|| format!("rabbitnut-{}", ups_name));
+
+		let mut options = MqttOptions::new(client_id, config.host.clone(), config.port);
+		options.set_keep_alive(Duration::from_secs(30));
+		options.set_last_will(LastWill::new(
+			availability_topic.clone(),
+			"offline",
+			QoS::AtLeastOnce,
+			true,
+		));
+
+		if let (Some(username), Some(password)) = (&config.username, &config.password) {
+			options.set_credentials(username.clone(), password.clone());
+		}
+
+		if config.tls {
+			options.set_transport(Transport::tls_with_default_config());
+		}
+
+		let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+		let availability_client = client.clone();
+		let host = config.host.clone();
+		let port = config.port;
+		tokio::spawn(async move {
+			loop {
+				match eventloop.poll().await {
+					Ok(Event::Incoming(Packet::ConnAck(_))) => {
+						info!("Connected to MQTT broker {}:{}", host, port);
+						if let Err(e) = availability_client
+							.publish(&availability_topic, QoS::AtLeastOnce, true, "online")
+							.await
+						{
+							warn!("Failed to publish MQTT availability: {}", e);
+						}
+					}
+					Ok(_) => {}
+					Err(e) => {
+						warn!("MQTT connection error: {}", e);
+						tokio::time::sleep(Duration::from_secs(5)).await;
+					}
+				}
+			}
+		});
+
+		MqttPublisher {
+			client,
+			topic_prefix,
+		}
+	}
+
+	/// Publishes the status as a JSON `state` topic plus one retained topic per field.
+	pub fn publish_status(&self, status: &UpsStatus, on_battery_duration: Option<u64>) {
+		let state = serde_json::json!({
+			"battery_charge": status.battery_charge,
+			"battery_runtime": status.battery_runtime,
+			"status": status.ups_status,
+			"on_battery": status.on_battery,
+			"charging": status.charging,
+			"on_battery_duration": on_battery_duration,
+			"output_power": status.output_power,
+		});
+
+		self.publish("state", state.to_string());
+		if let Some(charge) = status.battery_charge {
+			self.publish("battery_charge", charge.to_string());
+		}
+		if let Some(runtime) = status.battery_runtime {
+			self.publish("battery_runtime", runtime.to_string());
+		}
+		self.publish("status", status.ups_status.clone());
+		self.publish("on_battery", status.on_battery.to_string());
+		self.publish("charging", status.charging.to_string());
+
+		if let Some(power) = status.output_power {
+			self.publish("output_power", power.to_string());
+		}
+	}
+
+	fn publish(&self, topic: &str, payload: String) {
+		let topic = format!("{}/{}", self.topic_prefix, topic);
+		if let Err(e) = self
+			.client
+			.try_publish(&topic, QoS::AtLeastOnce, true, payload)
+		{
+			debug!("Failed to queue MQTT message for {}: {}", topic, e);
+		}
+	}
+}
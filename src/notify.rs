@@ -0,0 +1,380 @@
+use log::{debug, error, info};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+use crate::config::{NotificationConfig, NotificationType};
+use crate::events::{EventHandler, EventType, PowerEvent};
+
+// Upper bound for each outgoing notification request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TELEGRAM_API: &str = "https://api.telegram.org";
+
+// Identical messages to the same channel within this window are dropped, which
+// keeps chat webhooks under their rate limits when the power flaps
+const DEBOUNCE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Body POSTed to every `notify_hosts` entry before the local shutdown.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShutdownAnnouncement {
+	pub event: &'static str,
+	pub ups_name: String,
+	pub ups_host: String,
+	pub reason: String,
+	pub grace_period_seconds: u64,
+	pub battery_charge: Option<f64>,
+	pub battery_runtime: Option<u64>,
+}
+
+/// POSTs the announcement to all hosts concurrently and waits for the results,
+/// logging failures without propagating them.
+pub async fn announce_shutdown(hosts: &[String], announcement: &ShutdownAnnouncement) {
+	let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+		Ok(client) => client,
+		Err(e) => {
+			error!(
+				"Failed to create HTTP client for shutdown notifications: {}",
+				e
+			);
+			return;
+		}
+	};
+
+	let mut requests = JoinSet::new();
+
+	for host in hosts {
+		// Bare host:port entries default to plain HTTP
+		let url = if host.contains("://") {
+			host.clone()
+		} else {
+			format!("http://{}", host)
+		};
+
+		let request = client.post(&url).json(announcement);
+		requests.spawn(async move { (url, request.send().await) });
+	}
+
+	while let Some(result) = requests.join_next().await {
+		match result {
+			Ok((url, Ok(response))) if response.status().is_success() => {
+				info!("Notified {} of the imminent shutdown", url);
+			}
+			Ok((url, Ok(response))) => {
+				error!(
+					"Shutdown notification to {} failed: HTTP {}",
+					url,
+					response.status()
+				);
+			}
+			Ok((url, Err(e))) => error!("Shutdown notification to {} failed: {}", url, e),
+			Err(e) => error!("Shutdown notification task failed: {}", e),
+		}
+	}
+}
+
+/// Sends power events to the configured `[[notifications]]` channels in the background.
+pub struct Notifier {
+	client: reqwest::Client,
+	channels: Vec<NotificationConfig>,
+	ups_name: String,
+	hostname: String,
+	pending: Mutex<JoinSet<()>>,
+	// Last message sent to each channel, by channel index
+	last_sent: Mutex<HashMap<usize, (String, Instant)>>,
+}
+
+/// Body POSTed to generic webhooks: the rendered message plus the raw event.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+	message: &'a str,
+	ups_name: &'a str,
+	host: &'a str,
+	#[serde(flatten)]
+	event: &'a PowerEvent,
+}
+
+impl Notifier {
+	pub fn new(channels: Vec<NotificationConfig>, ups_name: String) -> Self {
+		let client = reqwest::Client::builder()
+			.timeout(REQUEST_TIMEOUT)
+			.build()
+			.unwrap_or_default();
+
+		Notifier {
+			client,
+			channels,
+			ups_name,
+			hostname: hostname(),
+			pending: Mutex::new(JoinSet::new()),
+			last_sent: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Renders the event for every channel subscribed to it and POSTs it in
+	/// the background on the current runtime.
+	pub fn notify(&self, event: &PowerEvent) {
+		let mut pending = self.pending.lock().unwrap();
+		let mut last_sent = self.last_sent.lock().unwrap();
+
+		// Reap finished requests so the set doesn't grow forever
+		while pending.try_join_next().is_some() {}
+
+		for (index, channel) in self.channels.iter().enumerate() {
+			if channel
+				.events
+				.as_ref()
+				.is_some_and(|events| !events.contains(&event.event))
+			{
+				continue;
+			}
+
+			let message = self.render(channel, event);
+
+			let now = Instant::now();
+			if let Some((previous, sent_at)) = last_sent.get(&index)
+				&& *previous == message
+				&& now.duration_since(*sent_at) < DEBOUNCE_WINDOW
+			{
+				debug!(
+					"Skipping duplicate notification to {}",
+					channel_label(channel)
+				);
+				continue;
+			}
+			last_sent.insert(index, (message.clone(), now));
+
+			let request = match channel.notification_type {
+				NotificationType::Generic => self.client.post(&channel.url).json(&WebhookPayload {
+					message: &message,
+					ups_name: &self.ups_name,
+					host: &self.hostname,
+					event,
+				}),
+				NotificationType::Slack => self
+					.client
+					.post(&channel.url)
+					.json(&serde_json::json!({ "text": message })),
+				NotificationType::Discord => self
+					.client
+					.post(&channel.url)
+					.json(&serde_json::json!({ "content": message })),
+				NotificationType::Telegram => {
+					// Both are checked by `Config::validate`
+					let token = channel.bot_token.as_deref().unwrap_or_default();
+					let chat_id = channel.chat_id.as_deref().unwrap_or_default();
+					self
+						.client
+						.post(format!("{}/bot{}/sendMessage", TELEGRAM_API, token))
+						.json(&serde_json::json!({
+							"chat_id": chat_id,
+							"text": message,
+							"parse_mode": "Markdown",
+						}))
+				}
+			};
+			let label = channel_label(channel);
+
+			pending.spawn(async move {
+				match request.send().await {
+					Ok(response) if response.status().is_success() => {
+						debug!("Notification sent to {}", label);
+					}
+					Ok(response) => {
+						let status = response.status();
+						let body = response.text().await.unwrap_or_default();
+						match api_error_description(&body) {
+							Some(description) => error!(
+								"Notification to {} failed: HTTP {} ({})",
+								label, status, description
+							),
+							None => error!("Notification to {} failed: HTTP {}", label, status),
+						}
+					}
+					// reqwest errors include the URL, which holds the Telegram bot token
+					Err(e) => error!("Notification to {} failed: {}", label, e.without_url()),
+				}
+			});
+		}
+	}
+
+	/// Waits for notifications still in flight, e.g. before the process exits.
+	pub async fn flush(&self) {
+		let mut pending = std::mem::take(&mut *self.pending.lock().unwrap());
+		while pending.join_next().await.is_some() {}
+	}
+
+	fn render(&self, channel: &NotificationConfig, event: &PowerEvent) -> String {
+		let template = channel
+			.templates
+			.get(event.event.as_str())
+			.or(channel.message_template.as_ref())
+			.map(String::as_str)
+			.unwrap_or_else(|| default_template(event.event));
+
+		match channel.notification_type {
+			NotificationType::Telegram => {
+				render_telegram_template(template, &self.ups_name, &self.hostname, event)
+			}
+			_ => render_template(template, &self.ups_name, &self.hostname, event),
+		}
+	}
+}
+
+impl EventHandler for Notifier {
+	fn on_event(&self, event: &PowerEvent) {
+		self.notify(event);
+	}
+}
+
+fn default_template(event: EventType) -> &'static str {
+	match event {
+		EventType::OnBattery => {
+			"⚠️ {ups_name} switched to battery power ({charge}% charge, {runtime}s runtime)"
+		}
+		EventType::OnLine => "✅ {ups_name} is back on line power after {duration}s on battery",
+		EventType::ShutdownCountdown => "⏳ {host}: {reason} ({charge}% charge)",
+		EventType::ShutdownTriggered => "🚨 {host} is shutting down: {reason} ({charge}% charge)",
+		EventType::ShutdownCancelled => "🛑 Shutdown of {host} was cancelled",
+		EventType::TemperatureHigh => "🌡️ {ups_name}: {reason}",
+		EventType::PollFailing => "❌ {ups_name} is unreachable: {reason}",
+		EventType::PollRecovered => "✅ {ups_name} is reachable again: {reason}",
+		EventType::BatteryLow => "🔋 {ups_name} battery is getting low: {reason}",
+		EventType::ReplaceBattery => "🪫 {ups_name} reports its battery needs replacing",
+		EventType::Overload => "⚡ {ups_name} output is overloaded ({status})",
+		EventType::OnBypass => "⚠️ {ups_name} is on bypass, the battery is not protecting the load",
+	}
+}
+
+/// Replaces `{ups_name}`, `{host}`, `{event}`, `{charge}`, `{runtime}`,
+/// `{status}`, `{duration}` and `{reason}` in the template.
+pub fn render_template(template: &str, ups_name: &str, host: &str, event: &PowerEvent) -> String {
+	render_with(template, ups_name, host, event, str::to_string)
+}
+
+/// Like [`render_template`], but escapes the substituted values for Telegram's
+/// Markdown, which rejects the whole message over an unmatched `_` or `*`
+/// such as the one in `ACCESS_DENIED`. The template itself stays Markdown.
+pub fn render_telegram_template(
+	template: &str,
+	ups_name: &str,
+	host: &str,
+	event: &PowerEvent,
+) -> String {
+	render_with(template, ups_name, host, event, escape_markdown)
+}
+
+fn render_with(
+	template: &str,
+	ups_name: &str,
+	host: &str,
+	event: &PowerEvent,
+	escape: fn(&str) -> String,
+) -> String {
+	let unknown = || "unknown".to_string();
+	let values = [
+		("{ups_name}", ups_name.to_string()),
+		("{host}", host.to_string()),
+		("{event}", event.event.as_str().to_string()),
+		(
+			"{charge}",
+			event.battery_charge.map_or_else(unknown, |c| c.to_string()),
+		),
+		(
+			"{runtime}",
+			event
+				.battery_runtime
+				.map_or_else(unknown, |r| r.to_string()),
+		),
+		("{status}", event.ups_status.clone().unwrap_or_else(unknown)),
+		(
+			"{duration}",
+			event
+				.on_battery_duration_seconds
+				.map_or_else(unknown, |d| d.to_string()),
+		),
+		("{reason}", event.reason.clone().unwrap_or_default()),
+	];
+
+	values
+		.iter()
+		.fold(template.to_string(), |message, (placeholder, value)| {
+			message.replace(placeholder, &escape(value))
+		})
+}
+
+// Legacy Markdown only treats these as markup, and a backslash escapes each
+fn escape_markdown(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		if matches!(c, '_' | '*' | '`' | '[') {
+			escaped.push('\\');
+		}
+		escaped.push(c);
+	}
+	escaped
+}
+
+// How a channel appears in logs; Telegram URLs embed the bot token
+fn channel_label(channel: &NotificationConfig) -> String {
+	match channel.notification_type {
+		NotificationType::Telegram => format!(
+			"Telegram chat {}",
+			channel.chat_id.as_deref().unwrap_or_default()
+		),
+		_ => channel.url.clone(),
+	}
+}
+
+// Error text from APIs that answer `{"ok": false, "description": "..."}`, like Telegram
+fn api_error_description(body: &str) -> Option<String> {
+	serde_json::from_str::<serde_json::Value>(body)
+		.ok()?
+		.get("description")?
+		.as_str()
+		.map(str::to_string)
+}
+
+// Name of this machine, for telling hosts apart in shared channels
+pub(crate) fn hostname() -> String {
+	std::env::var("HOSTNAME")
+		.or_else(|_| std::env::var("COMPUTERNAME"))
+		.ok()
+		.or_else(|| {
+			std::fs::read_to_string("/etc/hostname")
+				.ok()
+				.map(|name| name.trim().to_string())
+		})
+		.filter(|name| !name.is_empty())
+		.unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn telegram_values_are_escaped_but_not_the_template() {
+		let event = PowerEvent {
+			timestamp: String::new(),
+			event: EventType::PollFailing,
+			battery_charge: None,
+			battery_runtime: None,
+			ups_status: None,
+			on_battery_duration_seconds: None,
+			reason: Some("ERR ACCESS_DENIED".to_string()),
+		};
+		let template = "*{ups_name}* is unreachable: {reason}";
+
+		assert_eq!(
+			render_template(template, "my_ups", "host", &event),
+			"*my_ups* is unreachable: ERR ACCESS_DENIED"
+		);
+		assert_eq!(
+			render_telegram_template(template, "my_ups", "host", &event),
+			"*my\\_ups* is unreachable: ERR ACCESS\\_DENIED"
+		);
+	}
+}
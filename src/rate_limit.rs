@@ -0,0 +1,85 @@
+use axum::{
+	extract::{ConnectInfo, Request, State},
+	http::{StatusCode, header},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Forget idle clients once this many are tracked
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+/// Per-client-IP token bucket allowing `per_minute` requests per minute,
+/// with bursts of up to `per_minute` requests.
+pub struct RateLimiter {
+	capacity: f64,
+	refill_per_second: f64,
+	buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+impl RateLimiter {
+	pub fn new(per_minute: u32) -> Self {
+		RateLimiter {
+			capacity: per_minute as f64,
+			refill_per_second: per_minute as f64 / 60.0,
+			buckets: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Takes a token for `ip`, or returns the seconds until one is available.
+	fn acquire(&self, ip: IpAddr) -> Result<(), u64> {
+		let now = Instant::now();
+		let mut buckets = self.buckets.lock().unwrap();
+
+		if buckets.len() >= MAX_TRACKED_CLIENTS {
+			let capacity = self.capacity;
+			let refill = self.refill_per_second;
+			buckets.retain(|_, bucket| {
+				bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill < capacity
+			});
+		}
+
+		let bucket = buckets.entry(ip).or_insert(Bucket {
+			tokens: self.capacity,
+			updated: now,
+		});
+
+		let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+		bucket.tokens = (bucket.tokens + elapsed * self.refill_per_second).min(self.capacity);
+		bucket.updated = now;
+
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			Ok(())
+		} else if self.refill_per_second > 0.0 {
+			Err(((1.0 - bucket.tokens) / self.refill_per_second).ceil() as u64)
+		} else {
+			Err(60)
+		}
+	}
+}
+
+pub async fn rate_limit(
+	State(limiter): State<Arc<RateLimiter>>,
+	ConnectInfo(addr): ConnectInfo<SocketAddr>,
+	request: Request,
+	next: Next,
+) -> Response {
+	match limiter.acquire(addr.ip()) {
+		Ok(()) => next.run(request).await,
+		Err(retry_after) => (
+			StatusCode::TOO_MANY_REQUESTS,
+			[(header::RETRY_AFTER, retry_after.to_string())],
+			"Too Many Requests",
+		)
+			.into_response(),
+	}
+}
@@ -0,0 +1,92 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::SimulationConfig;
+use crate::ups::{UpsError, UpsSource, UpsStatus};
+
+/// Replays a scripted outage instead of talking to a NUT server.
+///
+/// Each cycle starts on line power for `online_polls` polls, then runs on
+/// battery for `battery_polls` polls with charge and runtime dropping every
+/// poll, after which power is restored and the cycle repeats.
+pub struct SimulatedUpsSource {
+	config: SimulationConfig,
+	poll: AtomicU64,
+}
+
+impl SimulatedUpsSource {
+	pub fn new(config: SimulationConfig) -> Self {
+		SimulatedUpsSource {
+			config,
+			poll: AtomicU64::new(0),
+		}
+	}
+
+	fn status_at(&self, poll: u64) -> UpsStatus {
+		let cycle_length = self.config.online_polls + self.config.battery_polls;
+		let step = if cycle_length == 0 {
+			0
+		} else {
+			poll % cycle_length
+		};
+
+		if step < self.config.online_polls {
+			return UpsStatus {
+				battery_charge: Some(100.0),
+				battery_runtime: Some(self.config.full_runtime),
+				ups_status: "OL".to_string(),
+				on_battery: false,
+				charging: false,
+				output_power: Some(self.config.output_power),
+				battery_temperature: None,
+				ups_temperature: None,
+				input_frequency: None,
+				battery_voltage: None,
+				battery_voltage_nominal: None,
+				battery_charge_restart: None,
+				battery_date: None,
+				extra_vars: HashMap::new(),
+			};
+		}
+
+		let battery_step = (step - self.config.online_polls + 1) as f64;
+		let battery_charge = (100.0 - self.config.charge_drop_per_poll * battery_step).max(0.0);
+		let battery_runtime = (self.config.full_runtime as f64 * battery_charge / 100.0) as u64;
+
+		UpsStatus {
+			battery_charge: Some(battery_charge),
+			battery_runtime: Some(battery_runtime),
+			ups_status: "OB DISCHRG".to_string(),
+			on_battery: true,
+			charging: false,
+			output_power: Some(self.config.output_power),
+			battery_temperature: None,
+			ups_temperature: None,
+			input_frequency: None,
+			battery_voltage: None,
+			battery_voltage_nominal: None,
+			battery_charge_restart: None,
+			battery_date: None,
+			extra_vars: HashMap::new(),
+		}
+	}
+}
+
+#[async_trait]
+impl UpsSource for SimulatedUpsSource {
+	async fn get_status(&self) -> Result<UpsStatus, UpsError> {
+		let poll = self.poll.fetch_add(1, Ordering::Relaxed);
+		Ok(self.status_at(poll))
+	}
+
+	async fn list_vars(&self) -> Result<Vec<(String, String)>, UpsError> {
+		Ok(vec![
+			(
+				"device.model".to_string(),
+				"RabbitNUT Simulator".to_string(),
+			),
+			("ups.status".to_string(), "OL".to_string()),
+		])
+	}
+}
@@ -0,0 +1,186 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::address::{self, format_address};
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_IPV6: u8 = 0x04;
+
+/// A SOCKS5 proxy (RFC 1928) the NUT connection is tunnelled through.
+#[derive(Debug, Clone)]
+pub struct Socks5Proxy {
+	pub host: String,
+	pub port: u16,
+	// Username/password authentication (RFC 1929) when set
+	pub credentials: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+	/// Connects to the proxy and asks it to open a TCP connection to `host:port`.
+	/// The target name is resolved by the proxy, not locally.
+	pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+		let mut stream = address::connect(&self.host, self.port)
+			.await
+			.map_err(|e| self.error(e.kind(), format!("unreachable: {}", e)))?;
+
+		self.negotiate(&mut stream, host, port).await.map_err(|e| {
+			// Our own errors already name the proxy
+			if e.to_string().starts_with("SOCKS5") {
+				e
+			} else {
+				self.error(e.kind(), format!("negotiation failed: {}", e))
+			}
+		})?;
+
+		Ok(stream)
+	}
+
+	async fn negotiate(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+		let method = if self.credentials.is_some() {
+			USERNAME_PASSWORD
+		} else {
+			NO_AUTH
+		};
+		stream.write_all(&[VERSION, 1, method]).await?;
+
+		let mut reply = [0u8; 2];
+		stream.read_exact(&mut reply).await?;
+		if reply[0] != VERSION {
+			return Err(self.error(ErrorKind::InvalidData, "is not a SOCKS5 proxy"));
+		}
+		if reply[1] == NO_ACCEPTABLE_METHOD || reply[1] != method {
+			return Err(self.error(
+				ErrorKind::PermissionDenied,
+				if self.credentials.is_some() {
+					"does not accept username/password authentication"
+				} else {
+					"requires authentication, set ups.proxy.username and password"
+				},
+			));
+		}
+
+		if let Some((ref username, ref password)) = self.credentials {
+			self.authenticate(stream, username, password).await?;
+		}
+
+		// CONNECT with IP literals as addresses and anything else as a domain
+		// name, which the proxy resolves
+		let mut request = vec![VERSION, CONNECT, 0x00];
+		match address::ip_literal(host) {
+			Some(IpAddr::V4(ip)) => {
+				request.push(ADDRESS_IPV4);
+				request.extend_from_slice(&ip.octets());
+			}
+			Some(IpAddr::V6(ip)) => {
+				request.push(ADDRESS_IPV6);
+				request.extend_from_slice(&ip.octets());
+			}
+			None => {
+				let host_bytes = host.as_bytes();
+				let host_len = u8::try_from(host_bytes.len())
+					.map_err(|_| self.error(ErrorKind::InvalidInput, "target host name is too long"))?;
+				request.extend_from_slice(&[ADDRESS_DOMAIN, host_len]);
+				request.extend_from_slice(host_bytes);
+			}
+		}
+		request.extend_from_slice(&port.to_be_bytes());
+		stream.write_all(&request).await?;
+
+		let mut reply = [0u8; 4];
+		stream.read_exact(&mut reply).await?;
+		if reply[1] != 0x00 {
+			return Err(self.error(
+				ErrorKind::ConnectionRefused,
+				format!(
+					"could not connect to {}: {}",
+					format_address(host, port),
+					reply_message(reply[1])
+				),
+			));
+		}
+
+		// Skip the bound address the proxy reports back
+		let address_len = match reply[3] {
+			ADDRESS_IPV4 => 4,
+			ADDRESS_IPV6 => 16,
+			ADDRESS_DOMAIN => stream.read_u8().await? as usize,
+			other => {
+				return Err(self.error(
+					ErrorKind::InvalidData,
+					format!("sent an unknown address type {}", other),
+				));
+			}
+		};
+		let mut bound = vec![0u8; address_len + 2];
+		stream.read_exact(&mut bound).await?;
+
+		Ok(())
+	}
+
+	async fn authenticate(
+		&self,
+		stream: &mut TcpStream,
+		username: &str,
+		password: &str,
+	) -> Result<()> {
+		let (Ok(username_len), Ok(password_len)) =
+			(u8::try_from(username.len()), u8::try_from(password.len()))
+		else {
+			return Err(self.error(
+				ErrorKind::InvalidInput,
+				"username and password must be at most 255 bytes",
+			));
+		};
+
+		let mut request = vec![0x01, username_len];
+		request.extend_from_slice(username.as_bytes());
+		request.push(password_len);
+		request.extend_from_slice(password.as_bytes());
+		stream.write_all(&request).await?;
+
+		let mut reply = [0u8; 2];
+		stream.read_exact(&mut reply).await?;
+		if reply[1] != 0x00 {
+			return Err(self.error(
+				ErrorKind::PermissionDenied,
+				"rejected the username or password",
+			));
+		}
+
+		Ok(())
+	}
+
+	fn error(&self, kind: ErrorKind, message: impl std::fmt::Display) -> Error {
+		Error::new(
+			kind,
+			format!(
+				"SOCKS5 proxy {} {}",
+				format_address(&self.host, self.port),
+				message
+			),
+		)
+	}
+}
+
+// Reply codes from RFC 1928 section 6
+fn reply_message(code: u8) -> &'static str {
+	match code {
+		0x01 => "general failure",
+		0x02 => "connection not allowed by ruleset",
+		0x03 => "network unreachable",
+		0x04 => "host unreachable",
+		0x05 => "connection refused",
+		0x06 => "TTL expired",
+		0x07 => "command not supported",
+		0x08 => "address type not supported",
+		_ => "unknown error",
+	}
+}
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedState {
+	// Unix timestamp of the transfer to battery
+	on_battery_since: i64,
+}
+
+/// Small JSON file holding the start of the current outage, so a restart
+/// mid-outage doesn't reset the on-battery timer.
+pub struct StateFile {
+	path: PathBuf,
+}
+
+impl StateFile {
+	pub fn new(path: &str) -> Self {
+		StateFile {
+			path: PathBuf::from(path),
+		}
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Reads the saved outage start; `Ok(None)` when there is no file.
+	pub fn load(&self) -> io::Result<Option<i64>> {
+		let contents = match fs::read_to_string(&self.path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(e),
+		};
+
+		let state: SavedState = serde_json::from_str(&contents)?;
+		Ok(Some(state.on_battery_since))
+	}
+
+	pub fn save(&self, on_battery_since: i64) -> io::Result<()> {
+		if let Some(parent) = self.path.parent()
+			&& !parent.as_os_str().is_empty()
+			&& !parent.exists()
+		{
+			fs::create_dir_all(parent)?;
+		}
+
+		let contents = serde_json::to_string(&SavedState { on_battery_since })?;
+		fs::write(&self.path, contents)
+	}
+
+	pub fn clear(&self) -> io::Result<()> {
+		match fs::remove_file(&self.path) {
+			Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+			_ => Ok(()),
+		}
+	}
+}
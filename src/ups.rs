@@ -1,14 +1,40 @@
+use async_trait::async_trait;
+use chrono::{Datelike, NaiveDate};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpStream;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Mutex;
 
-#[derive(Debug, Clone)]
+use crate::address::{self, format_address};
+use crate::config::UpsConfig;
+use crate::socks::Socks5Proxy;
+
+/// One reading of the UPS variables RabbitNUT acts on.
+#[derive(Debug, Clone, PartialEq)]
 pub struct UpsStatus {
-	pub battery_charge: f64,
-	pub battery_runtime: u64,
+	// None when the UPS reported a value that could not be parsed
+	pub battery_charge: Option<f64>,
+	pub battery_runtime: Option<u64>,
 	pub ups_status: String,
 	pub on_battery: bool,
+	pub charging: bool,
 	pub output_power: Option<f64>,
+	pub battery_temperature: Option<f64>,
+	pub ups_temperature: Option<f64>,
+	pub input_frequency: Option<f64>,
+	pub battery_voltage: Option<f64>,
+	pub battery_voltage_nominal: Option<f64>,
+	// Charge the UPS waits for before restarting the load after a shutdown
+	pub battery_charge_restart: Option<f64>,
+	// Raw `battery.date`, or `battery.mfr.date` when that is missing
+	pub battery_date: Option<String>,
+	// User-requested `extra_vars` the UPS reported, by variable name
+	pub extra_vars: HashMap<String, String>,
 }
 
 impl fmt::Display for UpsStatus {
@@ -16,17 +42,367 @@ impl fmt::Display for UpsStatus {
 		write!(
 			f,
 			"Charge: {}%, Runtime: {}s, Status: {}, On Battery: {}",
-			self.battery_charge, self.battery_runtime, self.ups_status, self.on_battery
+			or_unknown(self.battery_charge),
+			or_unknown(self.battery_runtime),
+			self.ups_status,
+			self.on_battery
 		)
 	}
 }
 
+// Every state token NUT defines for `ups.status`
+const KNOWN_STATUS_FLAGS: [&str; 20] = [
+	"OL", "OB", "LB", "HB", "RB", "CHRG", "DISCHRG", "BYPASS", "CAL", "OFF", "OVER", "TRIM", "BOOST",
+	"FSD", "ALARM", "TEST", "ECO", "COMM", "NOCOMM", "WAIT",
+];
+
+impl UpsStatus {
+	/// Whether `ups.status` contains at least one state token NUT defines.
+	pub fn is_recognized(&self) -> bool {
+		self
+			.ups_status
+			.split_whitespace()
+			.any(|word| KNOWN_STATUS_FLAGS.contains(&word))
+	}
+
+	/// Whether `ups.status` contains the flag as a whole word, e.g. `RB`.
+	pub fn has_flag(&self, flag: &str) -> bool {
+		self.ups_status.split_whitespace().any(|word| word == flag)
+	}
+
+	/// Energy left at the current load, from `battery.runtime` and `output.power`.
+	pub fn energy_remaining_watthours(&self) -> Option<f64> {
+		let runtime = self.battery_runtime?;
+		let power = self.output_power?;
+		Some(runtime as f64 * power / 3600.0)
+	}
+
+	/// Days since `battery_date`, or None when it is missing or unparsable.
+	pub fn battery_age_days(&self, today: NaiveDate) -> Option<i64> {
+		let date = parse_battery_date(self.battery_date.as_deref()?, today)?;
+		Some((today - date).num_days())
+	}
+}
+
+// Formats drivers use for battery dates; APC reports mm/dd/yy, so month-first
+// is tried before day-first
+const BATTERY_DATE_FORMATS: [&str; 8] = [
+	"%Y/%m/%d", "%Y-%m-%d", "%Y%m%d", "%d.%m.%Y", "%m/%d/%Y", "%m/%d/%y", "%d/%m/%Y", "%d/%m/%y",
+];
+
+/// Parses a `battery.date` value in any of the formats NUT drivers report,
+/// including year and month only. Dates before 1990 or after `today` are
+/// treated as placeholders and rejected.
+pub fn parse_battery_date(value: &str, today: NaiveDate) -> Option<NaiveDate> {
+	let value = value.trim();
+	let full_dates = BATTERY_DATE_FORMATS
+		.iter()
+		.filter_map(|format| NaiveDate::parse_from_str(value, format).ok());
+	let month_dates =
+		[("/", "%Y/%m/%d"), ("-", "%Y-%m-%d")]
+			.iter()
+			.filter_map(|(separator, format)| {
+				NaiveDate::parse_from_str(&format!("{}{}01", value, separator), format).ok()
+			});
+
+	full_dates
+		.chain(month_dates)
+		.find(|date| date.year() >= 1990 && *date <= today)
+}
+
+// Identity labels of `ups_info` and the NUT variables they come from, in order
+// of preference; drivers fill either the device.* or the older ups.* names
+const DEVICE_INFO_VARS: [(&str, &[&str]); 7] = [
+	("manufacturer", &["device.mfr", "ups.mfr"]),
+	("model", &["device.model", "ups.model"]),
+	("serial", &["device.serial", "ups.serial"]),
+	("firmware", &["ups.firmware"]),
+	("driver", &["driver.name"]),
+	("driver_version", &["driver.version"]),
+	("location", &["device.location"]),
+];
+
+/// Picks the static identity (model, serial, firmware, ...) out of a `LIST VAR`
+/// result, leaving out whatever the UPS doesn't report.
+pub fn device_info(vars: &[(String, String)]) -> BTreeMap<String, String> {
+	DEVICE_INFO_VARS
+		.iter()
+		.filter_map(|(label, names)| {
+			names
+				.iter()
+				.find_map(|name| {
+					vars
+						.iter()
+						.find(|(var, value)| var == name && !value.trim().is_empty())
+				})
+				.map(|(_, value)| (label.to_string(), value.trim().to_string()))
+		})
+		.collect()
+}
+
+/// Formats an optional reading, with "unknown" for a missing value.
+pub fn or_unknown<T: fmt::Display>(value: Option<T>) -> String {
+	value.map_or_else(|| "unknown".to_string(), |value| value.to_string())
+}
+
+/// Parses a numeric NUT variable, ignoring surrounding whitespace and trailing
+/// unit text such as `"100 %"` or `"230V"`.
+pub fn parse_number(value: &str) -> Option<f64> {
+	let value = value.trim();
+	let end = value
+		.char_indices()
+		.find(|(index, c)| {
+			!(c.is_ascii_digit() || *c == '.' || (*index == 0 && (*c == '-' || *c == '+')))
+		})
+		.map_or(value.len(), |(index, _)| index);
+
+	value[..end]
+		.parse::<f64>()
+		.ok()
+		.filter(|number| number.is_finite())
+}
+
+/// Parses a duration in seconds, truncating fractional values such as `"3600.0"`.
+pub fn parse_seconds(value: &str) -> Option<u64> {
+	parse_number(value)
+		.filter(|seconds| *seconds >= 0.0)
+		.map(|seconds| seconds as u64)
+}
+
+/// Errors returned while talking to a NUT server.
+#[derive(Debug)]
+pub enum UpsError {
+	/// `ERR ACCESS-DENIED`: bad credentials or missing permissions.
+	AccessDenied,
+	/// The server rejected the username or password, or requires them
+	/// (`ERR INVALID-USERNAME`, `INVALID-PASSWORD`, `USERNAME-REQUIRED`, ...).
+	InvalidCredentials(String),
+	/// `ERR ACCESS-DENIED` for a command that needs `upsd.users` permissions,
+	/// with the permission it needs.
+	InsufficientPrivileges {
+		command: String,
+		required: String,
+	},
+	/// `ERR UNKNOWN-UPS`: the configured UPS name does not exist on the server.
+	UnknownUps,
+	/// `ERR VAR-NOT-SUPPORTED`: the UPS does not report the requested variable.
+	VarNotSupported,
+	/// `ERR DATA-STALE`: the driver lost contact with the UPS hardware.
+	DataStale,
+	/// `ERR DRIVER-NOT-CONNECTED`: upsd cannot reach the UPS driver.
+	DriverNotConnected,
+	/// `ERR READONLY`: the variable exists but the UPS doesn't allow changing it.
+	ReadOnly,
+	/// Any other `ERR` response, with the raw server message.
+	Nut(String),
+	/// A response that is not valid NUT protocol.
+	InvalidResponse(String),
+	Io(std::io::Error),
+}
+
+impl UpsError {
+	fn from_response(response: &str) -> Self {
+		let parts = tokenize(response);
+		match parts.get(1).map(String::as_str) {
+			Some("ACCESS-DENIED") => UpsError::AccessDenied,
+			Some(
+				code
+				@ ("INVALID-USERNAME" | "INVALID-PASSWORD" | "USERNAME-REQUIRED" | "PASSWORD-REQUIRED"),
+			) => UpsError::InvalidCredentials(code.to_string()),
+			Some("UNKNOWN-UPS") => UpsError::UnknownUps,
+			Some("VAR-NOT-SUPPORTED") => UpsError::VarNotSupported,
+			Some("DATA-STALE") => UpsError::DataStale,
+			Some("DRIVER-NOT-CONNECTED") => UpsError::DriverNotConnected,
+			Some("READONLY") => UpsError::ReadOnly,
+			_ => UpsError::Nut(response.trim().to_string()),
+		}
+	}
+}
+
+impl fmt::Display for UpsError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			UpsError::AccessDenied => write!(f, "Access denied by NUT server (ERR ACCESS-DENIED)"),
+			UpsError::InvalidCredentials(code) => write!(
+				f,
+				"NUT server rejected the login (ERR {}) - check ups.username and ups.password",
+				code
+			),
+			UpsError::InsufficientPrivileges { command, required } => write!(
+				f,
+				"NUT server denied {} (ERR ACCESS-DENIED) - the user needs {} in upsd.users, \
+				 or the password is wrong",
+				command, required
+			),
+			UpsError::UnknownUps => write!(f, "UPS not known to NUT server (ERR UNKNOWN-UPS)"),
+			UpsError::VarNotSupported => write!(f, "Variable not supported (ERR VAR-NOT-SUPPORTED)"),
+			UpsError::DataStale => write!(f, "UPS data is stale (ERR DATA-STALE)"),
+			UpsError::DriverNotConnected => {
+				write!(f, "UPS driver not connected (ERR DRIVER-NOT-CONNECTED)")
+			}
+			UpsError::ReadOnly => write!(f, "Variable is read-only on this UPS (ERR READONLY)"),
+			UpsError::Nut(message) => write!(f, "UPS error response: {}", message),
+			UpsError::InvalidResponse(response) => write!(f, "Invalid response: {}", response.trim()),
+			UpsError::Io(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl std::error::Error for UpsError {}
+
+impl From<std::io::Error> for UpsError {
+	fn from(e: std::io::Error) -> Self {
+		UpsError::Io(e)
+	}
+}
+
+/// Versions reported by the NUT server; `None` when it doesn't implement the command.
+#[derive(Debug, Default)]
+pub struct ServerVersion {
+	pub server: Option<String>,
+	pub protocol: Option<String>,
+}
+
+/// A provider of UPS readings consumed by the monitor.
+#[async_trait]
+pub trait UpsSource: Send + Sync {
+	async fn get_status(&self) -> Result<UpsStatus, UpsError>;
+	async fn list_vars(&self) -> Result<Vec<(String, String)>, UpsError>;
+
+	async fn server_version(&self) -> Result<ServerVersion, UpsError> {
+		Ok(ServerVersion::default())
+	}
+
+	/// The `host:port` of the NUT server currently in use, if any.
+	fn current_server(&self) -> Option<String> {
+		None
+	}
+
+	/// How many times polling switched to another NUT server.
+	fn failovers(&self) -> u64 {
+		0
+	}
+
+	/// Runs an instant command such as `shutdown.return` on the UPS.
+	async fn instant_command(&self, command: &str) -> Result<(), UpsError> {
+		Err(UpsError::Nut(format!(
+			"instant command {} is not supported by this source",
+			command
+		)))
+	}
+
+	/// Sets the forced shutdown (FSD) flag on the UPS.
+	async fn forced_shutdown(&self) -> Result<(), UpsError> {
+		Err(UpsError::Nut(
+			"forced shutdown is not supported by this source".to_string(),
+		))
+	}
+
+	/// Changes a writable UPS variable such as `battery.charge.low`.
+	async fn set_var(&self, var: &str, _value: &str) -> Result<(), UpsError> {
+		Err(UpsError::Nut(format!(
+			"setting {} is not supported by this source",
+			var
+		)))
+	}
+
+	/// Names and descriptions of every UPS the server exposes.
+	async fn list_ups(&self) -> Result<Vec<(String, String)>, UpsError> {
+		Err(UpsError::Nut(
+			"listing UPSes is not supported by this source".to_string(),
+		))
+	}
+
+	/// Exercises any long-lived connection between polls, reconnecting if it
+	/// was dropped.
+	async fn keep_alive(&self) {}
+}
+
+/// Client for a NUT server (`upsd`), opening a connection per request and
+/// failing over to the other configured servers.
 pub struct UpsClient {
-	host: String,
-	port: u16,
+	// NUT servers in failover order, all exposing the same UPS
+	servers: Vec<(String, u16)>,
+	current: AtomicUsize,
+	failovers: AtomicU64,
+	proxy: Option<Socks5Proxy>,
 	name: String,
+	extra_vars: Vec<String>,
 	username: Option<String>,
 	password: Option<String>,
+	primary: bool,
+	// Connection holding the primary login; upsd counts us only while it stays open
+	session: Mutex<Option<Connection>>,
+	// Whether the last primary login failed, so a refusal is warned about once
+	session_failing: AtomicBool,
+}
+
+// Upper bound for a complete response line, however slowly it trickles in
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A NUT connection whose reader keeps any bytes received past the current
+/// line, so responses split across or merged into TCP reads parse the same.
+struct Connection {
+	reader: BufReader<OwnedReadHalf>,
+	writer: OwnedWriteHalf,
+}
+
+impl Connection {
+	async fn open(host: &str, port: u16, proxy: Option<&Socks5Proxy>) -> std::io::Result<Self> {
+		let stream = match proxy {
+			Some(proxy) => proxy.connect(host, port).await?,
+			None => address::connect(host, port).await?,
+		};
+		let (reader, writer) = stream.into_split();
+		Ok(Connection {
+			reader: BufReader::new(reader),
+			writer,
+		})
+	}
+
+	async fn send(&mut self, command: &str) -> Result<(), UpsError> {
+		self
+			.writer
+			.write_all(format!("{}\n", command).as_bytes())
+			.await?;
+		Ok(())
+	}
+
+	// Read the next non-blank line without its line ending, reassembling it
+	// from as many reads as it takes until RESPONSE_TIMEOUT
+	async fn read_line(&mut self) -> Result<String, UpsError> {
+		match tokio::time::timeout(RESPONSE_TIMEOUT, self.read_non_blank_line()).await {
+			Ok(result) => result,
+			Err(_) => {
+				Err(std::io::Error::new(ErrorKind::TimedOut, "timed out waiting for NUT response").into())
+			}
+		}
+	}
+
+	async fn read_non_blank_line(&mut self) -> Result<String, UpsError> {
+		let mut line = Vec::new();
+
+		loop {
+			line.clear();
+			self.reader.read_until(b'\n', &mut line).await?;
+
+			if line.last() != Some(&b'\n') {
+				return Err(
+					std::io::Error::new(
+						ErrorKind::UnexpectedEof,
+						"NUT server closed the connection mid-response",
+					)
+					.into(),
+				);
+			}
+
+			// Skip stray blank lines between responses
+			let text = String::from_utf8_lossy(&line).trim_end().to_string();
+			if !text.is_empty() {
+				return Ok(text);
+			}
+		}
+	}
 }
 
 impl UpsClient {
@@ -38,128 +414,1372 @@ impl UpsClient {
 		password: Option<String>,
 	) -> Self {
 		UpsClient {
-			host,
-			port,
+			servers: vec![(host, port)],
+			current: AtomicUsize::new(0),
+			failovers: AtomicU64::new(0),
+			proxy: None,
 			name,
+			extra_vars: Vec::new(),
 			username,
 			password,
+			primary: false,
+			session: Mutex::new(None),
+			session_failing: AtomicBool::new(false),
 		}
 	}
 
-	fn connect(&self) -> Result<TcpStream, Box<dyn std::error::Error>> {
-		let addr = format!("{}:{}", self.host, self.port);
-		let mut stream = TcpStream::connect(addr)?;
+	/// Builds the client described by the `[ups]` section, with its failover
+	/// servers, proxy and role.
+	pub fn from_config(config: &UpsConfig) -> Self {
+		UpsClient::new(
+			config.host.clone(),
+			config.port,
+			config.name.clone(),
+			config.username.clone(),
+			config.password.clone(),
+		)
+		.with_failover(
+			config
+				.servers
+				.iter()
+				.map(|server| (server.host.clone(), server.port))
+				.collect(),
+		)
+		.with_extra_vars(config.extra_vars.clone())
+		.with_primary_role(config.role == "primary")
+		.with_proxy(config.proxy.as_ref().map(|proxy| Socks5Proxy {
+			host: proxy.host.clone(),
+			port: proxy.port,
+			credentials: proxy.username.clone().zip(proxy.password.clone()),
+		}))
+	}
 
-		if self.username.is_some() && self.password.is_some() {
-			self.authenticate(&mut stream)?;
+	/// Adds servers to fail over to, in order, when the current one is unreachable.
+	pub fn with_failover(mut self, servers: Vec<(String, u16)>) -> Self {
+		self.servers.extend(servers);
+		self
+	}
+
+	/// Tunnels every connection through a SOCKS5 proxy instead of connecting directly.
+	pub fn with_proxy(mut self, proxy: Option<Socks5Proxy>) -> Self {
+		self.proxy = proxy;
+		self
+	}
+
+	/// Sets additional variables fetched on every poll; missing ones are skipped.
+	pub fn with_extra_vars(mut self, extra_vars: Vec<String>) -> Self {
+		self.extra_vars = extra_vars;
+		self
+	}
+
+	/// Registers as the primary upsmon for the UPS so upsd waits for us
+	/// before its own shutdown; requires `upsmon primary` in upsd.users.
+	pub fn with_primary_role(mut self, primary: bool) -> Self {
+		self.primary = primary;
+		self
+	}
+
+	/// Connects (logging in when credentials are set) and checks that the
+	/// configured UPS exists on the server.
+	pub async fn check_connection(&self) -> Result<(), UpsError> {
+		let upses = self.list_ups().await?;
+		if upses.iter().any(|(name, _)| *name == self.name) {
+			Ok(())
+		} else {
+			Err(UpsError::UnknownUps)
+		}
+	}
+
+	// Keep the primary login alive, logging in again when the session was
+	// never established or the server dropped it
+	async fn ensure_primary_session(&self) {
+		let mut session = self.session.lock().await;
+
+		if let Some(connection) = session.as_mut() {
+			match self.count_logins(connection).await {
+				Ok(()) => return,
+				Err(e) => {
+					warn!("Lost primary session with NUT server: {}", e);
+					*session = None;
+				}
+			}
 		}
 
-		Ok(stream)
+		match self.login_primary().await {
+			Ok((connection, role)) => {
+				info!(
+					"Logged in to NUT server {} as primary ({} {})",
+					self.current_server().unwrap_or_default(),
+					role,
+					self.name
+				);
+				*session = Some(connection);
+				self.session_failing.store(false, Ordering::Relaxed);
+			}
+			// Retried every poll, so only the first failure in a row is a warning
+			Err(e) if self.session_failing.swap(true, Ordering::Relaxed) => {
+				debug!("Still failing to log in to NUT server as primary: {}", e);
+			}
+			Err(e) => warn!("Failed to log in to NUT server as primary: {}", e),
+		}
 	}
 
-	fn authenticate(&self, stream: &mut TcpStream) -> Result<(), Box<dyn std::error::Error>> {
-		let username = self.username.as_ref().unwrap();
-		let password = self.password.as_ref().unwrap();
+	// LOGIN, then PRIMARY, falling back to MASTER for servers older than NUT 2.8
+	async fn login_primary(&self) -> Result<(Connection, &'static str), UpsError> {
+		let mut connection = self.connect().await?;
 
-		let user_cmd = format!("USERNAME {}\n", username);
-		stream.write_all(user_cmd.as_bytes())?;
+		// LOGIN succeeds for any upsmon user, so a denial here is bad credentials
+		// or a user without an upsmon role
+		self
+			.send_privileged(
+				&mut connection,
+				&format!("LOGIN {}", self.name),
+				"upsmon primary",
+			)
+			.await?;
 
-		let mut reader = BufReader::new(stream.try_clone()?);
-		let mut response = String::new();
-		reader.read_line(&mut response)?;
+		let role = match self
+			.send_privileged(
+				&mut connection,
+				&format!("PRIMARY {}", self.name),
+				"upsmon primary",
+			)
+			.await
+		{
+			Ok(()) => "PRIMARY",
+			Err(UpsError::Nut(message)) if message.contains("UNKNOWN-COMMAND") => {
+				self
+					.send_privileged(
+						&mut connection,
+						&format!("MASTER {}", self.name),
+						"upsmon primary",
+					)
+					.await?;
+				"MASTER"
+			}
+			Err(e) => return Err(e),
+		};
+
+		Ok((connection, role))
+	}
 
-		if !response.to_uppercase().contains("OK") {
-			return Err(format!("Authentication failed at USERNAME: {}", response).into());
+	// Cheap round trip on the session connection to check it is still open
+	async fn count_logins(&self, connection: &mut Connection) -> Result<(), UpsError> {
+		match self
+			.query_line(connection, &format!("GET NUMLOGINS {}", self.name))
+			.await?
+		{
+			Some(_) => Ok(()),
+			None => Err(UpsError::InvalidResponse(
+				"unexpected NUMLOGINS response".to_string(),
+			)),
 		}
+	}
+
+	// Connect to the server in use, falling over to the next reachable one in
+	// order and sticking with it until it becomes unreachable in turn
+	async fn connect(&self) -> Result<Connection, UpsError> {
+		let start = self.current.load(Ordering::Relaxed);
+		let mut last_error = None;
+		let mut connection = None;
+
+		for offset in 0..self.servers.len() {
+			let index = (start + offset) % self.servers.len();
+			let (host, port) = &self.servers[index];
 
-		let pass_cmd = format!("PASSWORD {}\n", password);
-		stream.write_all(pass_cmd.as_bytes())?;
+			match Connection::open(host, *port, self.proxy.as_ref()).await {
+				Ok(connected) => {
+					if index != start {
+						warn!("Failing over to NUT server {}", format_address(host, *port));
+						self.current.store(index, Ordering::Relaxed);
+						self.failovers.fetch_add(1, Ordering::Relaxed);
+					}
+					connection = Some(connected);
+					break;
+				}
+				Err(e) => {
+					if self.servers.len() > 1 {
+						warn!(
+							"NUT server {} is unreachable: {}",
+							format_address(host, *port),
+							e
+						);
+					}
+					last_error = Some(e);
+				}
+			}
+		}
 
-		response.clear();
-		reader.read_line(&mut response)?;
+		let Some(mut connection) = connection else {
+			return Err(
+				last_error
+					.expect("at least one NUT server is configured")
+					.into(),
+			);
+		};
 
-		if !response.to_uppercase().contains("OK") {
-			return Err(format!("Authentication failed at PASSWORD: {}", response).into());
+		if self.username.is_some() && self.password.is_some() {
+			self.authenticate(&mut connection).await?;
 		}
 
+		Ok(connection)
+	}
+
+	async fn authenticate(&self, connection: &mut Connection) -> Result<(), UpsError> {
+		let username = self.username.as_ref().unwrap();
+		let password = self.password.as_ref().unwrap();
+
+		// Some servers check the credentials right away; a denial here can only
+		// mean they are wrong
+		let rejected = |e| match e {
+			UpsError::AccessDenied => UpsError::InvalidCredentials("ACCESS-DENIED".to_string()),
+			e => e,
+		};
+
+		self
+			.send_command(connection, &format!("USERNAME {}", username))
+			.await
+			.map_err(rejected)?;
+		self
+			.send_command(connection, &format!("PASSWORD {}", password))
+			.await
+			.map_err(rejected)?;
+
 		Ok(())
 	}
 
-	fn get_var(
+	// Send a command that needs `upsd.users` permissions, naming the missing
+	// permission if upsd denies it
+	async fn send_privileged(
 		&self,
-		stream: &mut TcpStream,
-		var_name: &str,
-	) -> Result<String, Box<dyn std::error::Error>> {
-		let command = format!("GET VAR {} {}\n", self.name, var_name);
-		stream.write_all(command.as_bytes())?;
+		connection: &mut Connection,
+		command: &str,
+		required: &str,
+	) -> Result<(), UpsError> {
+		self
+			.send_command(connection, command)
+			.await
+			.map_err(|e| match e {
+				UpsError::AccessDenied => UpsError::InsufficientPrivileges {
+					command: command.to_string(),
+					required: required.to_string(),
+				},
+				e => e,
+			})
+	}
+
+	// Send a command that is answered with a single OK or ERR line
+	async fn send_command(&self, connection: &mut Connection, command: &str) -> Result<(), UpsError> {
+		connection.send(command).await?;
+		let response = connection.read_line().await?;
+
+		if response.starts_with("OK") {
+			Ok(())
+		} else if response.starts_with("ERR") {
+			Err(UpsError::from_response(&response))
+		} else {
+			Err(UpsError::InvalidResponse(response))
+		}
+	}
+
+	// Send a command answered with a single free-form line, treating ERR
+	// (usually UNKNOWN-COMMAND on older servers) as unsupported
+	async fn query_line(
+		&self,
+		connection: &mut Connection,
+		command: &str,
+	) -> Result<Option<String>, UpsError> {
+		connection.send(command).await?;
+		let response = connection.read_line().await?;
+
+		if response.starts_with("ERR") {
+			Ok(None)
+		} else {
+			Ok(Some(response))
+		}
+	}
 
-		let mut reader = BufReader::new(stream.try_clone()?);
-		let mut response = String::new();
-		reader.read_line(&mut response)?;
+	async fn get_var(&self, connection: &mut Connection, var_name: &str) -> Result<String, UpsError> {
+		connection
+			.send(&format!("GET VAR {} {}", self.name, var_name))
+			.await?;
+		let response = connection.read_line().await?;
 
-		let parts: Vec<&str> = response.trim().split_whitespace().collect();
+		let mut parts = tokenize(&response);
 		if parts.len() >= 4 && parts[0] == "VAR" {
-			let value = parts[3..].join(" ").trim_matches('"').to_string();
-			Ok(value)
-		} else if response.contains("ERR") {
-			Err(format!("UPS error response: {}", response).into())
+			Ok(parts.swap_remove(3))
+		} else if response.starts_with("ERR") {
+			Err(UpsError::from_response(&response))
 		} else {
-			Err(format!("Invalid response: {}", response).into())
+			Err(UpsError::InvalidResponse(response))
 		}
 	}
 
-	pub fn get_status(&self) -> Result<UpsStatus, Box<dyn std::error::Error>> {
-		let mut stream = self.connect()?;
+	// Read every variable of the UPS with a single LIST VAR
+	async fn read_var_list(
+		&self,
+		connection: &mut Connection,
+	) -> Result<Vec<(String, String)>, UpsError> {
+		connection.send(&format!("LIST VAR {}", self.name)).await?;
 
-		let battery_charge = self
-			.get_var(&mut stream, "battery.charge")?
-			.parse::<f64>()
-			.unwrap_or(0.0);
+		let mut vars = Vec::new();
 
-		let battery_runtime = self
-			.get_var(&mut stream, "battery.runtime")?
-			.parse::<u64>()
-			.unwrap_or(0);
+		loop {
+			let line = connection.read_line().await?;
+			let mut parts = tokenize(&line);
+			match parts.first().map(String::as_str) {
+				Some("VAR") if parts.len() >= 4 => {
+					let value = parts.swap_remove(3);
+					vars.push((parts.swap_remove(2), value));
+				}
+				Some("ERR") => return Err(UpsError::from_response(&line)),
+				Some("END") if parts.get(1).is_some_and(|p| p == "LIST") => break,
+				_ => {}
+			}
+		}
+
+		Ok(vars)
+	}
+
+	// Fetch a variable the UPS may not report, treating VAR-NOT-SUPPORTED as
+	// absent. A complete LIST VAR result answers for every variable, so GET VAR
+	// is only sent when LIST VAR failed.
+	async fn get_optional_var(
+		&self,
+		connection: &mut Connection,
+		listed: Option<&HashMap<String, String>>,
+		var_name: &str,
+	) -> Result<Option<String>, UpsError> {
+		if let Some(listed) = listed {
+			return Ok(listed.get(var_name).cloned());
+		}
+
+		match self.get_var(connection, var_name).await {
+			Ok(value) => Ok(Some(value)),
+			Err(UpsError::VarNotSupported) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	// Fetch an optional numeric variable, treating unparsable values as absent
+	async fn get_optional_number(
+		&self,
+		connection: &mut Connection,
+		listed: Option<&HashMap<String, String>>,
+		var_name: &str,
+	) -> Result<Option<f64>, UpsError> {
+		Ok(
+			self
+				.get_optional_var(connection, listed, var_name)
+				.await?
+				.and_then(|v| parse_number(&v)),
+		)
+	}
+}
+
+#[async_trait]
+impl UpsSource for UpsClient {
+	async fn get_status(&self) -> Result<UpsStatus, UpsError> {
+		if self.primary {
+			self.ensure_primary_session().await;
+		}
+
+		let mut connection = self.connect().await?;
+
+		// One LIST VAR round-trip covers every variable the UPS reports, and a
+		// variable missing from it is unsupported. GET VAR is only used when the
+		// server refuses LIST VAR.
+		let listed: Option<HashMap<String, String>> = match self.read_var_list(&mut connection).await {
+			Ok(vars) => Some(vars.into_iter().collect()),
+			Err(UpsError::Io(e)) => return Err(UpsError::Io(e)),
+			Err(e) => {
+				debug!("LIST VAR failed, falling back to GET VAR: {}", e);
+				None
+			}
+		};
+		let listed = listed.as_ref();
 
-		let ups_status = self.get_var(&mut stream, "ups.status")?;
-		let on_battery = ups_status.contains("OB") || ups_status.contains("DISCHRG");
+		// Some drivers don't report charge or runtime at all; both stay unknown
+		// rather than reading as 0, which would look like an empty battery
+		let charge = self
+			.get_optional_var(&mut connection, listed, "battery.charge")
+			.await?;
+		let battery_charge = charge.as_deref().and_then(parse_number);
+		if let Some(ref charge) = charge
+			&& battery_charge.is_none()
+		{
+			warn!("Ignoring unparsable battery.charge value '{}'", charge);
+		}
+
+		let runtime = self
+			.get_optional_var(&mut connection, listed, "battery.runtime")
+			.await?;
+		let battery_runtime = runtime.as_deref().and_then(parse_seconds);
+		if let Some(ref runtime) = runtime
+			&& battery_runtime.is_none()
+		{
+			warn!("Ignoring unparsable battery.runtime value '{}'", runtime);
+		}
 
-		let output_power = match self.get_var(&mut stream, "output.power") {
-			Ok(v) => v.parse::<f64>().ok(),
-			Err(_) => None,
+		let ups_status = match listed.and_then(|listed| listed.get("ups.status")) {
+			Some(status) => status.clone(),
+			None => self.get_var(&mut connection, "ups.status").await?,
+		};
+		// Compare whole flags, "DISCHRG" contains "CHRG" and garbage may contain "OB"
+		let on_battery = ups_status
+			.split_whitespace()
+			.any(|flag| flag == "OB" || flag == "DISCHRG");
+		let charging = ups_status.split_whitespace().any(|flag| flag == "CHRG");
+
+		let output_power = self
+			.get_optional_number(&mut connection, listed, "output.power")
+			.await?;
+		let battery_temperature = self
+			.get_optional_number(&mut connection, listed, "battery.temperature")
+			.await?;
+		let ups_temperature = self
+			.get_optional_number(&mut connection, listed, "ups.temperature")
+			.await?;
+		let input_frequency = self
+			.get_optional_number(&mut connection, listed, "input.frequency")
+			.await?;
+		let battery_voltage = self
+			.get_optional_number(&mut connection, listed, "battery.voltage")
+			.await?;
+		let battery_voltage_nominal = self
+			.get_optional_number(&mut connection, listed, "battery.voltage.nominal")
+			.await?;
+		let battery_charge_restart = self
+			.get_optional_number(&mut connection, listed, "battery.charge.restart")
+			.await?;
+		let battery_date = match self
+			.get_optional_var(&mut connection, listed, "battery.date")
+			.await?
+		{
+			Some(date) => Some(date),
+			None => {
+				self
+					.get_optional_var(&mut connection, listed, "battery.mfr.date")
+					.await?
+			}
 		};
 
+		let mut extra_vars = HashMap::new();
+		for name in &self.extra_vars {
+			if let Some(value) = self.get_optional_var(&mut connection, listed, name).await? {
+				extra_vars.insert(name.clone(), value);
+			}
+		}
+
 		Ok(UpsStatus {
 			battery_charge,
 			battery_runtime,
 			ups_status,
 			on_battery,
+			charging,
 			output_power,
+			battery_temperature,
+			ups_temperature,
+			input_frequency,
+			battery_voltage,
+			battery_voltage_nominal,
+			battery_charge_restart,
+			battery_date,
+			extra_vars,
 		})
 	}
 
-	pub fn list_vars(&self) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
-		let mut stream = self.connect()?;
-		let command = format!("LIST VAR {}\n", self.name);
-		stream.write_all(command.as_bytes())?;
+	async fn list_vars(&self) -> Result<Vec<(String, String)>, UpsError> {
+		let mut connection = self.connect().await?;
+		self.read_var_list(&mut connection).await
+	}
 
-		let reader = BufReader::new(stream.try_clone()?);
-		let mut vars = Vec::new();
+	async fn server_version(&self) -> Result<ServerVersion, UpsError> {
+		let mut connection = self.connect().await?;
+
+		let server = self.query_line(&mut connection, "VER").await?;
+		let protocol = match self.query_line(&mut connection, "NETVER").await? {
+			Some(version) => Some(version),
+			None => self.query_line(&mut connection, "PROTVER").await?,
+		};
+
+		Ok(ServerVersion { server, protocol })
+	}
+
+	fn current_server(&self) -> Option<String> {
+		let (host, port) = &self.servers[self.current.load(Ordering::Relaxed)];
+		Some(format_address(host, *port))
+	}
+
+	fn failovers(&self) -> u64 {
+		self.failovers.load(Ordering::Relaxed)
+	}
+
+	async fn instant_command(&self, command: &str) -> Result<(), UpsError> {
+		let mut connection = self.connect().await?;
+		self
+			.send_privileged(
+				&mut connection,
+				&format!("INSTCMD {} {}", self.name, command),
+				&format!("instcmds = {}", command),
+			)
+			.await
+	}
 
-		for line in reader.lines() {
-			let line = line?;
-			if line.starts_with("VAR") {
-				let parts: Vec<&str> = line.split_whitespace().collect();
-				if parts.len() >= 4 {
-					let var_name = parts[2].to_string();
-					let value = parts[3..].join(" ").trim_matches('"').to_string();
-					vars.push((var_name, value));
+	async fn forced_shutdown(&self) -> Result<(), UpsError> {
+		let mut connection = self.connect().await?;
+		self
+			.send_privileged(
+				&mut connection,
+				&format!("FSD {}", self.name),
+				"upsmon primary",
+			)
+			.await
+	}
+
+	async fn set_var(&self, var: &str, value: &str) -> Result<(), UpsError> {
+		let mut connection = self.connect().await?;
+		let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+		self
+			.send_privileged(
+				&mut connection,
+				&format!("SET VAR {} {} \"{}\"", self.name, var, escaped),
+				"actions = SET",
+			)
+			.await
+	}
+
+	// Only the primary session stays open; the NUMLOGINS check in
+	// `ensure_primary_session` doubles as the ping
+	async fn keep_alive(&self) {
+		if self.primary {
+			self.ensure_primary_session().await;
+		}
+	}
+
+	async fn list_ups(&self) -> Result<Vec<(String, String)>, UpsError> {
+		let mut connection = self.connect().await?;
+		connection.send("LIST UPS").await?;
+
+		let mut upses = Vec::new();
+
+		loop {
+			let line = connection.read_line().await?;
+			let mut parts = tokenize(&line);
+			match parts.first().map(String::as_str) {
+				Some("UPS") if parts.len() >= 2 => {
+					let description = if parts.len() >= 3 {
+						parts.swap_remove(2)
+					} else {
+						String::new()
+					};
+					upses.push((parts.swap_remove(1), description));
 				}
-			} else if line.starts_with("END LIST") {
-				break;
+				Some("ERR") => return Err(UpsError::from_response(&line)),
+				Some("END") if parts.get(1).is_some_and(|p| p == "LIST") => break,
+				_ => {}
 			}
 		}
 
-		Ok(vars)
+		Ok(upses)
+	}
+}
+
+/// Splits a NUT response line into words, treating double-quoted sections as
+/// single words and unescaping `\"` and `\\` inside them.
+fn tokenize(line: &str) -> Vec<String> {
+	let mut tokens = Vec::new();
+	let mut chars = line.trim_end_matches(['\r', '\n']).chars();
+
+	while let Some(c) = chars.next() {
+		if c.is_whitespace() {
+			continue;
+		}
+
+		let mut token = String::new();
+		if c == '"' {
+			while let Some(c) = chars.next() {
+				match c {
+					'\\' => token.extend(chars.next()),
+					'"' => break,
+					c => token.push(c),
+				}
+			}
+		} else {
+			token.push(c);
+			for c in chars.by_ref() {
+				if c.is_whitespace() {
+					break;
+				}
+				token.push(c);
+			}
+		}
+		tokens.push(token);
+	}
+
+	tokens
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock_nut::MockNutServer;
+
+	#[test]
+	fn device_info_prefers_device_variables() {
+		let vars: Vec<(String, String)> = [
+			("ups.mfr", "APC"),
+			("device.mfr", "American Power Conversion"),
+			("ups.model", "Smart-UPS 1500"),
+			("ups.serial", " "),
+			("ups.firmware", "UPS 09.3"),
+			("driver.name", "usbhid-ups"),
+		]
+		.iter()
+		.map(|(name, value)| (name.to_string(), value.to_string()))
+		.collect();
+
+		let info = device_info(&vars);
+		assert_eq!(
+			info.into_iter().collect::<Vec<_>>(),
+			[
+				("driver".to_string(), "usbhid-ups".to_string()),
+				("firmware".to_string(), "UPS 09.3".to_string()),
+				(
+					"manufacturer".to_string(),
+					"American Power Conversion".to_string()
+				),
+				("model".to_string(), "Smart-UPS 1500".to_string()),
+			]
+		);
+	}
+
+	#[test]
+	fn battery_dates_parse_in_driver_formats() {
+		let today = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+		let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d);
+
+		assert_eq!(parse_battery_date("2021/03/15", today), date(2021, 3, 15));
+		assert_eq!(parse_battery_date(" 2021-03-15 ", today), date(2021, 3, 15));
+		assert_eq!(parse_battery_date("20210315", today), date(2021, 3, 15));
+		assert_eq!(parse_battery_date("03/15/21", today), date(2021, 3, 15));
+		assert_eq!(parse_battery_date("15/03/2021", today), date(2021, 3, 15));
+		assert_eq!(parse_battery_date("15.03.2021", today), date(2021, 3, 15));
+		assert_eq!(parse_battery_date("2021/03", today), date(2021, 3, 1));
+
+		// Placeholders, future dates and garbage
+		assert_eq!(parse_battery_date("01/01/1980", today), None);
+		assert_eq!(parse_battery_date("2030/01/01", today), None);
+		assert_eq!(parse_battery_date("unknown", today), None);
+		assert_eq!(parse_battery_date("", today), None);
+	}
+
+	fn client(port: u16, name: &str) -> UpsClient {
+		UpsClient::new("127.0.0.1".to_string(), port, name.to_string(), None, None)
+	}
+
+	fn standard_server() -> MockNutServer {
+		MockNutServer::new("ups")
+			.with_var("battery.charge", "87")
+			.with_var("battery.runtime", "1260")
+			.with_var("ups.status", "OB DISCHRG")
+			.with_var("output.power", "230.5")
+	}
+
+	#[tokio::test]
+	async fn get_status_reads_all_variables() {
+		let server = standard_server().start();
+
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+
+		assert_eq!(status.battery_charge, Some(87.0));
+		assert_eq!(status.battery_runtime, Some(1260));
+		assert_eq!(status.ups_status, "OB DISCHRG");
+		assert!(status.on_battery);
+		assert_eq!(status.output_power, Some(230.5));
+	}
+
+	#[tokio::test]
+	async fn check_connection_needs_the_configured_ups() {
+		let server = standard_server().start();
+
+		let upses = client(server.port(), "ups").list_ups().await.unwrap();
+		assert_eq!(upses, [("ups".to_string(), "Mock UPS".to_string())]);
+
+		client(server.port(), "ups")
+			.check_connection()
+			.await
+			.unwrap();
+		assert!(matches!(
+			client(server.port(), "apc").check_connection().await,
+			Err(UpsError::UnknownUps)
+		));
+	}
+
+	#[tokio::test]
+	async fn get_status_reads_available_temperatures() {
+		let server = standard_server()
+			.with_var("battery.temperature", "31.5")
+			.start();
+
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+
+		assert_eq!(status.battery_temperature, Some(31.5));
+		assert_eq!(status.ups_temperature, None);
+	}
+
+	#[tokio::test]
+	async fn get_status_reads_power_quality_variables() {
+		let server = standard_server()
+			.with_var("input.frequency", "49.9")
+			.with_var("battery.voltage", "26.4")
+			.with_var("battery.voltage.nominal", "24")
+			.start();
+
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+
+		assert_eq!(status.input_frequency, Some(49.9));
+		assert_eq!(status.battery_voltage, Some(26.4));
+		assert_eq!(status.battery_voltage_nominal, Some(24.0));
+	}
+
+	#[test]
+	fn parse_number_strips_units() {
+		assert_eq!(parse_number("100"), Some(100.0));
+		assert_eq!(parse_number(" 100 %"), Some(100.0));
+		assert_eq!(parse_number("230.5V"), Some(230.5));
+		assert_eq!(parse_number("-5"), Some(-5.0));
+		assert_eq!(parse_seconds("3600.0"), Some(3600));
+		assert_eq!(parse_seconds("95.7"), Some(95));
+	}
+
+	#[test]
+	fn parse_number_rejects_malformed_values() {
+		assert_eq!(parse_number(""), None);
+		assert_eq!(parse_number("unknown"), None);
+		assert_eq!(parse_number("%"), None);
+		assert_eq!(parse_number("1.2.3"), None);
+		assert_eq!(parse_seconds("-60"), None);
+	}
+
+	#[tokio::test]
+	async fn get_status_treats_malformed_values_as_unknown() {
+		let server = MockNutServer::new("ups")
+			.with_var("battery.charge", "n/a")
+			.with_var("battery.runtime", "3600.0 s")
+			.with_var("ups.status", "OB DISCHRG")
+			.start();
+
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+
+		assert_eq!(status.battery_charge, None);
+		assert_eq!(status.battery_runtime, Some(3600));
+	}
+
+	#[tokio::test]
+	async fn get_status_without_charge_or_runtime_reports_unknown() {
+		let server = MockNutServer::new("ups")
+			.with_var("ups.status", "OB DISCHRG")
+			.start();
+
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+
+		assert!(status.on_battery);
+		assert_eq!(status.battery_charge, None);
+		assert_eq!(status.battery_runtime, None);
+	}
+
+	#[tokio::test]
+	async fn get_status_falls_back_to_get_var_when_list_var_fails() {
+		let server = standard_server()
+			.with_unlisted_var("ambient.humidity", "41")
+			.with_list_var_refused()
+			.start();
+
+		let status = client(server.port(), "ups")
+			.with_extra_vars(vec!["ambient.humidity".to_string()])
+			.get_status()
+			.await
+			.unwrap();
+
+		assert_eq!(status.battery_charge, Some(87.0));
+		assert_eq!(status.output_power, Some(230.5));
+		assert_eq!(
+			status
+				.extra_vars
+				.get("ambient.humidity")
+				.map(String::as_str),
+			Some("41")
+		);
+	}
+
+	#[tokio::test]
+	async fn get_status_sends_no_get_var_after_a_complete_list() {
+		let server = standard_server()
+			.with_unlisted_var("ambient.humidity", "41")
+			.start();
+
+		let status = client(server.port(), "ups")
+			.with_extra_vars(vec![
+				"ambient.humidity".to_string(),
+				"ups.delay.shutdown".to_string(),
+			])
+			.get_status()
+			.await
+			.unwrap();
+
+		assert_eq!(status.battery_charge, Some(87.0));
+		assert_eq!(status.battery_temperature, None);
+		assert!(status.extra_vars.is_empty());
+		assert_eq!(server.get_var_requests(), 0);
+	}
+
+	#[tokio::test]
+	async fn empty_and_garbage_status_are_not_recognized() {
+		for raw in ["", "NOBODY HOME"] {
+			let server = MockNutServer::new("ups")
+				.with_var("battery.charge", "5")
+				.with_var("battery.runtime", "30")
+				.with_var("ups.status", raw)
+				.start();
+
+			let status = client(server.port(), "ups").get_status().await.unwrap();
+
+			assert!(!status.is_recognized(), "'{}' was recognized", raw);
+			assert!(!status.on_battery, "'{}' counted as on battery", raw);
+		}
+	}
+
+	#[tokio::test]
+	async fn status_flags_match_whole_words() {
+		let server = MockNutServer::new("ups")
+			.with_var("battery.charge", "100")
+			.with_var("battery.runtime", "900")
+			.with_var("ups.status", "OL CHRG RB")
+			.start();
+
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+
+		assert!(status.has_flag("RB"));
+		assert!(status.has_flag("OL"));
+		assert!(!status.has_flag("B"));
+	}
+
+	#[tokio::test]
+	async fn energy_remaining_needs_runtime_and_power() {
+		let server = MockNutServer::new("ups")
+			.with_var("battery.charge", "80")
+			.with_var("battery.runtime", "1800")
+			.with_var("ups.status", "OB DISCHRG")
+			.start();
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+		assert_eq!(status.energy_remaining_watthours(), None);
+
+		let server = MockNutServer::new("ups")
+			.with_var("battery.charge", "80")
+			.with_var("battery.runtime", "1800")
+			.with_var("output.power", "200")
+			.with_var("ups.status", "OB DISCHRG")
+			.start();
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+		assert_eq!(status.energy_remaining_watthours(), Some(100.0));
+	}
+
+	#[tokio::test]
+	async fn get_status_detects_charging() {
+		let server = MockNutServer::new("ups")
+			.with_var("battery.charge", "64")
+			.with_var("battery.runtime", "900")
+			.with_var("ups.status", "OL CHRG")
+			.with_var("battery.charge.restart", "30")
+			.start();
+
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+
+		assert!(status.charging);
+		assert!(!status.on_battery);
+		assert_eq!(status.battery_charge_restart, Some(30.0));
+
+		// Discharging must not be mistaken for charging
+		let status = client(standard_server().start().port(), "ups")
+			.get_status()
+			.await
+			.unwrap();
+		assert!(!status.charging);
+	}
+
+	#[tokio::test]
+	async fn get_status_reads_extra_vars() {
+		let server = standard_server()
+			.with_var("ambient.humidity", "41.5")
+			.with_var("ups.test.result", "Done and passed")
+			.start();
+
+		let client = client(server.port(), "ups").with_extra_vars(vec![
+			"ambient.humidity".to_string(),
+			"ups.test.result".to_string(),
+			"ambient.temperature".to_string(),
+		]);
+		let status = client.get_status().await.unwrap();
+
+		assert_eq!(status.extra_vars.len(), 2);
+		assert_eq!(status.extra_vars["ambient.humidity"], "41.5");
+		assert_eq!(status.extra_vars["ups.test.result"], "Done and passed");
+	}
+
+	#[tokio::test]
+	async fn server_version_reads_ver_and_netver() {
+		let server = standard_server()
+			.with_version("Network UPS Tools upsd 2.8.1", "1.3")
+			.start();
+
+		let version = client(server.port(), "ups").server_version().await.unwrap();
+
+		assert_eq!(
+			version.server.as_deref(),
+			Some("Network UPS Tools upsd 2.8.1")
+		);
+		assert_eq!(version.protocol.as_deref(), Some("1.3"));
+	}
+
+	#[tokio::test]
+	async fn server_version_tolerates_unknown_commands() {
+		let server = standard_server().start();
+
+		let version = client(server.port(), "ups").server_version().await.unwrap();
+
+		assert_eq!(version.server, None);
+		assert_eq!(version.protocol, None);
+	}
+
+	// Minimal SOCKS5 proxy without authentication that only ever connects
+	// to `target`, for a single client connection
+	async fn socks5_proxy(target: u16) -> u16 {
+		use tokio::io::AsyncReadExt;
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+
+		tokio::spawn(async move {
+			let (mut client, _) = listener.accept().await.unwrap();
+			let mut greeting = [0u8; 3];
+			client.read_exact(&mut greeting).await.unwrap();
+			client.write_all(&[0x05, 0x00]).await.unwrap();
+
+			// IP literals arrive as addresses, host names as domains
+			let mut request = [0u8; 4];
+			client.read_exact(&mut request).await.unwrap();
+			let address_len = match request[3] {
+				0x01 => 4,
+				0x04 => 16,
+				_ => client.read_u8().await.unwrap() as usize,
+			};
+			let mut rest = vec![0u8; address_len + 2];
+			client.read_exact(&mut rest).await.unwrap();
+
+			let mut upstream = tokio::net::TcpStream::connect(("127.0.0.1", target))
+				.await
+				.unwrap();
+			client
+				.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0])
+				.await
+				.unwrap();
+			let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+		});
+
+		port
+	}
+
+	#[tokio::test]
+	async fn get_status_through_socks5_proxy() {
+		let server = standard_server().start();
+		let proxy_port = socks5_proxy(server.port()).await;
+
+		let client = client(server.port(), "ups").with_proxy(Some(Socks5Proxy {
+			host: "127.0.0.1".to_string(),
+			port: proxy_port,
+			credentials: None,
+		}));
+		let status = client.get_status().await.unwrap();
+
+		assert_eq!(status.battery_charge, Some(87.0));
+	}
+
+	#[tokio::test]
+	async fn get_status_fails_over_to_next_server() {
+		let unreachable = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let unreachable_port = unreachable.local_addr().unwrap().port();
+		drop(unreachable);
+		let server = standard_server().start();
+
+		let client =
+			client(unreachable_port, "ups").with_failover(vec![("127.0.0.1".to_string(), server.port())]);
+		let status = client.get_status().await.unwrap();
+
+		assert_eq!(status.battery_charge, Some(87.0));
+		assert_eq!(
+			client.current_server(),
+			Some(format!("127.0.0.1:{}", server.port()))
+		);
+		assert_eq!(client.failovers(), 1);
+
+		// Staying on the replica is not another failover
+		client.get_status().await.unwrap();
+		assert_eq!(client.failovers(), 1);
+	}
+
+	#[tokio::test]
+	async fn get_status_without_optional_output_power() {
+		let server = MockNutServer::new("ups")
+			.with_var("battery.charge", "100")
+			.with_var("battery.runtime", "3000")
+			.with_var("ups.status", "OL")
+			.start();
+
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+
+		assert!(!status.on_battery);
+		assert_eq!(status.output_power, None);
+	}
+
+	#[tokio::test]
+	async fn get_status_authenticates_with_valid_credentials() {
+		let server = standard_server()
+			.with_credentials("monitor", "secret")
+			.start();
+		let client = UpsClient::new(
+			"127.0.0.1".to_string(),
+			server.port(),
+			"ups".to_string(),
+			Some("monitor".to_string()),
+			Some("secret".to_string()),
+		);
+
+		assert!(client.get_status().await.is_ok());
+	}
+
+	#[tokio::test]
+	async fn get_status_fails_on_rejected_credentials() {
+		let server = standard_server()
+			.with_credentials("monitor", "secret")
+			.start();
+		let client = UpsClient::new(
+			"127.0.0.1".to_string(),
+			server.port(),
+			"ups".to_string(),
+			Some("monitor".to_string()),
+			Some("wrong".to_string()),
+		);
+
+		let error = client.get_status().await.unwrap_err();
+
+		assert!(
+			matches!(error, UpsError::InvalidCredentials(_)),
+			"unexpected error: {}",
+			error
+		);
+	}
+
+	#[tokio::test]
+	async fn instant_command_requires_credentials() {
+		let server = standard_server()
+			.with_credentials("admin", "secret")
+			.start();
+
+		let anonymous = client(server.port(), "ups");
+		assert!(matches!(
+			anonymous.instant_command("shutdown.return").await,
+			Err(UpsError::InvalidCredentials(_))
+		));
+
+		let authenticated = UpsClient::new(
+			"127.0.0.1".to_string(),
+			server.port(),
+			"ups".to_string(),
+			Some("admin".to_string()),
+			Some("secret".to_string()),
+		);
+		authenticated
+			.instant_command("shutdown.return")
+			.await
+			.unwrap();
+		authenticated.forced_shutdown().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn missing_permissions_are_reported_separately() {
+		let server = standard_server()
+			.with_credentials("monitor", "secret")
+			.with_restricted_user()
+			.start();
+		let ups = UpsClient::new(
+			"127.0.0.1".to_string(),
+			server.port(),
+			"ups".to_string(),
+			Some("monitor".to_string()),
+			Some("secret".to_string()),
+		);
+
+		// Polling works, the privileged commands name the missing permission
+		ups.get_status().await.unwrap();
+		match ups.instant_command("shutdown.return").await {
+			Err(UpsError::InsufficientPrivileges { command, required }) => {
+				assert_eq!(command, "INSTCMD ups shutdown.return");
+				assert_eq!(required, "instcmds = shutdown.return");
+			}
+			result => panic!("unexpected result: {:?}", result),
+		}
+		assert!(matches!(
+			ups.forced_shutdown().await,
+			Err(UpsError::InsufficientPrivileges { .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn set_var_reports_read_only_and_denied_variables() {
+		let server = standard_server()
+			.with_var("battery.charge.low", "10")
+			.with_writable_var("battery.charge.low")
+			.with_credentials("admin", "secret")
+			.start();
+		let ups = UpsClient::new(
+			"127.0.0.1".to_string(),
+			server.port(),
+			"ups".to_string(),
+			Some("admin".to_string()),
+			Some("secret".to_string()),
+		);
+
+		ups.set_var("battery.charge.low", "30").await.unwrap();
+		assert!(matches!(
+			ups.set_var("battery.charge", "100").await,
+			Err(UpsError::ReadOnly)
+		));
+		assert!(matches!(
+			ups.set_var("ups.delay.shutdown", "60").await,
+			Err(UpsError::VarNotSupported)
+		));
+
+		let restricted = standard_server()
+			.with_writable_var("battery.charge.low")
+			.with_credentials("monitor", "secret")
+			.with_restricted_user()
+			.start();
+		let ups = UpsClient::new(
+			"127.0.0.1".to_string(),
+			restricted.port(),
+			"ups".to_string(),
+			Some("monitor".to_string()),
+			Some("secret".to_string()),
+		);
+		match ups.set_var("battery.charge.low", "30").await {
+			Err(UpsError::InsufficientPrivileges { command, required }) => {
+				assert_eq!(command, "SET VAR ups battery.charge.low \"30\"");
+				assert_eq!(required, "actions = SET");
+			}
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+
+	#[tokio::test]
+	async fn forced_shutdown_fails_for_unknown_ups() {
+		let server = standard_server().start();
+		let result = client(server.port(), "other").forced_shutdown().await;
+		assert!(matches!(result, Err(UpsError::UnknownUps)));
+	}
+
+	#[tokio::test]
+	async fn primary_role_logs_in_and_keeps_session() {
+		let server = standard_server().start();
+		let ups = client(server.port(), "ups").with_primary_role(true);
+
+		ups.get_status().await.unwrap();
+		assert!(ups.session.lock().await.is_some());
+
+		// The session is reused while it stays open
+		ups.get_status().await.unwrap();
+		assert!(ups.session.lock().await.is_some());
+	}
+
+	#[tokio::test]
+	async fn primary_role_falls_back_to_master() {
+		let server = standard_server().with_legacy_master().start();
+		let ups = client(server.port(), "ups").with_primary_role(true);
+
+		let (_, role) = ups.login_primary().await.unwrap();
+		assert_eq!(role, "MASTER");
+	}
+
+	#[tokio::test]
+	async fn primary_login_denied_still_polls() {
+		let server = standard_server()
+			.with_credentials("admin", "secret")
+			.start();
+		let ups = client(server.port(), "ups").with_primary_role(true);
+
+		assert!(matches!(
+			ups.login_primary().await,
+			Err(UpsError::InvalidCredentials(_))
+		));
+		ups.get_status().await.unwrap();
+		assert!(ups.session.lock().await.is_none());
+	}
+
+	#[tokio::test]
+	async fn get_status_reassembles_byte_by_byte_responses() {
+		let server = standard_server()
+			.with_var("ups.temperature", "31.5")
+			.with_byte_by_byte()
+			.start();
+		let status = client(server.port(), "ups").get_status().await.unwrap();
+
+		assert_eq!(status.battery_charge, Some(87.0));
+		assert_eq!(status.battery_runtime, Some(1260));
+		assert_eq!(status.ups_status, "OB DISCHRG");
+		assert_eq!(status.ups_temperature, Some(31.5));
+	}
+
+	#[tokio::test]
+	async fn list_vars_reassembles_byte_by_byte_responses() {
+		let server = MockNutServer::new("ups")
+			.with_var("device.mfr", "American Power Conversion")
+			.with_var("ups.status", "OL")
+			.with_byte_by_byte()
+			.start();
+		let vars = client(server.port(), "ups").list_vars().await.unwrap();
+
+		assert_eq!(
+			vars,
+			vec![
+				(
+					"device.mfr".to_string(),
+					"American Power Conversion".to_string()
+				),
+				("ups.status".to_string(), "OL".to_string()),
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn stray_blank_lines_are_skipped() {
+		let server = standard_server()
+			.with_credentials("admin", "secret")
+			.with_blank_lines()
+			.start();
+		let ups = UpsClient::new(
+			"127.0.0.1".to_string(),
+			server.port(),
+			"ups".to_string(),
+			Some("admin".to_string()),
+			Some("secret".to_string()),
+		);
+
+		let status = ups.get_status().await.unwrap();
+		assert_eq!(status.battery_charge, Some(87.0));
+		assert_eq!(status.output_power, Some(230.5));
+		assert_eq!(ups.list_vars().await.unwrap().len(), 4);
+	}
+
+	#[tokio::test]
+	async fn get_status_fails_for_unknown_ups() {
+		let server = standard_server().start();
+
+		let error = client(server.port(), "missing")
+			.get_status()
+			.await
+			.unwrap_err();
+
+		assert!(
+			matches!(error, UpsError::UnknownUps),
+			"unexpected error: {}",
+			error
+		);
+	}
+
+	#[tokio::test]
+	async fn list_vars_fails_for_unknown_ups() {
+		let server = standard_server().start();
+
+		let error = client(server.port(), "missing")
+			.list_vars()
+			.await
+			.unwrap_err();
+
+		assert!(matches!(error, UpsError::UnknownUps));
+	}
+
+	#[test]
+	fn unrecognized_error_keeps_raw_message() {
+		let error = UpsError::from_response("ERR FEATURE-NOT-CONFIGURED\n");
+
+		assert!(matches!(error, UpsError::Nut(ref message) if message == "ERR FEATURE-NOT-CONFIGURED"));
+	}
+
+	#[tokio::test]
+	async fn list_vars_keeps_spaces_in_quoted_values() {
+		let server = standard_server()
+			.with_var("device.model", "Smart-UPS 1500 RM")
+			.start();
+
+		let vars = client(server.port(), "ups").list_vars().await.unwrap();
+
+		assert_eq!(vars.len(), 5);
+		assert!(vars.contains(&("device.model".to_string(), "Smart-UPS 1500 RM".to_string())));
+	}
+
+	#[test]
+	fn tokenize_unescapes_quoted_values() {
+		let tokens = tokenize(r#"VAR ups device.model "Smart UPS \"Pro\" 1500""#);
+
+		assert_eq!(
+			tokens,
+			["VAR", "ups", "device.model", r#"Smart UPS "Pro" 1500"#]
+		);
+	}
+
+	#[test]
+	fn tokenize_preserves_internal_whitespace_and_backslashes() {
+		let tokens = tokenize("VAR ups ups.id \"rack  4\\\\slot 2\"\r\n");
+
+		assert_eq!(tokens, ["VAR", "ups", "ups.id", "rack  4\\slot 2"]);
+	}
+
+	#[test]
+	fn tokenize_keeps_empty_quoted_value() {
+		let tokens = tokenize(r#"VAR ups ups.location """#);
+
+		assert_eq!(tokens, ["VAR", "ups", "ups.location", ""]);
+	}
+
+	#[tokio::test]
+	async fn get_var_returns_escaped_value_verbatim() {
+		let server = standard_server()
+			.with_var("device.model", r#"Smart UPS "Pro" 1500"#)
+			.start();
+		let client = client(server.port(), "ups");
+		let mut connection = client.connect().await.unwrap();
+
+		let value = client
+			.get_var(&mut connection, "device.model")
+			.await
+			.unwrap();
+
+		assert_eq!(value, r#"Smart UPS "Pro" 1500"#);
+	}
+
+	#[tokio::test]
+	async fn list_vars_is_not_cut_short_by_end_list_inside_a_value() {
+		let server = standard_server()
+			.with_var("ups.test.result", "END LIST reached  early")
+			.with_var("ups.firmware", "2.1")
+			.start();
+
+		let vars = client(server.port(), "ups").list_vars().await.unwrap();
+
+		assert!(vars.contains(&(
+			"ups.test.result".to_string(),
+			"END LIST reached  early".to_string()
+		)));
+		assert!(vars.contains(&("ups.firmware".to_string(), "2.1".to_string())));
 	}
 }
@@ -0,0 +1,62 @@
+use log::error;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Exit status when the watchdog fires, so the service manager restarts us
+const WATCHDOG_EXIT_CODE: i32 = 70;
+
+/// Exits the process when the monitor loop stops calling `touch` for longer
+/// than the timeout, e.g. because a poll hangs despite the per-read timeouts.
+///
+/// The check runs on a plain OS thread so it keeps working even when the
+/// async runtime itself is stuck.
+pub struct Watchdog {
+	started: Instant,
+	// Milliseconds since `started` at the last touch
+	last_touch: Arc<AtomicU64>,
+}
+
+impl Watchdog {
+	pub fn start(timeout: Duration) -> Self {
+		let started = Instant::now();
+		let last_touch = Arc::new(AtomicU64::new(0));
+
+		let touched = last_touch.clone();
+		thread::Builder::new()
+			.name("watchdog".to_string())
+			.spawn(move || {
+				let check_interval = (timeout / 4).clamp(Duration::from_millis(100), Duration::from_secs(5));
+				loop {
+					thread::sleep(check_interval);
+
+					let last = Duration::from_millis(touched.load(Ordering::Relaxed));
+					let stalled = started.elapsed().saturating_sub(last);
+					if stalled > timeout {
+						error!(
+							"💀 Monitor loop has not completed a cycle in {}s (cycle_watchdog_secs = {}), exiting so the service manager can restart RabbitNUT",
+							stalled.as_secs(),
+							timeout.as_secs()
+						);
+						log::logger().flush();
+						process::exit(WATCHDOG_EXIT_CODE);
+					}
+				}
+			})
+			.expect("Failed to start watchdog thread");
+
+		Watchdog {
+			started,
+			last_touch,
+		}
+	}
+
+	/// Records that the monitor loop is still making progress.
+	pub fn touch(&self) {
+		self
+			.last_touch
+			.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+	}
+}